@@ -0,0 +1,185 @@
+// src/async_processor.rs
+/*
+ * Async counterpart of WorkflowEngineProcessor (enabled by the `async` feature)
+ */
+
+use crate::cancel::CancellationToken;
+use crate::run::RunScope;
+use crate::retry::Attempt;
+use crate::scheduler::{self, Completion, Scheduler};
+use crate::workflow::Workflow;
+use crate::{ProcessResult, Result, StepResult, StepStatus, WorkflowEngineProcessor, WorkflowResult};
+use chrono::Utc;
+use futures::channel::oneshot;
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{debug, info};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Runs workflows without blocking the calling task.
+///
+/// Steps execute on dedicated threads, at most `workers` at a time across all
+/// runs of the processor, so the futures returned here never block an executor
+/// thread. Scheduling and result bookkeeping are shared with [`WorkflowEngineProcessor::run_workflow`], so
+/// both APIs behave the same. No particular runtime is required.
+#[derive(Debug, Clone)]
+pub struct AsyncProcessor {
+    inner: Arc<WorkflowEngineProcessor>,
+}
+
+impl AsyncProcessor {
+    pub fn new(processor: WorkflowEngineProcessor) -> Self {
+        Self {
+            inner: Arc::new(processor),
+        }
+    }
+
+    /// The underlying processor, e.g. for `get_stats`
+    pub fn processor(&self) -> &WorkflowEngineProcessor {
+        &self.inner
+    }
+
+    pub async fn process(&self, data: &str) -> Result<ProcessResult> {
+        self.inner.process(data)
+    }
+
+    pub async fn run_workflow(&self, workflow: &Workflow) -> Result<WorkflowResult> {
+        self.run_workflow_cancellable(workflow, &CancellationToken::new()).await
+    }
+
+    /// Async counterpart of [`WorkflowEngineProcessor::run_workflow_cancellable`]
+    pub async fn run_workflow_cancellable(&self, workflow: &Workflow, token: &CancellationToken) -> Result<WorkflowResult> {
+        let workflow = workflow.with_inputs(&serde_json::Map::new())?;
+        let workflow = &*self.inner.apply_defaults(&workflow);
+        info!("Running workflow '{}' with {} steps", workflow.name, workflow.steps.len());
+        let token = token.child(workflow.timeout_ms.map(Duration::from_millis));
+
+        let mut scheduler = Scheduler::new(workflow)?;
+        let run_id = self.inner.new_run_id();
+        let scope = Arc::new(RunScope::new(&run_id, workflow));
+        self.inner.start_run(workflow, &scope, false);
+        let workers = self.inner.workers();
+        let mut in_flight = FuturesUnordered::new();
+        let record = |result: StepResult| scope.record(result);
+
+        loop {
+            if token.is_cancelled() {
+                for index in scheduler.cancel_pending() {
+                    record(self.inner.record_unrun(&scope, &workflow.steps[index], StepStatus::Cancelled));
+                }
+            }
+            while in_flight.len() < workers {
+                let index = match scheduler.next_ready() {
+                    Some(index) => index,
+                    None => break,
+                };
+                if let Some(result) = self.inner.check_condition(&scope, &workflow.steps[index]) {
+                    for skipped in scheduler.complete(index, record(result)) {
+                        record(self.inner.record_unrun(&scope, &workflow.steps[skipped], StepStatus::Skipped));
+                    }
+                    continue;
+                }
+                debug!("Starting step '{}'", workflow.steps[index].id);
+                in_flight.push(self.spawn_step(workflow, index, &scope, &token));
+            }
+            if scheduler.is_finished() {
+                break;
+            }
+
+            let completion = match in_flight.next().await {
+                Some(completion) => completion,
+                None => break,
+            };
+            let step = &workflow.steps[completion.index];
+            let index = completion.index;
+            let status = record(self.inner.record_completion(&scope, step, completion));
+            for skipped in scheduler.complete(index, status) {
+                record(self.inner.record_unrun(&scope, &workflow.steps[skipped], StepStatus::Skipped));
+            }
+        }
+
+        Ok(self.inner.finish(workflow, &scope, &token))
+    }
+
+    // Runs one step on its own thread and resolves once it is done.
+    async fn spawn_step(&self, workflow: &Workflow, index: usize, scope: &Arc<RunScope>, token: &CancellationToken) -> Completion {
+        let step = workflow.steps[index].clone();
+        let processor = Arc::clone(&self.inner);
+        let scope = Arc::clone(scope);
+        let token = token.clone();
+        let (tx, rx) = oneshot::channel();
+
+        let spawned = thread::Builder::new()
+            .name(format!("step-{}", step.id))
+            .spawn(move || {
+                let run_step = |step: &_, token: &_| processor.run_step(&scope, step, token);
+                let on_progress =
+                    |step: &_, progress: scheduler::Progress<'_>| processor.emit_progress(&scope, step, progress);
+                let completion = scheduler::execute(index, &step, processor.slots(), &run_step, &on_progress, &token);
+                let _ = tx.send(completion);
+            });
+
+        let message = match spawned {
+            Ok(_) => match rx.await {
+                Ok(completion) => return completion,
+                Err(_) => format!("Step '{}' stopped without a result", workflow.steps[index].id),
+            },
+            Err(e) => format!("Step '{}' could not be started: {}", workflow.steps[index].id, e),
+        };
+        Completion {
+            index,
+            attempts: vec![Attempt {
+                number: 1,
+                started_at: Utc::now(),
+                duration_ms: 0,
+                result: ProcessResult {
+                    success: false,
+                    message,
+                    data: None,
+                    timed_out: false,
+                },
+                retry_delay_ms: None,
+            }],
+            interrupted: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_async_process() {
+        let processor = AsyncProcessor::new(WorkflowEngineProcessor::new(false));
+        let result = block_on(processor.process("test data")).unwrap();
+        assert!(result.success);
+        assert_eq!(processor.processor().processed_count(), 1);
+    }
+
+    #[test]
+    fn test_async_matches_sync_behaviour() {
+        let workflow = Workflow::from_json(
+            r#"{"name": "demo", "steps": [
+                {"id": "fetch", "inputs": {"count": 3}},
+                {"id": "broken", "depends_on": ["fetch"], "outputs": ["missing"]},
+                {"id": "after", "depends_on": ["broken"]},
+                {"id": "side", "depends_on": ["fetch"]}
+            ]}"#,
+        )
+        .unwrap();
+
+        let sync = WorkflowEngineProcessor::new(false).with_workers(2).run_workflow(&workflow).unwrap();
+        let processor = AsyncProcessor::new(WorkflowEngineProcessor::new(false).with_workers(2));
+        let result = block_on(processor.run_workflow(&workflow)).unwrap();
+
+        assert_eq!(result.success, sync.success);
+        for step in &sync.steps {
+            assert_eq!(result.step(&step.id).unwrap().status, step.status, "{}", step.id);
+        }
+        assert_eq!(result.step("after").unwrap().status, StepStatus::Skipped);
+        assert_eq!(processor.processor().processed_count(), 3);
+    }
+}
@@ -0,0 +1,133 @@
+// src/cancel.rs
+/*
+ * Cooperative cancellation tokens with optional deadlines
+ */
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often sleeping steps look at their token
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Why a token stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CancelReason {
+    /// `cancel` was called, e.g. on Ctrl-C
+    Cancelled,
+    /// A deadline passed
+    TimedOut,
+}
+
+/// Signal that long-running work polls to find out it should stop.
+///
+/// Tokens form a tree: a child stops when its own deadline passes, when it is
+/// cancelled, or when any of its ancestors stops. Cloning a token yields a
+/// handle to the same token.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    deadline: Option<Instant>,
+    parent: Option<CancellationToken>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A child token that also times out after `timeout`, if given
+    pub fn child(&self, timeout: Option<Duration>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                cancelled: AtomicBool::new(false),
+                deadline: timeout.map(|timeout| Instant::now() + timeout),
+                parent: Some(self.clone()),
+            }),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn reason(&self) -> Option<CancelReason> {
+        if self.inner.cancelled.load(Ordering::SeqCst) {
+            return Some(CancelReason::Cancelled);
+        }
+        if self.inner.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Some(CancelReason::TimedOut);
+        }
+        self.inner.parent.as_ref().and_then(|parent| parent.reason())
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.reason().is_some()
+    }
+
+    /// Sleeps for `duration` unless the token stops first.
+    ///
+    /// Returns `false` when the sleep was cut short.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let until = Instant::now() + duration;
+        loop {
+            if self.is_cancelled() {
+                return false;
+            }
+            let now = Instant::now();
+            if now >= until {
+                return true;
+            }
+            thread::sleep((until - now).min(POLL_INTERVAL));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_propagates_to_children() {
+        let root = CancellationToken::new();
+        let child = root.child(None);
+        let grandchild = child.child(None);
+        assert_eq!(grandchild.reason(), None);
+
+        root.cancel();
+        assert_eq!(grandchild.reason(), Some(CancelReason::Cancelled));
+        assert!(child.is_cancelled());
+    }
+
+    #[test]
+    fn test_child_deadline_does_not_affect_parent() {
+        let root = CancellationToken::new();
+        let child = root.child(Some(Duration::from_millis(0)));
+        assert_eq!(child.reason(), Some(CancelReason::TimedOut));
+        assert_eq!(root.reason(), None);
+    }
+
+    #[test]
+    fn test_sleep_is_interrupted() {
+        let token = CancellationToken::new();
+        let handle = token.clone();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            handle.cancel();
+        });
+        let started = Instant::now();
+        assert!(!token.sleep(Duration::from_secs(5)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        canceller.join().unwrap();
+
+        assert!(CancellationToken::new().sleep(Duration::from_millis(1)));
+    }
+}
@@ -0,0 +1,243 @@
+// src/command.rs
+/*
+ * `command` steps: run an external program and capture its output
+ */
+
+use crate::cancel::CancellationToken;
+use crate::{ProcessResult, Result, WorkflowError};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a running program is checked for exit and cancellation
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long the output of a program that exited may stay open, held by
+/// something it left running in the background
+const OUTPUT_GRACE: Duration = Duration::from_millis(200);
+
+/// External program invocation for a `kind: command` step
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandSpec {
+    pub program: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// Extra environment variables, added to the engine's own environment
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<PathBuf>,
+}
+
+/// Runs the program to completion.
+///
+/// The step succeeds when the program exits with status 0. Its stdout, stderr
+/// and exit code end up in the result `data`; a program killed by a signal has
+/// a `null` exit code. Failing to start the program at all is an error.
+///
+/// When `token` stops, the program (and on Unix its whole process group) is
+/// killed and whatever it printed so far is kept. So is anything the program
+/// left running in the background that still holds its stdout or stderr
+/// shortly after it exited.
+pub fn run_command(step_id: &str, spec: &CommandSpec, token: &CancellationToken) -> Result<ProcessResult> {
+    debug!("Step '{}' running {} {:?}", step_id, spec.program, spec.args);
+
+    let mut command = Command::new(&spec.program);
+    command
+        .args(&spec.args)
+        .envs(&spec.env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(dir) = &spec.working_dir {
+        command.current_dir(dir);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // Own process group, so cancelling also stops anything the program spawned
+        command.process_group(0);
+    }
+
+    let mut child = command
+        .spawn()
+        .map_err(|e| WorkflowError::step_failed(step_id, format!("could not start '{}'", spec.program)).with_source(e))?;
+    let stdout = capture(child.stdout.take());
+    let stderr = capture(child.stderr.take());
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if token.is_cancelled() {
+            warn!("Step '{}' stopping '{}'", step_id, spec.program);
+            kill(&mut child);
+            break None;
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    // The pipes close once everything writing to them is gone
+    let mut stopped = status.is_none();
+    let mut deadline = Instant::now() + OUTPUT_GRACE;
+    while !(stdout.is_done() && stderr.is_done()) {
+        if Instant::now() >= deadline || (token.is_cancelled() && !stopped) {
+            if stopped {
+                warn!("Step '{}': not waiting any longer for the output of '{}'", step_id, spec.program);
+                break;
+            }
+            debug!("Step '{}' stopping what '{}' left running", step_id, spec.program);
+            kill(&mut child);
+            stopped = true;
+            deadline = Instant::now() + OUTPUT_GRACE;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    let stdout = stdout.take();
+    let stderr = stderr.take();
+    let exit_code = status.and_then(|status| status.code());
+    let success = status.is_some_and(|status| status.success());
+    let message = match (status, exit_code) {
+        (None, _) => format!("Command '{}' was stopped", spec.program),
+        (Some(_), Some(code)) => format!("Command '{}' exited with status {}", spec.program, code),
+        (Some(_), None) => format!("Command '{}' was terminated by a signal", spec.program),
+    };
+
+    Ok(ProcessResult {
+        success,
+        message,
+        data: Some(serde_json::json!({
+            "exit_code": exit_code,
+            "stdout": String::from_utf8_lossy(&stdout),
+            "stderr": String::from_utf8_lossy(&stderr),
+        })),
+        timed_out: false,
+    })
+}
+
+// Output read from a pipe so far, and the thread reading it
+struct Capture {
+    output: Arc<Mutex<Vec<u8>>>,
+    reader: thread::JoinHandle<()>,
+}
+
+impl Capture {
+    // True once the pipe is closed and read to the end
+    fn is_done(&self) -> bool {
+        self.reader.is_finished()
+    }
+
+    fn take(self) -> Vec<u8> {
+        std::mem::take(&mut *self.output.lock().unwrap())
+    }
+}
+
+// Drains a pipe on its own thread so a chatty program cannot block on a full buffer.
+fn capture<R: Read + Send + 'static>(pipe: Option<R>) -> Capture {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let buffer = Arc::clone(&output);
+    let reader = thread::spawn(move || {
+        let Some(mut pipe) = pipe else {
+            return;
+        };
+        let mut chunk = [0u8; 8192];
+        while let Ok(read @ 1..) = pipe.read(&mut chunk) {
+            buffer.lock().unwrap().extend_from_slice(&chunk[..read]);
+        }
+    });
+    Capture { output, reader }
+}
+
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    {
+        // SAFETY: plain syscall on the process group created for this child
+        unsafe {
+            libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+        }
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn shell(script: &str) -> CommandSpec {
+        CommandSpec {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            env: BTreeMap::new(),
+            working_dir: None,
+        }
+    }
+
+    #[test]
+    fn test_captures_output_and_exit_code() {
+        let result = run_command("t", &shell("echo out; echo err >&2; exit 3"), &CancellationToken::new()).unwrap();
+        assert!(!result.success);
+        let data = result.data.unwrap();
+        assert_eq!(data["exit_code"], 3);
+        assert_eq!(data["stdout"], "out\n");
+        assert_eq!(data["stderr"], "err\n");
+    }
+
+    #[test]
+    fn test_env_and_working_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spec = shell("printf '%s %s' \"$GREETING\" \"$(pwd)\"");
+        spec.env.insert("GREETING".to_string(), "hello".to_string());
+        spec.working_dir = Some(dir.path().to_path_buf());
+
+        let result = run_command("t", &spec, &CancellationToken::new()).unwrap();
+        assert!(result.success);
+        let stdout = result.data.unwrap()["stdout"].as_str().unwrap().to_string();
+        let cwd = dir.path().canonicalize().unwrap();
+        assert_eq!(stdout, format!("hello {}", cwd.display()));
+    }
+
+    #[test]
+    fn test_missing_program_is_an_error() {
+        let spec = CommandSpec {
+            program: "definitely-not-a-real-program".to_string(),
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            working_dir: None,
+        };
+        assert!(run_command("t", &spec, &CancellationToken::new()).is_err());
+    }
+
+    #[test]
+    fn test_cancelled_command_is_killed() {
+        let token = CancellationToken::new().child(Some(Duration::from_millis(50)));
+        let started = std::time::Instant::now();
+        let result = run_command("t", &shell("echo started; sleep 10 & sleep 10"), &token).unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!result.success);
+        let data = result.data.unwrap();
+        assert!(data["exit_code"].is_null());
+        assert_eq!(data["stdout"], "started\n");
+    }
+
+    #[test]
+    fn test_background_child_does_not_hold_up_the_step() {
+        let token = CancellationToken::new().child(Some(Duration::from_secs(1)));
+        let started = std::time::Instant::now();
+        let result = run_command("t", &shell("sleep 30 & echo started"), &token).unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+        assert!(!token.is_cancelled());
+        assert!(result.success);
+        assert_eq!(result.data.unwrap()["stdout"], "started\n");
+    }
+}
@@ -0,0 +1,503 @@
+// src/config.rs
+/*
+ * Engine configuration layered from defaults, a TOML file, the environment and CLI flags
+ */
+
+use crate::output::OutputFormat;
+use crate::retry::RetryPolicy;
+use crate::{Result, WorkflowError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// File picked up from the working directory when no other is given
+pub const DEFAULT_CONFIG_FILE: &str = "workflowengine.toml";
+
+/// Prefix of the environment variables that override file settings
+pub const ENV_PREFIX: &str = "WORKFLOWENGINE_";
+
+/// Environment variable naming the config file; it is not a setting itself
+pub const CONFIG_FILE_ENV: &str = "WORKFLOWENGINE_CONFIG";
+
+/// How log lines are written to stderr
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// env_logger's human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log shippers
+    Json,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}' (expected text or json)", s)),
+        }
+    }
+}
+
+/// Settings shared by every command.
+///
+/// Build one with [`ConfigLoader`] to get the usual layering, or start from
+/// [`EngineConfig::default`] when embedding the engine.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EngineConfig {
+    #[serde(default)]
+    pub verbose: bool,
+    /// Maximum number of steps running at the same time
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    /// Where run journals are kept
+    #[serde(default = "default_state_dir")]
+    pub state_dir: PathBuf,
+    /// Retry policy for steps that do not declare their own
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
+    /// Timeout for steps that do not declare their own
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    /// Directories searched for workflows that `workflow` steps call by name
+    #[serde(default)]
+    pub library: Vec<PathBuf>,
+    /// File the CLI writes its metrics to, in the Prometheus text format
+    #[serde(default)]
+    pub metrics_file: Option<PathBuf>,
+    #[serde(skip)]
+    sources: BTreeMap<String, ConfigSource>,
+    #[serde(skip)]
+    warnings: Vec<String>,
+}
+
+fn default_workers() -> usize {
+    crate::default_workers()
+}
+
+fn default_state_dir() -> PathBuf {
+    PathBuf::from(".workflowengine/runs")
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            verbose: false,
+            workers: default_workers(),
+            state_dir: default_state_dir(),
+            retry: None,
+            timeout_ms: None,
+            log_format: LogFormat::default(),
+            output_format: OutputFormat::default(),
+            library: Vec::new(),
+            metrics_file: None,
+            sources: BTreeMap::new(),
+            warnings: Vec::new(),
+        }
+    }
+}
+
+/// Top-level keys of [`EngineConfig`]
+const SETTINGS: [&str; 9] = [
+    "verbose",
+    "workers",
+    "state_dir",
+    "retry",
+    "timeout_ms",
+    "log_format",
+    "output_format",
+    "library",
+    "metrics_file",
+];
+
+/// Settings holding a list of paths; from the environment they are split
+/// like `PATH`
+const PATH_LIST_SETTINGS: [&str; 1] = ["library"];
+
+/// Settings holding a string; from the environment they are taken as they
+/// are, so `2024` or `true` stay strings too
+const STRING_SETTINGS: [&str; 4] = ["state_dir", "metrics_file", "output_format", "log_format"];
+
+/// Where a setting came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    Default,
+    File(PathBuf),
+    /// Environment variable name
+    Env(String),
+    /// Command line flag, e.g. `--workers`
+    Flag(String),
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Default => write!(f, "default"),
+            ConfigSource::File(path) => write!(f, "file {}", path.display()),
+            ConfigSource::Env(name) => write!(f, "env {}", name),
+            ConfigSource::Flag(flag) => write!(f, "flag {}", flag),
+        }
+    }
+}
+
+impl EngineConfig {
+    /// Where the setting `key` (e.g. `workers` or `retry.max_attempts`) came from
+    pub fn source(&self, key: &str) -> &ConfigSource {
+        self.sources.get(key).unwrap_or(&ConfigSource::Default)
+    }
+
+    /// What loading the config found odd but not wrong, such as environment
+    /// variables that name no setting. Loading happens before logging is set
+    /// up, so these are logged once it is.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Every effective setting as `(key, value, source)`, with nested tables
+    /// flattened into dotted keys
+    pub fn entries(&self) -> Vec<(String, String, &ConfigSource)> {
+        let mut leaves = Vec::new();
+        if let Ok(value) = serde_json::to_value(self) {
+            flatten("", &value, &mut leaves);
+        }
+        leaves
+            .into_iter()
+            .map(|(key, value)| {
+                let source = self.source(&key);
+                (key, value, source)
+            })
+            .collect()
+    }
+}
+
+fn flatten(prefix: &str, value: &serde_json::Value, leaves: &mut Vec<(String, String)>) {
+    match value {
+        serde_json::Value::Object(fields) if !fields.is_empty() => {
+            for (key, value) in fields {
+                let key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                flatten(&key, value, leaves);
+            }
+        }
+        serde_json::Value::Null => leaves.push((prefix.to_string(), "none".to_string())),
+        serde_json::Value::String(text) => leaves.push((prefix.to_string(), text.clone())),
+        value => leaves.push((prefix.to_string(), value.to_string())),
+    }
+}
+
+/// Merges configuration layers; later layers win key by key.
+///
+/// ```no_run
+/// # use workflowengine::config::ConfigLoader;
+/// let config = ConfigLoader::new()
+///     .with_file("workflowengine.toml")?
+///     .with_env(std::env::vars())
+///     .with_override("workers", 2, "--workers")
+///     .load()?;
+/// # Ok::<(), workflowengine::WorkflowError>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct ConfigLoader {
+    table: toml::Table,
+    sources: BTreeMap<String, ConfigSource>,
+    warnings: Vec<String>,
+}
+
+impl ConfigLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the settings of a TOML file
+    pub fn with_file<P: AsRef<Path>>(mut self, path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let source = ConfigSource::File(path.to_path_buf());
+        let text = fs::read_to_string(path).map_err(|e| WorkflowError::from(e).with_path(path))?;
+        let table: toml::Table = text
+            .parse()
+            .map_err(|e: toml::de::Error| config_error(&source, e.message().trim()))?;
+        self.merge(table, &source);
+        Ok(self)
+    }
+
+    /// Adds `WORKFLOWENGINE_*` variables from `vars`; everything else is ignored.
+    ///
+    /// `WORKFLOWENGINE_STATE_DIR` sets `state_dir`, and `WORKFLOWENGINE_RETRY_MAX_ATTEMPTS`
+    /// sets `retry.max_attempts`. Values of string settings are taken as they
+    /// are; the others are read as TOML scalars when they parse as one and as
+    /// plain strings otherwise. `WORKFLOWENGINE_LIBRARY`
+    /// is split on the platform's path separator, like `PATH`. Variables that
+    /// name no setting are skipped with a warning in
+    /// [`EngineConfig::warnings`], since the prefix may be used for other things.
+    pub fn with_env<I: IntoIterator<Item = (String, String)>>(mut self, vars: I) -> Self {
+        let mut vars: Vec<(String, String)> = vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name != CONFIG_FILE_ENV)
+            .collect();
+        vars.sort();
+
+        for (name, raw) in vars {
+            let source = ConfigSource::Env(name.clone());
+            let key = name[ENV_PREFIX.len()..].to_ascii_lowercase();
+            let path: Vec<&str> = match key.strip_prefix("retry_") {
+                Some(field) => vec!["retry", field],
+                None => vec![key.as_str()],
+            };
+            if !SETTINGS.contains(&path[0]) {
+                self.warnings.push(format!("Ignoring {}: there is no setting '{}'", name, key));
+                continue;
+            }
+            let mut table = toml::Table::new();
+            insert_path(&mut table, &path, env_value(&key, &raw));
+            self.merge(table, &source);
+        }
+        self
+    }
+
+    /// Sets one dotted key, e.g. from a command line `flag`
+    pub fn with_override<V: Into<toml::Value>>(mut self, key: &str, value: V, flag: &str) -> Self {
+        let path: Vec<&str> = key.split('.').collect();
+        let mut table = toml::Table::new();
+        insert_path(&mut table, &path, value.into());
+        self.merge(table, &ConfigSource::Flag(flag.to_string()));
+        self
+    }
+
+    /// Resolves the layers into a config, rejecting unknown keys and bad values
+    pub fn load(self) -> Result<EngineConfig> {
+        for (key, source) in &self.sources {
+            let setting = key.split('.').next().unwrap_or_default();
+            if !SETTINGS.contains(&setting) {
+                return Err(config_error(source, &format!("unknown setting '{}'", key)));
+            }
+        }
+
+        let deserializer = toml::Value::Table(self.table);
+        let mut config: EngineConfig = serde_path_to_error::deserialize(deserializer).map_err(|e| {
+            let key = e.path().to_string();
+            let source = self
+                .sources
+                .iter()
+                .find(|(k, _)| **k == key || k.starts_with(&format!("{}.", key)) || key.starts_with(&format!("{}.", k)))
+                .map(|(_, source)| source.clone())
+                .unwrap_or(ConfigSource::Default);
+            // toml appends the key path on a second line; the key is reported separately
+            let message = e.into_inner().to_string().lines().next().unwrap_or_default().to_string();
+            if key == "." || key.is_empty() {
+                config_error(&source, message.trim())
+            } else {
+                config_error(&source, &format!("{}: {}", key, message.trim()))
+            }
+        })?;
+        if let Some(retry) = &config.retry {
+            retry.check().map_err(|message| {
+                let source = self.sources.iter().find(|(key, _)| key.starts_with("retry."));
+                config_error(source.map_or(&ConfigSource::Default, |(_, source)| source), &message)
+            })?;
+        }
+        config.workers = config.workers.max(1);
+        config.sources = self.sources;
+        config.warnings = self.warnings;
+        Ok(config)
+    }
+
+    fn merge(&mut self, layer: toml::Table, source: &ConfigSource) {
+        merge_table(&mut self.table, layer, "", source, &mut self.sources);
+    }
+}
+
+/// The config file to read: `explicit` if given, else `$WORKFLOWENGINE_CONFIG`,
+/// else `workflowengine.toml` when it exists in the working directory.
+pub fn config_file(explicit: Option<PathBuf>) -> Option<PathBuf> {
+    explicit
+        .or_else(|| std::env::var_os(CONFIG_FILE_ENV).map(PathBuf::from))
+        .or_else(|| Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|path| path.exists()))
+}
+
+fn config_error(source: &ConfigSource, message: &str) -> WorkflowError {
+    WorkflowError::Config {
+        origin: source.to_string(),
+        message: message.to_string(),
+    }
+}
+
+fn merge_table(
+    target: &mut toml::Table,
+    layer: toml::Table,
+    prefix: &str,
+    source: &ConfigSource,
+    sources: &mut BTreeMap<String, ConfigSource>,
+) {
+    for (key, value) in layer {
+        let path = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        match (target.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(nested)) => {
+                merge_table(existing, nested, &path, source, sources);
+            }
+            (_, toml::Value::Table(nested)) => {
+                let mut table = toml::Table::new();
+                merge_table(&mut table, nested, &path, source, sources);
+                target.insert(key, toml::Value::Table(table));
+            }
+            (_, value) => {
+                sources.insert(path, source.clone());
+                target.insert(key, value);
+            }
+        }
+    }
+}
+
+fn insert_path(table: &mut toml::Table, path: &[&str], value: toml::Value) {
+    match path {
+        [] => {}
+        [last] => {
+            table.insert(last.to_string(), value);
+        }
+        [first, rest @ ..] => {
+            let mut nested = toml::Table::new();
+            insert_path(&mut nested, rest, value);
+            table.insert(first.to_string(), toml::Value::Table(nested));
+        }
+    }
+}
+
+fn env_value(key: &str, raw: &str) -> toml::Value {
+    if PATH_LIST_SETTINGS.contains(&key) {
+        let paths = std::env::split_paths(raw).map(|path| toml::Value::String(path.display().to_string()));
+        return toml::Value::Array(paths.collect());
+    }
+    if STRING_SETTINGS.contains(&key) {
+        return toml::Value::String(raw.to_string());
+    }
+    format!("value = {}", raw)
+        .parse::<toml::Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .filter(|value| !value.is_table())
+        .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = ConfigLoader::new().load().unwrap();
+        assert_eq!(config, EngineConfig::default());
+        assert_eq!(config.source("workers"), &ConfigSource::Default);
+    }
+
+    #[test]
+    fn test_layers_override_each_other() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join(DEFAULT_CONFIG_FILE);
+        fs::write(
+            &file,
+            "workers = 2\nstate_dir = \"/var/lib/runs\"\noutput_format = \"csv\"\n\n[retry]\nmax_attempts = 4\njitter = 0.1\n",
+        )
+        .unwrap();
+
+        let config = ConfigLoader::new()
+            .with_file(&file)
+            .unwrap()
+            .with_env(env(&[
+                ("WORKFLOWENGINE_WORKERS", "6"),
+                ("WORKFLOWENGINE_RETRY_MAX_ATTEMPTS", "5"),
+                ("WORKFLOWENGINE_LOG_FORMAT", "json"),
+                ("WORKFLOWENGINE_CONFIG", "ignored.toml"),
+                ("HOME", "/root"),
+            ]))
+            .with_override("workers", 8, "--workers")
+            .load()
+            .unwrap();
+
+        assert_eq!(config.workers, 8);
+        assert_eq!(config.source("workers"), &ConfigSource::Flag("--workers".to_string()));
+        assert_eq!(config.state_dir, PathBuf::from("/var/lib/runs"));
+        assert_eq!(config.source("state_dir"), &ConfigSource::File(file.clone()));
+        assert_eq!(config.output_format, OutputFormat::Csv);
+        assert_eq!(config.log_format, LogFormat::Json);
+
+        let retry = config.retry.as_ref().unwrap();
+        assert_eq!(retry.max_attempts, 5);
+        assert_eq!(retry.jitter, 0.1);
+        assert_eq!(retry.initial_delay_ms, RetryPolicy::default().initial_delay_ms);
+        assert_eq!(
+            config.source("retry.max_attempts"),
+            &ConfigSource::Env("WORKFLOWENGINE_RETRY_MAX_ATTEMPTS".to_string())
+        );
+        assert_eq!(config.source("retry.jitter"), &ConfigSource::File(file));
+
+        let entries = config.entries();
+        let timeout = entries.iter().find(|(key, _, _)| key == "timeout_ms").unwrap();
+        assert_eq!((timeout.1.as_str(), timeout.2), ("none", &ConfigSource::Default));
+    }
+
+    #[test]
+    fn test_errors_name_their_source() {
+        let error = ConfigLoader::new()
+            .with_env(env(&[("WORKFLOWENGINE_WORKERS", "many")]))
+            .load()
+            .unwrap_err();
+        assert_eq!(error.exit_code(), 2);
+        let message = error.to_string();
+        assert!(message.contains("env WORKFLOWENGINE_WORKERS"), "{}", message);
+        assert!(message.contains("workers"), "{}", message);
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join(DEFAULT_CONFIG_FILE);
+        fs::write(&file, "workres = 4\n").unwrap();
+        let error = ConfigLoader::new().with_file(&file).unwrap().load().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("invalid configuration from file {}: unknown setting 'workres'", file.display())
+        );
+    }
+
+    #[test]
+    fn test_environment_variables() {
+        let library = std::env::join_paths(["/etc/workflows", "/opt/workflows"]).unwrap();
+        let config = ConfigLoader::new()
+            .with_env(env(&[
+                ("WORKFLOWENGINE_HOME", "/x"),
+                ("WORKFLOWENGINE_LIBRARY", library.to_str().unwrap()),
+            ]))
+            .load()
+            .unwrap();
+        assert_eq!(config.library, vec![PathBuf::from("/etc/workflows"), PathBuf::from("/opt/workflows")]);
+        assert_eq!(config.source("library"), &ConfigSource::Env("WORKFLOWENGINE_LIBRARY".to_string()));
+        assert_eq!(config.warnings(), ["Ignoring WORKFLOWENGINE_HOME: there is no setting 'home'"]);
+        assert!(ConfigLoader::new().load().unwrap().warnings().is_empty());
+
+        let config = ConfigLoader::new()
+            .with_env(env(&[("WORKFLOWENGINE_STATE_DIR", "2024"), ("WORKFLOWENGINE_METRICS_FILE", "true")]))
+            .load()
+            .unwrap();
+        assert_eq!(config.state_dir, PathBuf::from("2024"));
+        assert_eq!(config.metrics_file, Some(PathBuf::from("true")));
+    }
+}
@@ -0,0 +1,377 @@
+// src/daemon.rs
+/*
+ * Schedule daemon: starts runs of workflows at the times their `schedule` gives
+ */
+
+use crate::cancel::CancellationToken;
+use crate::schedule::{self, CatchUp, CronExpr};
+use crate::workflow::Workflow;
+use crate::{Result, WorkflowEngineProcessor, WorkflowError};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Most catch-up runs queued for one workflow with `catch_up: run_all`
+const MAX_CATCH_UP: usize = 1000;
+
+/// Longest the daemon sleeps before looking at the clock again, so it
+/// notices when the system clock is changed
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// Starts runs of scheduled workflows through a [`WorkflowEngineProcessor`].
+///
+/// A workflow never runs twice at the same time: a time that comes up while
+/// the previous run is still going is skipped. The last time each workflow
+/// was due is kept in a state file, so times missed while the daemon was
+/// down can be caught up on according to the workflow's [`CatchUp`] policy.
+#[derive(Debug)]
+pub struct Daemon {
+    processor: Arc<WorkflowEngineProcessor>,
+    entries: Vec<Entry>,
+    state_file: Option<PathBuf>,
+    runs: Vec<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    workflow: Arc<Workflow>,
+    cron: CronExpr,
+    timezone: Tz,
+    catch_up: CatchUp,
+    /// When the workflow was last due
+    last: Option<DateTime<Utc>>,
+    next: Option<DateTime<Utc>>,
+    /// Runs waiting to start, from catching up
+    pending: usize,
+    running: Arc<AtomicBool>,
+}
+
+impl Daemon {
+    /// A daemon for `workflows`, which must all have a `schedule`
+    pub fn new(processor: WorkflowEngineProcessor, workflows: Vec<Workflow>) -> Result<Self> {
+        let mut entries: Vec<Entry> = Vec::new();
+        for workflow in workflows {
+            let invalid = |message: String| WorkflowError::Config {
+                origin: format!("workflow '{}'", workflow.name),
+                message,
+            };
+            let Some(schedule) = &workflow.schedule else {
+                return Err(invalid("has no schedule".to_string()));
+            };
+            if entries.iter().any(|entry| entry.workflow.name == workflow.name) {
+                return Err(invalid("is scheduled twice; workflow names must be unique".to_string()));
+            }
+            let (cron, timezone) = schedule.parse().map_err(invalid)?;
+            let catch_up = schedule.catch_up;
+            entries.push(Entry {
+                workflow: Arc::new(workflow),
+                cron,
+                timezone,
+                catch_up,
+                last: None,
+                next: None,
+                pending: 0,
+                running: Arc::new(AtomicBool::new(false)),
+            });
+        }
+        Ok(Self {
+            processor: Arc::new(processor),
+            entries,
+            state_file: None,
+            runs: Vec::new(),
+        })
+    }
+
+    /// Remembers when each workflow was last due in `path`, a JSON object of
+    /// workflow names and times, and reads what an earlier daemon left there
+    pub fn with_state_file<P: Into<PathBuf>>(mut self, path: P) -> Result<Self> {
+        let path = path.into();
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                let times: BTreeMap<String, DateTime<Utc>> = serde_json::from_str(&text).map_err(|e| {
+                    WorkflowError::persistence("schedule", format!("cannot read {}", path.display())).with_source(e)
+                })?;
+                for entry in &mut self.entries {
+                    entry.last = times.get(&entry.workflow.name).copied();
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(WorkflowError::from(e).with_path(&path)),
+        }
+        self.state_file = Some(path);
+        Ok(self)
+    }
+
+    /// Starts runs until `token` is cancelled, then waits for the runs that
+    /// are still going, which are cancelled along with it.
+    pub fn run(&mut self, token: &CancellationToken) -> Result<()> {
+        self.catch_up(Utc::now());
+        for entry in &self.entries {
+            match entry.next {
+                Some(next) => info!("Workflow '{}' next runs at {}", entry.workflow.name, next.to_rfc3339()),
+                None => warn!("Workflow '{}' will never run: its schedule does not match any time", entry.workflow.name),
+            }
+        }
+        while !token.is_cancelled() {
+            self.tick(Utc::now(), token);
+            let wait = match self.next_wake() {
+                Some(at) => (at - Utc::now()).to_std().unwrap_or_default().min(MAX_SLEEP),
+                None => MAX_SLEEP,
+            };
+            token.sleep(wait);
+        }
+        info!("Schedule daemon stopping; waiting for {} running workflows", self.running());
+        self.wait();
+        Ok(())
+    }
+
+    // Works out what each workflow missed since it was last due and plans
+    // its next time
+    pub(crate) fn catch_up(&mut self, now: DateTime<Utc>) {
+        for entry in &mut self.entries {
+            // How many times were missed, and the latest of them; only the
+            // count is capped, so the whole window is caught up on once
+            let mut missed = 0;
+            let mut latest = None;
+            if let Some(mut time) = entry.last {
+                while let Some(next) = schedule::next_run(&entry.cron, &entry.timezone, time).filter(|next| *next <= now) {
+                    missed += 1;
+                    latest = Some(next);
+                    time = next;
+                }
+            }
+            if let Some(latest) = latest {
+                entry.pending = match entry.catch_up {
+                    CatchUp::Skip => 0,
+                    CatchUp::RunOnce => 1,
+                    CatchUp::RunAll => missed.min(MAX_CATCH_UP),
+                };
+                info!(
+                    "Workflow '{}' missed {} scheduled runs; catch_up {} starts {}",
+                    entry.workflow.name, missed, entry.catch_up, entry.pending
+                );
+                entry.last = Some(latest);
+            }
+            entry.next = schedule::next_run(&entry.cron, &entry.timezone, now);
+        }
+        self.save();
+    }
+
+    // Handles every time that has come by `now` and starts the runs that
+    // are waiting, one at a time per workflow. Returns the names of the
+    // workflows started.
+    pub(crate) fn tick(&mut self, now: DateTime<Utc>, token: &CancellationToken) -> Vec<String> {
+        let mut due = false;
+        for entry in &mut self.entries {
+            while let Some(next) = entry.next.filter(|next| *next <= now) {
+                due = true;
+                entry.last = Some(next);
+                entry.next = schedule::next_run(&entry.cron, &entry.timezone, next.max(now));
+                if entry.running.load(Ordering::SeqCst) || entry.pending > 0 {
+                    warn!(
+                        "Skipping the {} run of workflow '{}': the previous run is still going",
+                        next.to_rfc3339(),
+                        entry.workflow.name
+                    );
+                } else {
+                    entry.pending = 1;
+                }
+            }
+        }
+        if due {
+            self.save();
+        }
+
+        self.runs.retain(|run| !run.is_finished());
+        let mut started = Vec::new();
+        for entry in &mut self.entries {
+            if entry.pending == 0 || entry.running.load(Ordering::SeqCst) {
+                continue;
+            }
+            entry.pending -= 1;
+            info!("Starting scheduled run of workflow '{}'", entry.workflow.name);
+            let spawned = spawn_run(
+                &self.processor,
+                &entry.workflow,
+                serde_json::Map::new(),
+                &entry.running,
+                token,
+            );
+            match spawned {
+                Ok(run) => {
+                    self.runs.push(run);
+                    started.push(entry.workflow.name.clone());
+                }
+                Err(e) => error!("Could not start workflow '{}': {}", entry.workflow.name, e),
+            }
+        }
+        started
+    }
+
+    // The next time something needs doing: a schedule coming due, or a
+    // queued run that can start once the one before it is done
+    fn next_wake(&self) -> Option<DateTime<Utc>> {
+        if self.entries.iter().any(|entry| entry.pending > 0) {
+            return Some(Utc::now() + chrono::Duration::seconds(1));
+        }
+        self.entries.iter().filter_map(|entry| entry.next).min()
+    }
+
+    fn running(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.running.load(Ordering::SeqCst)).count()
+    }
+
+    /// Waits for every run started so far
+    pub fn wait(&mut self) {
+        for run in self.runs.drain(..) {
+            let _ = run.join();
+        }
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        let times: BTreeMap<&str, DateTime<Utc>> = self
+            .entries
+            .iter()
+            .filter_map(|entry| entry.last.map(|last| (entry.workflow.name.as_str(), last)))
+            .collect();
+        // Written next to the real file and renamed, so a crash never leaves half a file
+        let temporary = path.with_extension("json.tmp");
+        let written = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&temporary, serde_json::to_string_pretty(&times).unwrap_or_default()))
+            .and_then(|_| fs::rename(&temporary, path));
+        if let Err(e) = written {
+            error!("Could not save schedule state to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Runs `workflow` on a thread of its own with `inputs`, setting `running`
+/// until it is done. The run stops when `token` is cancelled.
+pub(crate) fn spawn_run(
+    processor: &Arc<WorkflowEngineProcessor>,
+    workflow: &Arc<Workflow>,
+    inputs: serde_json::Map<String, serde_json::Value>,
+    running: &Arc<AtomicBool>,
+    token: &CancellationToken,
+) -> std::io::Result<JoinHandle<()>> {
+    let processor = Arc::clone(processor);
+    let workflow = Arc::clone(workflow);
+    let flag = Arc::clone(running);
+    let token = token.child(None);
+    running.store(true, Ordering::SeqCst);
+    let spawned = thread::Builder::new().name(format!("run-{}", workflow.name)).spawn(move || {
+        match processor.run_workflow_with_inputs(&workflow, &inputs, &token) {
+            Ok(result) => info!("Run {} of workflow '{}' finished: {}", result.run_id, workflow.name, result.status),
+            Err(e) => error!("Run of workflow '{}' failed: {}", workflow.name, e.full_message()),
+        }
+        flag.store(false, Ordering::SeqCst);
+    });
+    if spawned.is_err() {
+        running.store(false, Ordering::SeqCst);
+    }
+    spawned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().with_timezone(&Utc)
+    }
+
+    fn scheduled(name: &str, catch_up: &str) -> Workflow {
+        Workflow::from_json(&format!(
+            r#"{{"name": "{}", "schedule": {{"cron": "0 * * * *", "catch_up": "{}"}}, "steps": [{{"id": "a"}}]}}"#,
+            name, catch_up
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_catch_up_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = dir.path().join("schedule.json");
+        fs::write(&state, r#"{"skip": "2024-03-01T09:00:00Z", "once": "2024-03-01T09:00:00Z", "all": "2024-03-01T09:00:00Z"}"#)
+            .unwrap();
+        let workflows = vec![scheduled("skip", "skip"), scheduled("once", "run_once"), scheduled("all", "run_all")];
+        let mut daemon = Daemon::new(WorkflowEngineProcessor::new(false), workflows)
+            .unwrap()
+            .with_state_file(&state)
+            .unwrap();
+
+        // Three hours were missed: 10:00, 11:00 and 12:00
+        let now = utc("2024-03-01T12:30:00Z");
+        daemon.catch_up(now);
+        let pending: Vec<usize> = daemon.entries.iter().map(|entry| entry.pending).collect();
+        assert_eq!(pending, vec![0, 1, 3]);
+        assert_eq!(daemon.entries[0].next, Some(utc("2024-03-01T13:00:00Z")));
+
+        let token = CancellationToken::new();
+        assert_eq!(daemon.tick(now, &token), vec!["once", "all"]);
+        daemon.wait();
+        assert_eq!(daemon.tick(now, &token), vec!["all"]);
+        daemon.wait();
+        assert_eq!(daemon.tick(now, &token), vec!["all"]);
+        daemon.wait();
+        assert!(daemon.tick(now, &token).is_empty());
+        assert_eq!(daemon.processor.processed_count(), 4);
+
+        let saved: BTreeMap<String, DateTime<Utc>> = serde_json::from_str(&fs::read_to_string(&state).unwrap()).unwrap();
+        assert_eq!(saved["all"], utc("2024-03-01T12:00:00Z"));
+    }
+
+    #[test]
+    fn test_capped_catch_up_covers_the_whole_window() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = dir.path().join("schedule.json");
+        fs::write(&state, r#"{"all": "2024-01-01T00:00:00Z"}"#).unwrap();
+        let mut daemon = Daemon::new(WorkflowEngineProcessor::new(false), vec![scheduled("all", "run_all")])
+            .unwrap()
+            .with_state_file(&state)
+            .unwrap();
+
+        // Two months of hourly runs, more than are queued
+        daemon.catch_up(utc("2024-03-01T12:30:00Z"));
+        assert_eq!(daemon.entries[0].pending, MAX_CATCH_UP);
+        assert_eq!(daemon.entries[0].last, Some(utc("2024-03-01T12:00:00Z")));
+    }
+
+    #[test]
+    fn test_overlapping_runs_are_skipped() {
+        let mut daemon = Daemon::new(WorkflowEngineProcessor::new(false), vec![scheduled("hourly", "skip")]).unwrap();
+        daemon.catch_up(utc("2024-03-01T09:30:00Z"));
+        let token = CancellationToken::new();
+        assert!(daemon.tick(utc("2024-03-01T09:59:00Z"), &token).is_empty());
+
+        // While a run is still going, the times that come up are skipped
+        daemon.entries[0].running.store(true, Ordering::SeqCst);
+        assert!(daemon.tick(utc("2024-03-01T10:00:00Z"), &token).is_empty());
+        assert!(daemon.tick(utc("2024-03-01T11:00:00Z"), &token).is_empty());
+        assert_eq!(daemon.entries[0].pending, 0);
+        daemon.entries[0].running.store(false, Ordering::SeqCst);
+        assert!(daemon.tick(utc("2024-03-01T11:30:00Z"), &token).is_empty());
+        assert_eq!(daemon.tick(utc("2024-03-01T12:00:00Z"), &token), vec!["hourly"]);
+        daemon.wait();
+        assert_eq!(daemon.entries[0].next, Some(utc("2024-03-01T13:00:00Z")));
+    }
+
+    #[test]
+    fn test_unscheduled_workflows_are_refused() {
+        let workflow = Workflow::from_json(r#"{"name": "manual", "steps": [{"id": "a"}]}"#).unwrap();
+        let error = Daemon::new(WorkflowEngineProcessor::new(false), vec![workflow]).unwrap_err();
+        assert_eq!(error.to_string(), "invalid configuration from workflow 'manual': has no schedule");
+    }
+}
@@ -0,0 +1,302 @@
+// src/error.rs
+/*
+ * Error type shared by the library and the CLI
+ */
+
+use crate::format::ParseError;
+use crate::workflow::ValidationError;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Everything that can go wrong while loading, running or resuming a workflow.
+///
+/// `Display` describes the failure itself; the underlying cause, if any, is
+/// available through [`Error::source`]. Use [`WorkflowError::full_message`] to
+/// render the whole chain.
+#[derive(Debug)]
+pub enum WorkflowError {
+    /// A definition could not be read as JSON, YAML or TOML
+    Parse {
+        path: Option<PathBuf>,
+        source: Box<ParseError>,
+    },
+    /// A definition parsed but does not describe a runnable workflow
+    Validation {
+        path: Option<PathBuf>,
+        source: ValidationError,
+    },
+    /// A step could not run or did not succeed
+    StepFailed {
+        step: String,
+        message: String,
+        source: Option<Box<dyn Error + Send + Sync>>,
+    },
+    /// A step or workflow timeout expired; `step` is the first step that was stopped
+    Timeout { step: Option<String> },
+    /// The run was cancelled, e.g. on Ctrl-C
+    Cancelled,
+    /// Reading a definition or writing results failed
+    Io {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+    /// A config file, environment variable or flag has an invalid setting;
+    /// `origin` says which one
+    Config { origin: String, message: String },
+    /// A run journal could not be written, read or resumed
+    Persistence {
+        run_id: String,
+        message: String,
+        source: Option<Box<dyn Error + Send + Sync>>,
+    },
+    /// Several inputs failed independently, e.g. files given to `validate`
+    Multiple(Vec<WorkflowError>),
+}
+
+impl WorkflowError {
+    pub fn step_failed<S: Into<String>, M: Into<String>>(step: S, message: M) -> Self {
+        WorkflowError::StepFailed {
+            step: step.into(),
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    pub fn persistence<S: Into<String>, M: Into<String>>(run_id: S, message: M) -> Self {
+        WorkflowError::Persistence {
+            run_id: run_id.into(),
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// Attaches the underlying cause to a step or persistence error
+    pub fn with_source<E: Error + Send + Sync + 'static>(mut self, cause: E) -> Self {
+        match &mut self {
+            WorkflowError::StepFailed { source, .. } | WorkflowError::Persistence { source, .. } => {
+                *source = Some(Box::new(cause));
+            }
+            _ => {}
+        }
+        self
+    }
+
+    /// Records the file a parse, validation or I/O error came from
+    pub fn with_path<P: Into<PathBuf>>(mut self, file: P) -> Self {
+        match &mut self {
+            WorkflowError::Parse { path, .. }
+            | WorkflowError::Validation { path, .. }
+            | WorkflowError::Io { path, .. } => *path = Some(file.into()),
+            _ => {}
+        }
+        self
+    }
+
+    /// Id of the step the error is about, when there is one
+    pub fn step_id(&self) -> Option<&str> {
+        match self {
+            WorkflowError::Parse { source, .. } => source.step.as_deref(),
+            WorkflowError::Validation { source, .. } => match source {
+                ValidationError::DuplicateStep(step)
+                | ValidationError::UnknownDependency { step, .. }
+                | ValidationError::InvalidStep { step, .. } => Some(step),
+                ValidationError::NoSteps
+                | ValidationError::EmptyStepId
+                | ValidationError::Cycle(_)
+                | ValidationError::InvalidSchema(_)
+                | ValidationError::InvalidSchedule(_)
+                | ValidationError::InvalidWatch(_)
+                | ValidationError::InvalidInput { .. }
+                | ValidationError::InvalidOutput { .. } => None,
+            },
+            WorkflowError::StepFailed { step, .. } => Some(step),
+            WorkflowError::Timeout { step } => step.as_deref(),
+            WorkflowError::Cancelled
+            | WorkflowError::Io { .. }
+            | WorkflowError::Config { .. }
+            | WorkflowError::Persistence { .. }
+            | WorkflowError::Multiple(_) => None,
+        }
+    }
+
+    /// The failures this error stands for: those of a `Multiple`, else itself
+    pub fn errors(&self) -> Vec<&WorkflowError> {
+        match self {
+            WorkflowError::Multiple(errors) => errors.iter().collect(),
+            error => vec![error],
+        }
+    }
+
+    /// Process exit code the CLI uses for this kind of error.
+    ///
+    /// | Code | Error |
+    /// |------|-------|
+    /// | 1    | a step failed |
+    /// | 2    | configuration error |
+    /// | 3    | parse error |
+    /// | 4    | validation error |
+    /// | 5    | I/O error |
+    /// | 6    | persistence error |
+    /// | 124  | timeout |
+    /// | 130  | cancelled |
+    ///
+    /// Several failures exit with the code of the most severe one: I/O
+    /// errors before parse errors before validation errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            WorkflowError::StepFailed { .. } => 1,
+            WorkflowError::Config { .. } => 2,
+            WorkflowError::Parse { .. } => 3,
+            WorkflowError::Validation { .. } => 4,
+            WorkflowError::Io { .. } => 5,
+            WorkflowError::Persistence { .. } => 6,
+            WorkflowError::Timeout { .. } => 124,
+            WorkflowError::Cancelled => 130,
+            WorkflowError::Multiple(errors) => errors
+                .iter()
+                .max_by_key(|error| match error {
+                    WorkflowError::Io { .. } => 3,
+                    WorkflowError::Parse { .. } => 2,
+                    WorkflowError::Validation { .. } => 1,
+                    _ => 0,
+                })
+                .map_or(1, |error| error.exit_code()),
+        }
+    }
+
+    /// The error followed by all of its causes, separated by `: `
+    pub fn full_message(&self) -> String {
+        let mut message = self.to_string();
+        let mut cause = self.source();
+        while let Some(error) = cause {
+            message.push_str(": ");
+            message.push_str(&error.to_string());
+            cause = error.source();
+        }
+        message
+    }
+}
+
+impl fmt::Display for WorkflowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkflowError::Parse { path: Some(path), .. } => write!(f, "cannot parse {}", path.display()),
+            WorkflowError::Parse { path: None, .. } => write!(f, "cannot parse workflow"),
+            WorkflowError::Validation { path: Some(path), .. } => write!(f, "invalid workflow {}", path.display()),
+            WorkflowError::Validation { path: None, .. } => write!(f, "invalid workflow"),
+            WorkflowError::StepFailed { step, message, .. } => write!(f, "step '{}' failed: {}", step, message),
+            WorkflowError::Timeout { step: Some(step) } => write!(f, "step '{}' timed out", step),
+            WorkflowError::Timeout { step: None } => write!(f, "workflow timed out"),
+            WorkflowError::Cancelled => write!(f, "workflow run was cancelled"),
+            WorkflowError::Io { path: Some(path), .. } => write!(f, "cannot access {}", path.display()),
+            WorkflowError::Io { path: None, .. } => write!(f, "I/O error"),
+            WorkflowError::Config { origin, message } => write!(f, "invalid configuration from {}: {}", origin, message),
+            WorkflowError::Persistence { run_id, message, .. } => write!(f, "run '{}': {}", run_id, message),
+            WorkflowError::Multiple(errors) => write!(f, "{} inputs failed", errors.len()),
+        }
+    }
+}
+
+impl Error for WorkflowError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WorkflowError::Parse { source, .. } => Some(source.as_ref()),
+            WorkflowError::Validation { source, .. } => Some(source),
+            WorkflowError::Io { source, .. } => Some(source),
+            WorkflowError::StepFailed { source, .. } | WorkflowError::Persistence { source, .. } => {
+                source.as_deref().map(|source| source as &(dyn Error + 'static))
+            }
+            WorkflowError::Timeout { .. }
+            | WorkflowError::Cancelled
+            | WorkflowError::Config { .. }
+            | WorkflowError::Multiple(_) => None,
+        }
+    }
+}
+
+impl From<ParseError> for WorkflowError {
+    fn from(source: ParseError) -> Self {
+        WorkflowError::Parse {
+            path: None,
+            source: Box::new(source),
+        }
+    }
+}
+
+impl From<ValidationError> for WorkflowError {
+    fn from(source: ValidationError) -> Self {
+        WorkflowError::Validation { path: None, source }
+    }
+}
+
+impl From<io::Error> for WorkflowError {
+    fn from(source: io::Error) -> Self {
+        WorkflowError::Io { path: None, source }
+    }
+}
+
+impl From<serde_json::Error> for WorkflowError {
+    fn from(error: serde_json::Error) -> Self {
+        WorkflowError::Io {
+            path: None,
+            source: error.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workflow::Workflow;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_exit_codes_are_distinct() {
+        let errors = [
+            Workflow::from_json("{").unwrap_err(),
+            Workflow::from_json(r#"{"name": "w", "steps": []}"#).unwrap_err(),
+            WorkflowError::step_failed("a", "boom"),
+            WorkflowError::Timeout { step: None },
+            WorkflowError::Cancelled,
+            io::Error::new(io::ErrorKind::NotFound, "gone").into(),
+            WorkflowError::persistence("r1", "journal is empty"),
+            WorkflowError::Config { origin: "default".to_string(), message: "bad".to_string() },
+        ];
+        let codes: HashSet<i32> = errors.iter().map(|e| e.exit_code()).collect();
+        assert_eq!(codes.len(), errors.len());
+        assert!(!codes.contains(&0));
+    }
+
+    #[test]
+    fn test_multiple_errors_exit_with_the_most_severe() {
+        let error = WorkflowError::Multiple(vec![
+            Workflow::from_json(r#"{"name": "w", "steps": []}"#).unwrap_err(),
+            Workflow::from_json("{").unwrap_err(),
+            Workflow::from_json(r#"{"name": "v", "steps": []}"#).unwrap_err(),
+        ]);
+        assert_eq!(error.exit_code(), 3);
+        assert_eq!(error.to_string(), "3 inputs failed");
+        assert_eq!(error.errors().len(), 3);
+        assert_eq!(WorkflowError::Cancelled.errors().len(), 1);
+    }
+
+    #[test]
+    fn test_step_id_and_source_chain() {
+        let json = r#"{"name": "w", "steps": [{"id": "a", "depends_on": ["missing"]}]}"#;
+        let error = Workflow::from_json(json).unwrap_err().with_path("w.json");
+        assert!(matches!(error, WorkflowError::Validation { .. }));
+        assert_eq!(error.step_id(), Some("a"));
+        assert_eq!(
+            error.full_message(),
+            "invalid workflow w.json: step 'a' depends on unknown step 'missing'"
+        );
+
+        let error = WorkflowError::step_failed("b", "could not start 'x'")
+            .with_source(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+        assert_eq!(error.step_id(), Some("b"));
+        assert_eq!(error.source().unwrap().to_string(), "no such file");
+        assert_eq!(error.full_message(), "step 'b' failed: could not start 'x': no such file");
+    }
+}
@@ -0,0 +1,150 @@
+// src/events.rs
+/*
+ * Run lifecycle events emitted by the executor
+ */
+
+use crate::workflow::Workflow;
+use crate::{RunStatus, StepResult};
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+
+/// Events a subscriber of an [`EventBroadcast`] may fall behind by before it
+/// is dropped
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// Something that happened during a workflow run.
+///
+/// `run_started`, `step_finished` and `run_finished` are emitted in order
+/// from the thread coordinating the run, so a sink sees the steps of one run
+/// in the order they finished. `step_started` and `retry_scheduled` come
+/// from the worker running the step, as they happen.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RunEvent {
+    RunStarted {
+        run_id: String,
+        /// Definition being run, so a run can be resumed from its events alone
+        workflow: Box<Workflow>,
+        at: DateTime<Utc>,
+        /// True when a previously interrupted run is picked up again
+        #[serde(default)]
+        resumed: bool,
+        /// The run and step that started this one, for sub-workflow runs
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        parent: Option<ParentRun>,
+    },
+    StepStarted {
+        run_id: String,
+        step: String,
+        /// 1 for the first attempt, higher for retries
+        attempt: u32,
+        at: DateTime<Utc>,
+    },
+    /// An attempt failed and the step will be tried again after `delay_ms`
+    RetryScheduled {
+        run_id: String,
+        step: String,
+        /// The attempt that failed
+        attempt: u32,
+        delay_ms: u64,
+        error: String,
+        at: DateTime<Utc>,
+    },
+    StepFinished {
+        run_id: String,
+        step: StepResult,
+    },
+    RunFinished {
+        run_id: String,
+        status: RunStatus,
+        at: DateTime<Utc>,
+        /// The workflow's `outputs`, evaluated at the end of the run
+        #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+        outputs: serde_json::Map<String, serde_json::Value>,
+    },
+}
+
+/// Where a sub-workflow run was started from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParentRun {
+    pub run_id: String,
+    /// Id of the `workflow` step that started the child run
+    pub step: String,
+}
+
+impl RunEvent {
+    /// The `event` tag, e.g. `step_finished`
+    pub fn name(&self) -> &'static str {
+        match self {
+            RunEvent::RunStarted { .. } => "run_started",
+            RunEvent::StepStarted { .. } => "step_started",
+            RunEvent::RetryScheduled { .. } => "retry_scheduled",
+            RunEvent::StepFinished { .. } => "step_finished",
+            RunEvent::RunFinished { .. } => "run_finished",
+        }
+    }
+
+    pub fn run_id(&self) -> &str {
+        match self {
+            RunEvent::RunStarted { run_id, .. }
+            | RunEvent::StepStarted { run_id, .. }
+            | RunEvent::RetryScheduled { run_id, .. }
+            | RunEvent::StepFinished { run_id, .. }
+            | RunEvent::RunFinished { run_id, .. } => run_id,
+        }
+    }
+}
+
+/// Receives the events of every run on a processor.
+///
+/// Sinks are called synchronously, so they should return quickly.
+pub trait EventSink: Send + Sync {
+    /// Claims `run_id` for a run about to start. Returns `false` if the sink
+    /// already knows a run by that id, so the processor picks another one.
+    fn reserve(&self, _run_id: &str) -> bool {
+        true
+    }
+
+    fn emit(&self, event: &RunEvent);
+}
+
+/// Hands every event to any number of subscribers, e.g. clients of the
+/// server's event stream.
+#[derive(Debug, Default)]
+pub struct EventBroadcast {
+    subscribers: Mutex<Vec<SyncSender<RunEvent>>>,
+}
+
+impl EventBroadcast {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receives the events emitted from now on. A subscriber that falls too
+    /// far behind is dropped, which disconnects its receiver.
+    pub fn subscribe(&self) -> Receiver<RunEvent> {
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_BUFFER);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+}
+
+impl EventSink for EventBroadcast {
+    fn emit(&self, event: &RunEvent) {
+        self.subscribers.lock().unwrap().retain(|subscriber| match subscriber.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("Dropping an event subscriber that is {} events behind", SUBSCRIBER_BUFFER);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
@@ -0,0 +1,1025 @@
+// src/expr.rs
+/*
+ * Expression language for `when` conditions and `${{ ... }}` interpolation
+ */
+
+use serde_json::{Map, Value};
+use std::fmt;
+
+/// Longest expression accepted, in bytes
+const MAX_LENGTH: usize = 4096;
+
+/// Deepest nesting of sub-expressions the parser accepts
+const MAX_DEPTH: usize = 64;
+
+/// Why an expression could not be parsed, checked or evaluated
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExprError {
+    pub message: String,
+}
+
+impl ExprError {
+    fn new<M: Into<String>>(message: M) -> Self {
+        Self { message: message.into() }
+    }
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+pub(crate) type ExprResult<T> = std::result::Result<T, ExprError>;
+
+/// A parsed expression such as `steps.fetch.output.count > 10`.
+///
+/// Expressions are pure: they read the scope they are evaluated against and
+/// can neither loop nor call anything but a handful of built-in functions.
+/// Operators are strictly typed; comparing a string with a number, or using
+/// a number as a condition, is an error rather than a coercion.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Literal(Value),
+    Name(String),
+    Member(Box<Node>, String),
+    Index(Box<Node>, Box<Node>),
+    Not(Box<Node>),
+    Negate(Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+    Call(Function, Vec<Node>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinaryOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Or => "||",
+            BinaryOp::And => "&&",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Function {
+    Len,
+    Contains,
+    StartsWith,
+    EndsWith,
+}
+
+impl Function {
+    fn lookup(name: &str) -> Option<Self> {
+        match name {
+            "len" => Some(Function::Len),
+            "contains" => Some(Function::Contains),
+            "starts_with" => Some(Function::StartsWith),
+            "ends_with" => Some(Function::EndsWith),
+            _ => None,
+        }
+    }
+
+    fn arity(self) -> usize {
+        match self {
+            Function::Len => 1,
+            _ => 2,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Function::Len => "len",
+            Function::Contains => "contains",
+            Function::StartsWith => "starts_with",
+            Function::EndsWith => "ends_with",
+        }
+    }
+}
+
+/// Static type of a sub-expression; `Any` when it depends on runtime data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Any,
+    Null,
+    Bool,
+    Number,
+    String,
+    Array,
+    Object,
+}
+
+impl Type {
+    pub fn of(value: &Value) -> Self {
+        match value {
+            Value::Null => Type::Null,
+            Value::Bool(_) => Type::Bool,
+            Value::Number(_) => Type::Number,
+            Value::String(_) => Type::String,
+            Value::Array(_) => Type::Array,
+            Value::Object(_) => Type::Object,
+        }
+    }
+
+    fn accepts(self, other: Type) -> bool {
+        self == Type::Any || other == Type::Any || self == other
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Type::Any => "any",
+            Type::Null => "null",
+            Type::Bool => "boolean",
+            Type::Number => "number",
+            Type::String => "string",
+            Type::Array => "array",
+            Type::Object => "object",
+        })
+    }
+}
+
+/// One segment of a reference like `steps.fetch.output.items[0]`; `None`
+/// for an index computed at runtime
+pub type PathSegment = Option<String>;
+
+impl Expression {
+    pub fn parse(source: &str) -> ExprResult<Self> {
+        if source.len() > MAX_LENGTH {
+            return Err(ExprError::new(format!("expression is longer than {} bytes", MAX_LENGTH)));
+        }
+        let tokens = lex(source)?;
+        let mut parser = Parser { tokens, position: 0, depth: 0 };
+        let root = parser.expression()?;
+        match parser.peek() {
+            Token::End => Ok(Self { source: source.trim().to_string(), root }),
+            token => Err(ExprError::new(format!("unexpected {} in `{}`", token, source.trim()))),
+        }
+    }
+
+    /// Parses a `when` condition, written either bare or wrapped in `${{ }}`
+    pub fn parse_condition(source: &str) -> ExprResult<Self> {
+        let trimmed = source.trim();
+        let inner = trimmed
+            .strip_prefix("${{")
+            .and_then(|rest| rest.strip_suffix("}}"))
+            .unwrap_or(trimmed);
+        Self::parse(inner)
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Every reference the expression reads, e.g. `["steps", "fetch", "output"]`
+    pub fn references(&self) -> Vec<Vec<PathSegment>> {
+        let mut references = Vec::new();
+        collect_references(&self.root, &mut references);
+        references
+    }
+
+    /// Type-checks the expression; `resolve` gives the type of each reference
+    /// or explains why it is invalid.
+    pub fn check<R>(&self, resolve: &R) -> ExprResult<Type>
+    where
+        R: Fn(&[PathSegment]) -> std::result::Result<Type, String> + ?Sized,
+    {
+        infer(&self.root, resolve).map_err(|message| ExprError::new(format!("`{}`: {}", self.source, message)))
+    }
+
+    pub fn evaluate(&self, scope: &Value) -> ExprResult<Value> {
+        evaluate(&self.root, scope).map_err(|message| ExprError::new(format!("`{}`: {}", self.source, message)))
+    }
+
+    /// Evaluates a condition, which must produce a boolean
+    pub fn evaluate_condition(&self, scope: &Value) -> ExprResult<bool> {
+        match self.evaluate(scope)? {
+            Value::Bool(value) => Ok(value),
+            other => Err(ExprError::new(format!(
+                "`{}`: condition must be a boolean, got {}",
+                self.source,
+                Type::of(&other)
+            ))),
+        }
+    }
+}
+
+fn collect_references(node: &Node, references: &mut Vec<Vec<PathSegment>>) {
+    if let Some(path) = reference_path(node) {
+        references.push(path);
+    }
+    match node {
+        Node::Literal(_) | Node::Name(_) => {}
+        Node::Member(base, _) => {
+            if reference_path(base).is_none() {
+                collect_references(base, references);
+            }
+        }
+        Node::Index(base, index) => {
+            if reference_path(base).is_none() {
+                collect_references(base, references);
+            }
+            collect_references(index, references);
+        }
+        Node::Not(inner) | Node::Negate(inner) => collect_references(inner, references),
+        Node::Binary(_, left, right) => {
+            collect_references(left, references);
+            collect_references(right, references);
+        }
+        Node::Call(_, args) => args.iter().for_each(|arg| collect_references(arg, references)),
+    }
+}
+
+// The path of a member chain rooted at a name, e.g. `steps.fetch.output`
+fn reference_path(node: &Node) -> Option<Vec<PathSegment>> {
+    match node {
+        Node::Name(name) => Some(vec![Some(name.clone())]),
+        Node::Member(base, field) => {
+            let mut path = reference_path(base)?;
+            path.push(Some(field.clone()));
+            Some(path)
+        }
+        Node::Index(base, index) => {
+            let mut path = reference_path(base)?;
+            path.push(match index.as_ref() {
+                Node::Literal(Value::String(key)) => Some(key.clone()),
+                Node::Literal(Value::Number(n)) => Some(n.to_string()),
+                _ => None,
+            });
+            Some(path)
+        }
+        _ => None,
+    }
+}
+
+fn infer<R>(node: &Node, resolve: &R) -> std::result::Result<Type, String>
+where
+    R: Fn(&[PathSegment]) -> std::result::Result<Type, String> + ?Sized,
+{
+    if let Some(path) = reference_path(node) {
+        if let Node::Index(_, index) = node {
+            infer(index, resolve)?;
+        }
+        return resolve(&path);
+    }
+    match node {
+        Node::Literal(value) => Ok(Type::of(value)),
+        Node::Name(_) => Ok(Type::Any),
+        Node::Member(base, field) => match infer(base, resolve)? {
+            Type::Any | Type::Object | Type::Null => Ok(Type::Any),
+            other => Err(format!("cannot read field '{}' of a {}", field, other)),
+        },
+        Node::Index(base, index) => {
+            let base = infer(base, resolve)?;
+            let index = infer(index, resolve)?;
+            match (base, index) {
+                (Type::Array, Type::Number | Type::Any) | (Type::Object, Type::String | Type::Any) => Ok(Type::Any),
+                (Type::Any | Type::Null, _) => Ok(Type::Any),
+                (base, index) => Err(format!("cannot index a {} with a {}", base, index)),
+            }
+        }
+        Node::Not(inner) => expect(Type::Bool, infer(inner, resolve)?, "operand of `!`").map(|_| Type::Bool),
+        Node::Negate(inner) => expect(Type::Number, infer(inner, resolve)?, "operand of `-`").map(|_| Type::Number),
+        Node::Binary(op, left, right) => {
+            let (left, right) = (infer(left, resolve)?, infer(right, resolve)?);
+            let operands = format!("operands of `{}`", op.symbol());
+            match op {
+                BinaryOp::Or | BinaryOp::And => {
+                    expect(Type::Bool, left, &operands)?;
+                    expect(Type::Bool, right, &operands)?;
+                    Ok(Type::Bool)
+                }
+                BinaryOp::Eq | BinaryOp::Ne => Ok(Type::Bool),
+                BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => {
+                    comparable(left, right, *op)?;
+                    Ok(Type::Bool)
+                }
+                BinaryOp::Add => match (left, right) {
+                    (Type::String, Type::String) => Ok(Type::String),
+                    (Type::Number, Type::Number) => Ok(Type::Number),
+                    (Type::Any, Type::String | Type::Number) => Ok(right),
+                    (Type::String | Type::Number, Type::Any) => Ok(left),
+                    (Type::Any, Type::Any) => Ok(Type::Any),
+                    _ => Err(format!("cannot add a {} and a {}", left, right)),
+                },
+                BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => {
+                    expect(Type::Number, left, &operands)?;
+                    expect(Type::Number, right, &operands)?;
+                    Ok(Type::Number)
+                }
+            }
+        }
+        Node::Call(function, args) => {
+            let types = args.iter().map(|arg| infer(arg, resolve)).collect::<std::result::Result<Vec<_>, _>>()?;
+            let argument = format!("argument of {}()", function.name());
+            match function {
+                Function::Len => match types[0] {
+                    Type::Any | Type::String | Type::Array | Type::Object => Ok(Type::Number),
+                    other => Err(format!("{} must be a string, array or object, got {}", argument, other)),
+                },
+                Function::Contains => match types[0] {
+                    Type::Any | Type::Array => Ok(Type::Bool),
+                    Type::String => expect(Type::String, types[1], &argument).map(|_| Type::Bool),
+                    other => Err(format!("{} must be a string or array, got {}", argument, other)),
+                },
+                Function::StartsWith | Function::EndsWith => {
+                    expect(Type::String, types[0], &argument)?;
+                    expect(Type::String, types[1], &argument)?;
+                    Ok(Type::Bool)
+                }
+            }
+        }
+    }
+}
+
+fn expect(expected: Type, actual: Type, what: &str) -> std::result::Result<Type, String> {
+    if expected.accepts(actual) {
+        Ok(expected)
+    } else {
+        Err(format!("{} must be a {}, got {}", what, expected, actual))
+    }
+}
+
+fn comparable(left: Type, right: Type, op: BinaryOp) -> std::result::Result<(), String> {
+    let ordered = |t: Type| matches!(t, Type::Any | Type::Number | Type::String);
+    if ordered(left) && ordered(right) && left.accepts(right) {
+        Ok(())
+    } else {
+        Err(format!("cannot compare a {} with a {} using `{}`", left, right, op.symbol()))
+    }
+}
+
+fn evaluate(node: &Node, scope: &Value) -> std::result::Result<Value, String> {
+    match node {
+        Node::Literal(value) => Ok(value.clone()),
+        Node::Name(name) => scope.get(name).cloned().ok_or_else(|| format!("unknown name '{}'", name)),
+        Node::Member(base, field) => match evaluate(base, scope)? {
+            Value::Object(mut fields) => Ok(fields.remove(field).unwrap_or(Value::Null)),
+            Value::Null => Ok(Value::Null),
+            other => Err(format!("cannot read field '{}' of a {}", field, Type::of(&other))),
+        },
+        Node::Index(base, index) => match (evaluate(base, scope)?, evaluate(index, scope)?) {
+            (Value::Array(mut items), Value::Number(n)) => {
+                let position = n.as_u64().filter(|&i| (i as usize) < items.len());
+                Ok(position.map(|i| items.swap_remove(i as usize)).unwrap_or(Value::Null))
+            }
+            (Value::Object(mut fields), Value::String(key)) => Ok(fields.remove(&key).unwrap_or(Value::Null)),
+            (Value::Null, _) => Ok(Value::Null),
+            (base, index) => Err(format!("cannot index a {} with a {}", Type::of(&base), Type::of(&index))),
+        },
+        Node::Not(inner) => match evaluate(inner, scope)? {
+            Value::Bool(value) => Ok(Value::Bool(!value)),
+            other => Err(format!("operand of `!` must be a boolean, got {}", Type::of(&other))),
+        },
+        Node::Negate(inner) => match evaluate(inner, scope)? {
+            Value::Number(n) => number(-n.as_f64().unwrap_or_default()),
+            other => Err(format!("operand of `-` must be a number, got {}", Type::of(&other))),
+        },
+        Node::Binary(BinaryOp::And, left, right) => {
+            Ok(Value::Bool(boolean(evaluate(left, scope)?, BinaryOp::And)? && boolean(evaluate(right, scope)?, BinaryOp::And)?))
+        }
+        Node::Binary(BinaryOp::Or, left, right) => {
+            Ok(Value::Bool(boolean(evaluate(left, scope)?, BinaryOp::Or)? || boolean(evaluate(right, scope)?, BinaryOp::Or)?))
+        }
+        Node::Binary(op, left, right) => binary(*op, evaluate(left, scope)?, evaluate(right, scope)?),
+        Node::Call(function, args) => {
+            let args = args.iter().map(|arg| evaluate(arg, scope)).collect::<std::result::Result<Vec<_>, _>>()?;
+            call(*function, &args)
+        }
+    }
+}
+
+fn boolean(value: Value, op: BinaryOp) -> std::result::Result<bool, String> {
+    match value {
+        Value::Bool(value) => Ok(value),
+        other => Err(format!("operands of `{}` must be booleans, got {}", op.symbol(), Type::of(&other))),
+    }
+}
+
+fn binary(op: BinaryOp, left: Value, right: Value) -> std::result::Result<Value, String> {
+    match op {
+        BinaryOp::Eq => return Ok(Value::Bool(equal(&left, &right))),
+        BinaryOp::Ne => return Ok(Value::Bool(!equal(&left, &right))),
+        _ => {}
+    }
+    match (&left, &right) {
+        (Value::Number(a), Value::Number(b)) => {
+            let (a, b) = (a.as_f64().unwrap_or_default(), b.as_f64().unwrap_or_default());
+            match op {
+                BinaryOp::Lt => Ok(Value::Bool(a < b)),
+                BinaryOp::Le => Ok(Value::Bool(a <= b)),
+                BinaryOp::Gt => Ok(Value::Bool(a > b)),
+                BinaryOp::Ge => Ok(Value::Bool(a >= b)),
+                BinaryOp::Add => number(a + b),
+                BinaryOp::Sub => number(a - b),
+                BinaryOp::Mul => number(a * b),
+                BinaryOp::Div | BinaryOp::Rem if b == 0.0 => Err("division by zero".to_string()),
+                BinaryOp::Div => number(a / b),
+                BinaryOp::Rem => number(a % b),
+                _ => unreachable!("handled above"),
+            }
+        }
+        (Value::String(a), Value::String(b)) => match op {
+            BinaryOp::Lt => Ok(Value::Bool(a < b)),
+            BinaryOp::Le => Ok(Value::Bool(a <= b)),
+            BinaryOp::Gt => Ok(Value::Bool(a > b)),
+            BinaryOp::Ge => Ok(Value::Bool(a >= b)),
+            BinaryOp::Add => Ok(Value::String(format!("{}{}", a, b))),
+            _ => Err(format!("operands of `{}` must be numbers, got string", op.symbol())),
+        },
+        _ => {
+            let (left, right) = (Type::of(&left), Type::of(&right));
+            Err(match op {
+                BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => {
+                    format!("cannot compare a {} with a {} using `{}`", left, right, op.symbol())
+                }
+                BinaryOp::Add => format!("cannot add a {} and a {}", left, right),
+                _ => format!("operands of `{}` must be numbers, got {} and {}", op.symbol(), left, right),
+            })
+        }
+    }
+}
+
+// Structural equality where 1 and 1.0 are the same number
+fn equal(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        (Value::Array(a), Value::Array(b)) => a.len() == b.len() && a.iter().zip(b).all(|(a, b)| equal(a, b)),
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len() && a.iter().all(|(key, value)| b.get(key).is_some_and(|other| equal(value, other)))
+        }
+        _ => left == right,
+    }
+}
+
+// Keeps whole numbers integral so they render as `10`, not `10.0`
+fn number(value: f64) -> std::result::Result<Value, String> {
+    if !value.is_finite() {
+        return Err("result is not a finite number".to_string());
+    }
+    if value.fract() == 0.0 && value.abs() < 9_007_199_254_740_992.0 {
+        return Ok(Value::from(value as i64));
+    }
+    Ok(serde_json::Number::from_f64(value).map(Value::Number).unwrap_or(Value::Null))
+}
+
+fn call(function: Function, args: &[Value]) -> std::result::Result<Value, String> {
+    let argument = format!("argument of {}()", function.name());
+    match (function, args) {
+        (Function::Len, [Value::String(text)]) => Ok(Value::from(text.chars().count())),
+        (Function::Len, [Value::Array(items)]) => Ok(Value::from(items.len())),
+        (Function::Len, [Value::Object(fields)]) => Ok(Value::from(fields.len())),
+        (Function::Len, [other]) => Err(format!("{} must be a string, array or object, got {}", argument, Type::of(other))),
+        (Function::Contains, [Value::String(text), Value::String(part)]) => Ok(Value::Bool(text.contains(part.as_str()))),
+        (Function::Contains, [Value::Array(items), needle]) => Ok(Value::Bool(items.iter().any(|item| equal(item, needle)))),
+        (Function::StartsWith, [Value::String(text), Value::String(part)]) => Ok(Value::Bool(text.starts_with(part.as_str()))),
+        (Function::EndsWith, [Value::String(text), Value::String(part)]) => Ok(Value::Bool(text.ends_with(part.as_str()))),
+        (_, args) => {
+            let types: Vec<String> = args.iter().map(|arg| Type::of(arg).to_string()).collect();
+            Err(format!("{}() cannot take ({})", function.name(), types.join(", ")))
+        }
+    }
+}
+
+/// A string containing `${{ ... }}` placeholders
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Expression(Expression),
+}
+
+impl Template {
+    /// Parses `text`; `None` when it has no placeholders.
+    pub fn parse(text: &str) -> ExprResult<Option<Self>> {
+        if !text.contains("${{") {
+            return Ok(None);
+        }
+        let mut parts = Vec::new();
+        let mut rest = text;
+        while let Some(start) = rest.find("${{") {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let body = &rest[start + 3..];
+            let end = closing_braces(body)
+                .ok_or_else(|| ExprError::new(format!("unterminated `${{{{` in '{}'", text)))?;
+            parts.push(Part::Expression(Expression::parse(&body[..end])?));
+            rest = &body[end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Some(Self { parts }))
+    }
+
+    pub fn expressions(&self) -> impl Iterator<Item = &Expression> {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Expression(expression) => Some(expression),
+            Part::Text(_) => None,
+        })
+    }
+
+    /// Renders the template.
+    ///
+    /// A template that is a single placeholder keeps the type of its value,
+    /// so `${{ steps.fetch.output.items }}` yields an array; anything else
+    /// becomes a string.
+    pub fn render(&self, scope: &Value) -> ExprResult<Value> {
+        if let [Part::Expression(expression)] = self.parts.as_slice() {
+            return expression.evaluate(scope);
+        }
+        let mut text = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(literal) => text.push_str(literal),
+                Part::Expression(expression) => text.push_str(&display(&expression.evaluate(scope)?)),
+            }
+        }
+        Ok(Value::String(text))
+    }
+}
+
+// Finds the `}}` closing a placeholder, skipping quoted strings
+fn closing_braces(body: &str) -> Option<usize> {
+    let bytes = body.as_bytes();
+    let mut quote: Option<u8> = None;
+    let mut i = 0;
+    while i < bytes.len() {
+        match (quote, bytes[i]) {
+            (Some(_), b'\\') => i += 1,
+            (Some(q), c) if c == q => quote = None,
+            (None, c @ (b'\'' | b'"')) => quote = Some(c),
+            (None, b'}') if bytes.get(i + 1) == Some(&b'}') => return Some(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// How a value is written into a string: strings as they are, `null` as
+/// nothing, everything else as JSON
+pub fn display(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Replaces placeholders in every string inside `value`; returns `None`
+/// when there were none.
+pub fn interpolate(value: &Value, scope: &Value) -> ExprResult<Option<Value>> {
+    match value {
+        Value::String(text) => match Template::parse(text)? {
+            Some(template) => template.render(scope).map(Some),
+            None => Ok(None),
+        },
+        Value::Array(items) => {
+            let mut changed = false;
+            let mut rendered = Vec::with_capacity(items.len());
+            for item in items {
+                match interpolate(item, scope)? {
+                    Some(value) => {
+                        changed = true;
+                        rendered.push(value);
+                    }
+                    None => rendered.push(item.clone()),
+                }
+            }
+            Ok(changed.then_some(Value::Array(rendered)))
+        }
+        Value::Object(fields) => {
+            let mut changed = false;
+            let mut rendered = Map::new();
+            for (key, field) in fields {
+                match interpolate(field, scope)? {
+                    Some(value) => {
+                        changed = true;
+                        rendered.insert(key.clone(), value);
+                    }
+                    None => {
+                        rendered.insert(key.clone(), field.clone());
+                    }
+                }
+            }
+            Ok(changed.then_some(Value::Object(rendered)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Every template in the strings inside `value`
+pub fn templates(value: &Value) -> ExprResult<Vec<Template>> {
+    let mut found = Vec::new();
+    collect_templates(value, &mut found)?;
+    Ok(found)
+}
+
+fn collect_templates(value: &Value, found: &mut Vec<Template>) -> ExprResult<()> {
+    match value {
+        Value::String(text) => {
+            if let Some(template) = Template::parse(text)? {
+                found.push(template);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                collect_templates(item, found)?;
+            }
+        }
+        Value::Object(fields) => {
+            for field in fields.values() {
+                collect_templates(field, found)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(Value),
+    Str(String),
+    Ident(String),
+    Punct(&'static str),
+    End,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "number {}", n),
+            Token::Str(s) => write!(f, "string '{}'", s),
+            Token::Ident(name) => write!(f, "'{}'", name),
+            Token::Punct(p) => write!(f, "'{}'", p),
+            Token::End => write!(f, "end of expression"),
+        }
+    }
+}
+
+const PUNCTUATION: [&str; 20] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "+", "-", "*", "/", "%", "(", ")", "[", "]", ".", ",",
+];
+
+fn lex(source: &str) -> ExprResult<Vec<Token>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.' && chars.get(i + 1).is_some_and(char::is_ascii_digit)) {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let value = match text.parse::<i64>() {
+                Ok(n) => Value::from(n),
+                Err(_) => text
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(serde_json::Number::from_f64)
+                    .map(Value::Number)
+                    .ok_or_else(|| ExprError::new(format!("invalid number '{}'", text)))?,
+            };
+            tokens.push(Token::Number(value));
+        } else if c.is_alphabetic() || c == '_' {
+            // Step ids may contain dashes, so `a-b` is one name; write `a - b` to subtract
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '-' && chars.get(i + 1).is_some_and(|n| n.is_alphanumeric() || *n == '_'))
+            {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c == '\'' || c == '"' {
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(ExprError::new(format!("unterminated string in `{}`", source.trim()))),
+                    Some(&q) if q == c => break,
+                    Some('\\') => {
+                        i += 1;
+                        text.push(match chars.get(i) {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some(&other) => other,
+                            None => return Err(ExprError::new(format!("unterminated string in `{}`", source.trim()))),
+                        });
+                    }
+                    Some(&other) => text.push(other),
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push(Token::Str(text));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let punct = PUNCTUATION
+                .iter()
+                .find(|p| rest.starts_with(**p))
+                .ok_or_else(|| ExprError::new(format!("unexpected character '{}' in `{}`", c, source.trim())))?;
+            tokens.push(Token::Punct(punct));
+            i += punct.len();
+        }
+    }
+    tokens.push(Token::End);
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position.min(self.tokens.len() - 1)]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.peek().clone();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        if matches!(self.peek(), Token::Punct(p) if *p == punct) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, punct: &str) -> ExprResult<()> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            Err(ExprError::new(format!("expected '{}', found {}", punct, self.peek())))
+        }
+    }
+
+    fn expression(&mut self) -> ExprResult<Node> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(ExprError::new("expression is nested too deeply"));
+        }
+        let node = self.binary(0);
+        self.depth -= 1;
+        node
+    }
+
+    // Precedence climbing over the levels below, loosest first
+    fn binary(&mut self, level: usize) -> ExprResult<Node> {
+        const LEVELS: [&[(&str, BinaryOp)]; 6] = [
+            &[("||", BinaryOp::Or)],
+            &[("&&", BinaryOp::And)],
+            &[("==", BinaryOp::Eq), ("!=", BinaryOp::Ne)],
+            &[("<=", BinaryOp::Le), (">=", BinaryOp::Ge), ("<", BinaryOp::Lt), (">", BinaryOp::Gt)],
+            &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+            &[("*", BinaryOp::Mul), ("/", BinaryOp::Div), ("%", BinaryOp::Rem)],
+        ];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        'operators: loop {
+            for (symbol, op) in LEVELS[level] {
+                if self.eat(symbol) {
+                    let right = self.binary(level + 1)?;
+                    left = Node::Binary(*op, Box::new(left), Box::new(right));
+                    continue 'operators;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn unary(&mut self) -> ExprResult<Node> {
+        if self.eat("!") {
+            return Ok(Node::Not(Box::new(self.nested(Self::unary)?)));
+        }
+        if self.eat("-") {
+            return Ok(Node::Negate(Box::new(self.nested(Self::unary)?)));
+        }
+        self.postfix()
+    }
+
+    fn nested(&mut self, parse: fn(&mut Self) -> ExprResult<Node>) -> ExprResult<Node> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(ExprError::new("expression is nested too deeply"));
+        }
+        let node = parse(self);
+        self.depth -= 1;
+        node
+    }
+
+    fn postfix(&mut self) -> ExprResult<Node> {
+        let mut node = self.primary()?;
+        loop {
+            if self.eat(".") {
+                match self.next() {
+                    Token::Ident(field) => node = Node::Member(Box::new(node), field),
+                    token => return Err(ExprError::new(format!("expected a field name after '.', found {}", token))),
+                }
+            } else if self.eat("[") {
+                let index = self.expression()?;
+                self.expect("]")?;
+                node = Node::Index(Box::new(node), Box::new(index));
+            } else {
+                return Ok(node);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> ExprResult<Node> {
+        match self.next() {
+            Token::Number(n) => Ok(Node::Literal(n)),
+            Token::Str(s) => Ok(Node::Literal(Value::String(s))),
+            Token::Ident(name) => match name.as_str() {
+                "true" => Ok(Node::Literal(Value::Bool(true))),
+                "false" => Ok(Node::Literal(Value::Bool(false))),
+                "null" => Ok(Node::Literal(Value::Null)),
+                _ if self.eat("(") => {
+                    let function = Function::lookup(&name)
+                        .ok_or_else(|| ExprError::new(format!("unknown function '{}'", name)))?;
+                    let mut args = Vec::new();
+                    if !self.eat(")") {
+                        loop {
+                            args.push(self.expression()?);
+                            if self.eat(")") {
+                                break;
+                            }
+                            self.expect(",")?;
+                        }
+                    }
+                    if args.len() != function.arity() {
+                        return Err(ExprError::new(format!(
+                            "{}() takes {} argument(s), got {}",
+                            name,
+                            function.arity(),
+                            args.len()
+                        )));
+                    }
+                    Ok(Node::Call(function, args))
+                }
+                _ => Ok(Node::Name(name)),
+            },
+            Token::Punct("(") => {
+                let node = self.expression()?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            token => Err(ExprError::new(format!("expected a value, found {}", token))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn scope() -> Value {
+        json!({
+            "steps": {
+                "fetch": {"status": "succeeded", "success": true, "message": "ok",
+                          "output": {"count": 12, "items": ["a", "b"], "name": "orders"}},
+                "parse-data": {"status": "failed", "success": false, "message": "boom", "output": null}
+            },
+            "inputs": {"threshold": 10, "region": "eu"}
+        })
+    }
+
+    fn eval(source: &str) -> ExprResult<Value> {
+        Expression::parse(source)?.evaluate(&scope())
+    }
+
+    #[test]
+    fn test_evaluates_operators_and_references() {
+        assert_eq!(eval("steps.fetch.output.count > 10").unwrap(), json!(true));
+        assert_eq!(eval("steps.fetch.output.count > inputs.threshold && steps.fetch.success").unwrap(), json!(true));
+        assert_eq!(eval("steps.parse-data.status == 'failed' || false").unwrap(), json!(true));
+        assert_eq!(eval("(1 + 2) * 3 - 4 / 2").unwrap(), json!(7));
+        assert_eq!(eval("7 % 4 + 0.5").unwrap(), json!(3.5));
+        assert_eq!(eval("!(2 >= 3)").unwrap(), json!(true));
+        assert_eq!(eval("steps.fetch.output.items[1]").unwrap(), json!("b"));
+        assert_eq!(eval("steps['fetch'].output['name'] + '-' + inputs.region").unwrap(), json!("orders-eu"));
+        assert_eq!(eval("steps.fetch.output.missing").unwrap(), Value::Null);
+        assert_eq!(eval("steps.parse-data.output.count").unwrap(), Value::Null);
+        assert_eq!(eval("len(steps.fetch.output.items) == 2 && contains(steps.fetch.output.items, 'a')").unwrap(), json!(true));
+        assert_eq!(eval("starts_with(inputs.region, 'e') && ends_with('abc', 'bc')").unwrap(), json!(true));
+        assert_eq!(eval("1 == 1.0 && 'a' < 'b' && null == steps.fetch.output.missing").unwrap(), json!(true));
+    }
+
+    #[test]
+    fn test_rejects_mixed_types_at_runtime() {
+        assert!(eval("steps.fetch.output.name > 3").unwrap_err().message.contains("cannot compare a string with a number"));
+        assert!(eval("steps.fetch.output.count && true").unwrap_err().message.contains("must be booleans"));
+        assert!(eval("1 / 0").unwrap_err().message.contains("division by zero"));
+        assert!(eval("steps.fetch.output.count.value").unwrap_err().message.contains("cannot read field"));
+        let condition = Expression::parse_condition("${{ steps.fetch.output.count }}").unwrap();
+        assert!(condition.evaluate_condition(&scope()).unwrap_err().message.contains("must be a boolean"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Expression::parse("1 +").unwrap_err().message.contains("expected a value"));
+        assert!(Expression::parse("a b").unwrap_err().message.contains("unexpected 'b'"));
+        assert!(Expression::parse("'open").unwrap_err().message.contains("unterminated string"));
+        assert!(Expression::parse("exec('rm')").unwrap_err().message.contains("unknown function 'exec'"));
+        assert!(Expression::parse("len(1, 2)").unwrap_err().message.contains("takes 1 argument"));
+        assert!(Expression::parse("a ; b").unwrap_err().message.contains("unexpected character ';'"));
+        let deep = format!("{}1{}", "(".repeat(100), ")".repeat(100));
+        assert!(Expression::parse(&deep).unwrap_err().message.contains("nested too deeply"));
+    }
+
+    #[test]
+    fn test_static_check() {
+        let resolve = |path: &[PathSegment]| -> std::result::Result<Type, String> {
+            match path.get(1).cloned().flatten().as_deref() {
+                Some("fetch") => Ok(if path.get(2) == Some(&Some("status".to_string())) { Type::String } else { Type::Any }),
+                Some(other) => Err(format!("unknown step '{}'", other)),
+                None => Err("step must be named".to_string()),
+            }
+        };
+        let check = |source: &str| Expression::parse(source).unwrap().check(&resolve);
+
+        assert_eq!(check("steps.fetch.output.count > 10").unwrap(), Type::Bool);
+        assert_eq!(check("steps.fetch.output.items[0]").unwrap(), Type::Any);
+        assert!(check("steps.fetchh.output.count > 10").unwrap_err().message.contains("unknown step 'fetchh'"));
+        assert!(check("steps.fetch.status > 10").unwrap_err().message.contains("cannot compare a string with a number"));
+        assert!(check("!'yes'").unwrap_err().message.contains("must be a boolean, got string"));
+        assert!(check("1 + true").unwrap_err().message.contains("cannot add"));
+
+        let references = Expression::parse("steps.fetch.output.count > len(steps.b.output)").unwrap().references();
+        assert_eq!(references.len(), 2);
+        assert_eq!(references[1], vec![Some("steps".to_string()), Some("b".to_string()), Some("output".to_string())]);
+    }
+
+    #[test]
+    fn test_templates() {
+        let single = Template::parse("${{ steps.fetch.output.items }}").unwrap().unwrap();
+        assert_eq!(single.render(&scope()).unwrap(), json!(["a", "b"]));
+
+        let mixed = Template::parse("found ${{ steps.fetch.output.count }} in ${{ 'x}}y' }}!").unwrap().unwrap();
+        assert_eq!(mixed.render(&scope()).unwrap(), json!("found 12 in x}}y!"));
+        assert_eq!(mixed.expressions().count(), 2);
+
+        assert!(Template::parse("plain text").unwrap().is_none());
+        assert!(Template::parse("${{ 1 + ").unwrap_err().message.contains("unterminated"));
+
+        let inputs = json!({"limit": "${{ inputs.threshold * 2 }}", "tags": ["${{ inputs.region }}", "fixed"], "n": 1});
+        let rendered = interpolate(&inputs, &scope()).unwrap().unwrap();
+        assert_eq!(rendered, json!({"limit": 20, "tags": ["eu", "fixed"], "n": 1}));
+        assert!(interpolate(&json!({"n": 1}), &scope()).unwrap().is_none());
+        assert_eq!(templates(&inputs).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,240 @@
+// src/format.rs
+/*
+ * Workflow file formats (JSON, YAML, TOML) and positioned parse errors
+ */
+
+use crate::workflow::Workflow;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+
+/// Serialization format of a workflow definition file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkflowFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl WorkflowFormat {
+    /// Picks the format from the file extension, defaulting to JSON.
+    pub fn from_path(path: &Path) -> Self {
+        match path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase())
+            .as_deref()
+        {
+            Some("yaml") | Some("yml") => WorkflowFormat::Yaml,
+            Some("toml") => WorkflowFormat::Toml,
+            _ => WorkflowFormat::Json,
+        }
+    }
+}
+
+impl fmt::Display for WorkflowFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            WorkflowFormat::Json => "JSON",
+            WorkflowFormat::Yaml => "YAML",
+            WorkflowFormat::Toml => "TOML",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A workflow file that could not be deserialized
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub format: WorkflowFormat,
+    pub message: String,
+    /// 1-based position of the error in the source text, when known
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    /// Location inside the document, e.g. `steps[1].depends_on`
+    pub path: Option<String>,
+    /// Id of the step the error belongs to, when it has one
+    pub step: Option<String>,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, "line {}, column {}: ", line, column)?;
+        }
+        write!(f, "invalid {} workflow", self.format)?;
+        match (&self.path, &self.step) {
+            (Some(path), Some(step)) => write!(f, " at {} (step '{}')", path, step)?,
+            (Some(path), None) => write!(f, " at {}", path)?,
+            _ => {}
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Deserializes a workflow from `text` without validating it.
+pub fn parse_workflow(text: &str, format: WorkflowFormat) -> std::result::Result<Workflow, ParseError> {
+    let (message, line, column, path) = match format {
+        WorkflowFormat::Json => {
+            let mut de = serde_json::Deserializer::from_str(text);
+            let (inner, path) = match serde_path_to_error::deserialize(&mut de) {
+                Ok(workflow) => match de.end() {
+                    Ok(()) => return Ok(workflow),
+                    Err(e) => (e, String::new()),
+                },
+                Err(e) => {
+                    let path = e.path().to_string();
+                    (e.into_inner(), path)
+                }
+            };
+            let position = (inner.line() > 0).then(|| (inner.line(), inner.column()));
+            (strip_position(&inner.to_string()), position.map(|p| p.0), position.map(|p| p.1), path)
+        }
+        WorkflowFormat::Yaml => {
+            let de = serde_yaml::Deserializer::from_str(text);
+            match serde_path_to_error::deserialize(de) {
+                Ok(workflow) => return Ok(workflow),
+                Err(e) => {
+                    let path = e.path().to_string();
+                    let inner = e.into_inner();
+                    let location = inner.location();
+                    // serde_yaml also prefixes the message with the path
+                    let message = strip_position(&inner.to_string());
+                    let message = message
+                        .strip_prefix(&format!("{}: ", path))
+                        .map(|m| m.to_string())
+                        .unwrap_or(message);
+                    (
+                        message,
+                        location.as_ref().map(|l| l.line()),
+                        location.as_ref().map(|l| l.column()),
+                        path,
+                    )
+                }
+            }
+        }
+        WorkflowFormat::Toml => {
+            let de = toml::Deserializer::new(text);
+            match serde_path_to_error::deserialize(de) {
+                Ok(workflow) => return Ok(workflow),
+                Err(e) => {
+                    let path = e.path().to_string();
+                    let inner = e.into_inner();
+                    let position = inner.span().map(|span| line_column(text, span.start));
+                    (inner.message().to_string(), position.map(|p| p.0), position.map(|p| p.1), path)
+                }
+            }
+        }
+    };
+
+    let path = (!path.is_empty() && path != ".").then_some(path);
+    let step = path.as_deref().and_then(|path| step_id_for_path(text, format, path));
+    Err(ParseError {
+        format,
+        message,
+        line,
+        column,
+        path,
+        step,
+    })
+}
+
+// Converts a byte offset into a 1-based line and column.
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map(|l| l.chars().count()).unwrap_or(0) + 1;
+    (line, column)
+}
+
+// serde_json and serde_yaml append "at line X column Y" to their messages;
+// the position is reported separately, so drop the suffix.
+fn strip_position(message: &str) -> String {
+    match message.rfind(" at line ") {
+        Some(pos) => message[..pos].to_string(),
+        None => message.to_string(),
+    }
+}
+
+// Resolves `steps[N]...` to the id of step N by re-reading the document loosely.
+fn step_id_for_path(text: &str, format: WorkflowFormat, path: &str) -> Option<String> {
+    let rest = path.strip_prefix("steps[")?;
+    let index: usize = rest[..rest.find(']')?].parse().ok()?;
+    let document: serde_json::Value = match format {
+        WorkflowFormat::Json => serde_json::from_str(text).ok()?,
+        WorkflowFormat::Yaml => serde_yaml::from_str(text).ok()?,
+        WorkflowFormat::Toml => toml::from_str(text).ok()?,
+    };
+    document
+        .get("steps")?
+        .get(index)?
+        .get("id")?
+        .as_str()
+        .map(|id| id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_from_extension() {
+        assert_eq!(WorkflowFormat::from_path(Path::new("a.yaml")), WorkflowFormat::Yaml);
+        assert_eq!(WorkflowFormat::from_path(Path::new("a.YML")), WorkflowFormat::Yaml);
+        assert_eq!(WorkflowFormat::from_path(Path::new("a.toml")), WorkflowFormat::Toml);
+        assert_eq!(WorkflowFormat::from_path(Path::new("a.json")), WorkflowFormat::Json);
+        assert_eq!(WorkflowFormat::from_path(Path::new("workflow")), WorkflowFormat::Json);
+    }
+
+    #[test]
+    fn test_same_workflow_in_every_format() {
+        let json = r#"{"name": "demo", "steps": [{"id": "a"}, {"id": "b", "depends_on": ["a"], "inputs": {"n": 2}}]}"#;
+        let yaml = "name: demo\nsteps:\n  - id: a\n  - id: b\n    depends_on: [a]\n    inputs:\n      n: 2\n";
+        let toml = "name = \"demo\"\n\n[[steps]]\nid = \"a\"\n\n[[steps]]\nid = \"b\"\ndepends_on = [\"a\"]\ninputs = { n = 2 }\n";
+
+        for (text, format) in [(json, WorkflowFormat::Json), (yaml, WorkflowFormat::Yaml), (toml, WorkflowFormat::Toml)] {
+            let workflow = parse_workflow(text, format).unwrap();
+            assert_eq!(workflow.steps.len(), 2, "{}", format);
+            assert_eq!(workflow.steps[1].depends_on, vec!["a".to_string()], "{}", format);
+            assert_eq!(workflow.steps[1].inputs["n"], 2, "{}", format);
+        }
+    }
+
+    #[test]
+    fn test_json_error_position_and_step() {
+        let text = "{\"name\": \"demo\", \"steps\": [\n  {\"id\": \"a\"},\n  {\"id\": \"b\", \"depends_on\": \"a\"}\n]}";
+        let err = parse_workflow(text, WorkflowFormat::Json).unwrap_err();
+        assert_eq!(err.line, Some(3));
+        assert_eq!(err.path.as_deref(), Some("steps[1].depends_on"));
+        assert_eq!(err.step.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn test_yaml_error_position_and_step() {
+        let text = "name: demo\nsteps:\n  - id: a\n  - id: b\n    depends_on: 7\n";
+        let err = parse_workflow(text, WorkflowFormat::Yaml).unwrap_err();
+        assert_eq!(err.line, Some(5));
+        assert_eq!(err.path.as_deref(), Some("steps[1].depends_on"));
+        assert_eq!(err.step.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn test_toml_error_position_and_step() {
+        let text = "name = \"demo\"\n\n[[steps]]\nid = \"a\"\n\n[[steps]]\nid = \"b\"\ndepends_on = 7\n";
+        let err = parse_workflow(text, WorkflowFormat::Toml).unwrap_err();
+        assert_eq!(err.line, Some(8));
+        assert_eq!(err.path.as_deref(), Some("steps[1].depends_on"));
+        assert_eq!(err.step.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn test_syntax_error_has_position() {
+        let err = parse_workflow("name: demo\nsteps: [\n", WorkflowFormat::Yaml).unwrap_err();
+        assert!(err.line.is_some());
+        let err = parse_workflow("name = \"demo\"\nsteps = [\n", WorkflowFormat::Toml).unwrap_err();
+        assert!(err.line.is_some());
+    }
+}
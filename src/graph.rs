@@ -0,0 +1,444 @@
+// src/graph.rs
+/*
+ * Rendering of the step dependency graph
+ */
+
+use crate::workflow::{Step, ValidationError, Workflow};
+use crate::{StepResult, StepStatus};
+use std::fmt::{self, Write};
+use std::str::FromStr;
+
+/// How [`render`] draws a workflow
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// Stages of steps, for reading in a terminal
+    #[default]
+    Text,
+    /// Graphviz DOT, for `dot -Tsvg`
+    Dot,
+    /// A Mermaid flowchart, for Markdown that renders Mermaid blocks
+    Mermaid,
+}
+
+impl GraphFormat {
+    pub const ALL: [GraphFormat; 3] = [GraphFormat::Text, GraphFormat::Dot, GraphFormat::Mermaid];
+}
+
+impl fmt::Display for GraphFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            GraphFormat::Text => "text",
+            GraphFormat::Dot => "dot",
+            GraphFormat::Mermaid => "mermaid",
+        })
+    }
+}
+
+impl FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        GraphFormat::ALL
+            .into_iter()
+            .find(|format| format.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown graph format '{}' (expected text, dot or mermaid)", s))
+    }
+}
+
+/// Groups step indices into stages.
+///
+/// Every step comes one stage after its latest dependency, so the steps of
+/// one stage only depend on earlier stages and can run in parallel.
+pub fn stages(workflow: &Workflow) -> Result<Vec<Vec<usize>>, ValidationError> {
+    let order = workflow.topological_order()?;
+    let mut depth = vec![0usize; workflow.steps.len()];
+    let mut stages: Vec<Vec<usize>> = Vec::new();
+    for index in order {
+        let step = &workflow.steps[index];
+        let stage = step
+            .depends_on
+            .iter()
+            .filter_map(|dependency| workflow.steps.iter().position(|s| &s.id == dependency))
+            .map(|dependency| depth[dependency] + 1)
+            .max()
+            .unwrap_or(0);
+        depth[index] = stage;
+        if stages.len() <= stage {
+            stages.resize(stage + 1, Vec::new());
+        }
+        stages[stage].push(index);
+    }
+    for stage in &mut stages {
+        stage.sort();
+    }
+    Ok(stages)
+}
+
+/// Plain-text view of the DAG, one stage per block.
+///
+/// ```text
+/// Workflow 'etl' (3 steps, 2 stages)
+///
+/// Stage 1
+///   fetch (command)
+/// Stage 2
+///   parse (process) <- fetch
+///   notify (process) <- fetch
+/// ```
+///
+/// The branches of `if` and `switch` steps, and the steps a `map` runs per
+/// item, are listed below them.
+pub fn render_text(workflow: &Workflow) -> Result<String, ValidationError> {
+    text(workflow, &[])
+}
+
+/// Draws `workflow` in `format`.
+///
+/// Given the step results of a run, every step that has one is annotated
+/// with its status and duration, and in DOT and Mermaid colored by status.
+/// Steps without a result are drawn as not run.
+pub fn render(workflow: &Workflow, format: GraphFormat, results: &[StepResult]) -> Result<String, ValidationError> {
+    match format {
+        GraphFormat::Text => text(workflow, results),
+        GraphFormat::Dot => render_dot(workflow, results),
+        GraphFormat::Mermaid => render_mermaid(workflow, results),
+    }
+}
+
+fn text(workflow: &Workflow, results: &[StepResult]) -> Result<String, ValidationError> {
+    let stages = stages(workflow)?;
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "Workflow '{}' ({} steps, {} stages)",
+        workflow.name,
+        workflow.steps.len(),
+        stages.len()
+    );
+    for (number, stage) in stages.iter().enumerate() {
+        let _ = write!(out, "\nStage {}", number + 1);
+        for &index in stage {
+            let step = &workflow.steps[index];
+            let _ = write!(out, "\n  {} ({})", step.id, step.action.kind());
+            if !step.depends_on.is_empty() {
+                let _ = write!(out, " <- {}", step.depends_on.join(", "));
+            }
+            if let Some(result) = result_of(results, &step.id) {
+                let _ = write!(out, " [{}]", outcome(result));
+            }
+            for (name, branch) in step.action.nested() {
+                let ids: Vec<&str> = branch.iter().map(|nested| nested.id.as_str()).collect();
+                let _ = write!(out, "\n    {}: {}", name, if ids.is_empty() { "-".to_string() } else { ids.join(", ") });
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Graphviz DOT for `workflow`, left to right.
+///
+/// The branches of `if` and `switch` steps and the bodies of `loop` and
+/// `map` steps are drawn as clusters, entered from their step by a dashed
+/// edge. `results` overlays a run as in [`render`].
+pub fn render_dot(workflow: &Workflow, results: &[StepResult]) -> Result<String, ValidationError> {
+    workflow.topological_order()?;
+    let mut out = String::new();
+    let _ = writeln!(out, "digraph {} {{", dot_id(&workflow.name));
+    out.push_str("  rankdir=LR;\n");
+    out.push_str("  node [shape=box, style=\"rounded,filled\", fillcolor=\"#ffffff\", fontname=\"Helvetica\"];\n");
+    out.push_str("  edge [fontname=\"Helvetica\"];\n");
+    dot_steps(&mut out, &workflow.steps, results, 1);
+    out.push('}');
+    Ok(out)
+}
+
+fn dot_steps(out: &mut String, steps: &[Step], results: &[StepResult], depth: usize) {
+    let indent = "  ".repeat(depth);
+    for step in steps {
+        let mut label = format!("{}\\n{}", dot_escape(&step.id), step.action.kind());
+        let mut attributes = String::new();
+        if let Some(result) = result_of(results, &step.id) {
+            let (fill, font) = colors(result.status);
+            let _ = write!(label, "\\n{}", dot_escape(&outcome(result)));
+            let _ = write!(attributes, ", fillcolor=\"{}\", fontcolor=\"{}\"", fill, font);
+        }
+        let _ = writeln!(out, "{}{} [label=\"{}\"{}];", indent, dot_id(&step.id), label, attributes);
+        for dependency in &step.depends_on {
+            let _ = writeln!(out, "{}{} -> {};", indent, dot_id(dependency), dot_id(&step.id));
+        }
+        for (name, branch) in step.action.nested() {
+            if branch.is_empty() {
+                continue;
+            }
+            let _ = writeln!(out, "{}subgraph {} {{", indent, dot_id(&format!("cluster_{}_{}", step.id, name)));
+            let _ = writeln!(out, "{}  label=\"{}: {}\";", indent, dot_escape(&step.id), dot_escape(name));
+            let _ = writeln!(out, "{}  style=dashed;", indent);
+            dot_steps(out, branch, results, depth + 1);
+            let _ = writeln!(out, "{}}}", indent);
+            for entry in branch.iter().filter(|nested| nested.depends_on.is_empty()) {
+                let _ = writeln!(
+                    out,
+                    "{}{} -> {} [style=dashed, label=\"{}\"];",
+                    indent,
+                    dot_id(&step.id),
+                    dot_id(&entry.id),
+                    dot_escape(name)
+                );
+            }
+        }
+    }
+}
+
+fn dot_id(id: &str) -> String {
+    format!("\"{}\"", dot_escape(id))
+}
+
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// A Mermaid flowchart for `workflow`, left to right.
+///
+/// Nested steps are drawn as subgraphs, as clusters are in [`render_dot`].
+/// Node names are generated, since Mermaid does not accept every step id;
+/// the labels carry the ids. `results` overlays a run as in [`render`].
+pub fn render_mermaid(workflow: &Workflow, results: &[StepResult]) -> Result<String, ValidationError> {
+    workflow.topological_order()?;
+    let mut names = Vec::new();
+    number_steps(&workflow.steps, &mut names);
+    let name = |id: &str| format!("s{}", names.iter().position(|known| *known == id).unwrap_or(0));
+
+    let mut out = String::from("flowchart LR\n");
+    let mut classes: Vec<(StepStatus, Vec<String>)> = Vec::new();
+    mermaid_steps(&mut out, &workflow.steps, results, &name, &mut classes, 1);
+    for (status, nodes) in &classes {
+        let (fill, font) = colors(*status);
+        let _ = writeln!(out, "  classDef {} fill:{},color:{}", status, fill, font);
+        let _ = writeln!(out, "  class {} {}", nodes.join(","), status);
+    }
+    out.truncate(out.trim_end().len());
+    Ok(out)
+}
+
+fn number_steps<'a>(steps: &'a [Step], names: &mut Vec<&'a str>) {
+    for step in steps {
+        names.push(&step.id);
+        for (_, branch) in step.action.nested() {
+            number_steps(branch, names);
+        }
+    }
+}
+
+fn mermaid_steps<N: Fn(&str) -> String>(
+    out: &mut String,
+    steps: &[Step],
+    results: &[StepResult],
+    name: &N,
+    classes: &mut Vec<(StepStatus, Vec<String>)>,
+    depth: usize,
+) {
+    let indent = "  ".repeat(depth);
+    for step in steps {
+        let node = name(&step.id);
+        let mut label = format!("{}<br/>{}", mermaid_escape(&step.id), step.action.kind());
+        if let Some(result) = result_of(results, &step.id) {
+            let _ = write!(label, "<br/>{}", mermaid_escape(&outcome(result)));
+            match classes.iter_mut().find(|(status, _)| *status == result.status) {
+                Some((_, nodes)) => nodes.push(node.clone()),
+                None => classes.push((result.status, vec![node.clone()])),
+            }
+        }
+        let _ = writeln!(out, "{}{}[\"{}\"]", indent, node, label);
+        for dependency in &step.depends_on {
+            let _ = writeln!(out, "{}{} --> {}", indent, name(dependency), node);
+        }
+        for (branch_name, branch) in step.action.nested() {
+            if branch.is_empty() {
+                continue;
+            }
+            let _ = writeln!(
+                out,
+                "{}subgraph {}_{}[\"{}: {}\"]",
+                indent,
+                node,
+                branch_name.replace(|c: char| !c.is_ascii_alphanumeric(), "_"),
+                mermaid_escape(&step.id),
+                mermaid_escape(branch_name)
+            );
+            mermaid_steps(out, branch, results, name, classes, depth + 1);
+            let _ = writeln!(out, "{}end", indent);
+            for entry in branch.iter().filter(|nested| nested.depends_on.is_empty()) {
+                let _ = writeln!(out, "{}{} -.->|{}| {}", indent, node, mermaid_escape(branch_name), name(&entry.id));
+            }
+        }
+    }
+}
+
+fn mermaid_escape(text: &str) -> String {
+    text.replace('"', "#quot;").replace('<', "#lt;").replace('>', "#gt;").replace('|', "#124;")
+}
+
+fn result_of<'a>(results: &'a [StepResult], id: &str) -> Option<&'a StepResult> {
+    results.iter().find(|result| result.id == id)
+}
+
+// "succeeded, 1.2s", or only the status for steps that did not run
+fn outcome(result: &StepResult) -> String {
+    match result.duration_ms {
+        Some(ms) => format!("{}, {}", result.status, duration(ms)),
+        None => result.status.to_string(),
+    }
+}
+
+fn duration(ms: u64) -> String {
+    match ms {
+        0..=999 => format!("{}ms", ms),
+        1_000..=59_999 => format!("{:.1}s", ms as f64 / 1000.0),
+        _ => format!("{}m {}s", ms / 60_000, ms % 60_000 / 1000),
+    }
+}
+
+// Fill and text colors per status, the same as in the web UI
+fn colors(status: StepStatus) -> (&'static str, &'static str) {
+    match status {
+        StepStatus::Succeeded => ("#2e9d52", "#ffffff"),
+        StepStatus::Failed => ("#d0393e", "#ffffff"),
+        StepStatus::TimedOut => ("#e07b1a", "#ffffff"),
+        StepStatus::Cancelled => ("#7a7f87", "#ffffff"),
+        StepStatus::Skipped => ("#b9bec6", "#1f2328"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stages_follow_longest_dependency_chain() {
+        let workflow = Workflow::from_json(
+            r#"{"name": "diamond", "steps": [
+                {"id": "d", "depends_on": ["b", "c"]},
+                {"id": "c", "depends_on": ["a"]},
+                {"id": "b", "depends_on": ["a", "c"]},
+                {"id": "a"}
+            ]}"#,
+        )
+        .unwrap();
+        let ids: Vec<Vec<&str>> = stages(&workflow)
+            .unwrap()
+            .iter()
+            .map(|stage| stage.iter().map(|&i| workflow.steps[i].id.as_str()).collect())
+            .collect();
+        assert_eq!(ids, vec![vec!["a"], vec!["c"], vec!["b"], vec!["d"]]);
+    }
+
+    #[test]
+    fn test_render_text() {
+        let workflow = Workflow::from_json(
+            r#"{"name": "etl", "steps": [
+                {"id": "fetch", "kind": "command", "program": "curl"},
+                {"id": "parse", "depends_on": ["fetch"]},
+                {"id": "notify", "depends_on": ["fetch"]}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            render_text(&workflow).unwrap(),
+            "Workflow 'etl' (3 steps, 2 stages)\n\nStage 1\n  fetch (command)\nStage 2\n  parse (process) <- fetch\n  notify (process) <- fetch"
+        );
+
+        let workflow = Workflow::from_json(
+            r#"{"name": "branchy", "steps": [
+                {"id": "check", "kind": "if", "condition": "true", "then": [{"id": "a"}, {"id": "b"}]}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            render_text(&workflow).unwrap(),
+            "Workflow 'branchy' (1 steps, 1 stages)\n\nStage 1\n  check (if)\n    then: a, b\n    else: -"
+        );
+    }
+
+    fn etl_run() -> (Workflow, Vec<StepResult>) {
+        let workflow = Workflow::from_json(
+            r#"{"name": "etl", "steps": [
+                {"id": "fetch", "kind": "command", "program": "curl"},
+                {"id": "parse", "depends_on": ["fetch"]},
+                {"id": "check", "kind": "if", "condition": "true", "depends_on": ["parse"], "then": [{"id": "notify"}]}
+            ]}"#,
+        )
+        .unwrap();
+        let result = |id: &str, status, duration_ms| StepResult {
+            id: id.to_string(),
+            status,
+            result: None,
+            started_at: None,
+            duration_ms,
+            attempts: Vec::new(),
+        };
+        let results = vec![
+            result("fetch", StepStatus::Succeeded, Some(1250)),
+            result("parse", StepStatus::Failed, Some(40)),
+            result("check", StepStatus::Skipped, None),
+        ];
+        (workflow, results)
+    }
+
+    #[test]
+    fn test_render_dot() {
+        let (workflow, results) = etl_run();
+        assert_eq!(
+            render_dot(&workflow, &[]).unwrap(),
+            r##"digraph "etl" {
+  rankdir=LR;
+  node [shape=box, style="rounded,filled", fillcolor="#ffffff", fontname="Helvetica"];
+  edge [fontname="Helvetica"];
+  "fetch" [label="fetch\ncommand"];
+  "parse" [label="parse\nprocess"];
+  "fetch" -> "parse";
+  "check" [label="check\nif"];
+  "parse" -> "check";
+  subgraph "cluster_check_then" {
+    label="check: then";
+    style=dashed;
+    "notify" [label="notify\nprocess"];
+  }
+  "check" -> "notify" [style=dashed, label="then"];
+}"##
+        );
+        let dot = render_dot(&workflow, &results).unwrap();
+        assert!(dot.contains(r##""fetch" [label="fetch\ncommand\nsucceeded, 1.2s", fillcolor="#2e9d52", fontcolor="#ffffff"];"##));
+        assert!(dot.contains(r##""parse" [label="parse\nprocess\nfailed, 40ms", fillcolor="#d0393e""##));
+        assert!(dot.contains(r#""notify" [label="notify\nprocess"];"#));
+    }
+
+    #[test]
+    fn test_render_mermaid() {
+        let (workflow, results) = etl_run();
+        assert_eq!(
+            render_mermaid(&workflow, &results).unwrap(),
+            r#"flowchart LR
+  s0["fetch<br/>command<br/>succeeded, 1.2s"]
+  s1["parse<br/>process<br/>failed, 40ms"]
+  s0 --> s1
+  s2["check<br/>if<br/>skipped"]
+  s1 --> s2
+  subgraph s2_then["check: then"]
+    s3["notify<br/>process"]
+  end
+  s2 -.->|then| s3
+  classDef succeeded fill:#2e9d52,color:#ffffff
+  class s0 succeeded
+  classDef failed fill:#d0393e,color:#ffffff
+  class s1 failed
+  classDef skipped fill:#b9bec6,color:#1f2328
+  class s2 skipped"#
+        );
+        assert_eq!(
+            render(&workflow, GraphFormat::Text, &results).unwrap().lines().nth(3),
+            Some("  fetch (command) [succeeded, 1.2s]")
+        );
+        assert_eq!("Mermaid".parse(), Ok(GraphFormat::Mermaid));
+        assert!("svg".parse::<GraphFormat>().is_err());
+    }
+}
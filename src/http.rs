@@ -0,0 +1,389 @@
+// src/http.rs
+/*
+ * Just enough HTTP/1.1 for the API server: one request per connection
+ */
+
+use serde::Serialize;
+use std::io::{self, BufRead, Read, Write};
+
+/// Longest request line plus headers accepted
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+
+/// Largest request body accepted
+pub const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// A parsed request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    /// Path without the query string, percent-decoded
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    /// Header names are lowercase
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Reads one request. Errors of kind `InvalidData` mean the client sent
+    /// something that is not a request this parser accepts.
+    pub fn read<R: BufRead>(reader: &mut R) -> io::Result<Request> {
+        let mut head = 0;
+        let mut line = String::new();
+        let mut next_line = |reader: &mut R| -> io::Result<String> {
+            line.clear();
+            head += reader.take((MAX_HEAD_BYTES - head) as u64).read_line(&mut line)?;
+            if !line.ends_with('\n') {
+                return Err(invalid(if head >= MAX_HEAD_BYTES { "request head too large" } else { "unexpected end of request" }));
+            }
+            Ok(line.trim_end_matches(['\r', '\n']).to_string())
+        };
+
+        let request_line = next_line(reader)?;
+        let mut parts = request_line.split(' ');
+        let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid("malformed request line"));
+        };
+        if !version.starts_with("HTTP/1.") {
+            return Err(invalid("unsupported HTTP version"));
+        }
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(name, true), percent_decode(value, true))
+            })
+            .collect();
+
+        let mut headers = Vec::new();
+        loop {
+            let line = next_line(reader)?;
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').ok_or_else(|| invalid("malformed header"))?;
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+
+        let mut request = Request {
+            method: method.to_string(),
+            path: percent_decode(path, false),
+            query,
+            headers,
+            body: Vec::new(),
+        };
+        if request.header("transfer-encoding").is_some() {
+            return Err(invalid("chunked request bodies are not supported; send Content-Length"));
+        }
+        let length = match request.header("content-length") {
+            Some(length) => length.parse::<usize>().map_err(|_| invalid("malformed Content-Length"))?,
+            None => 0,
+        };
+        if length > MAX_BODY_BYTES {
+            return Err(invalid("request body too large"));
+        }
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body)?;
+        Ok(request)
+    }
+
+    /// Value of the header `name`, given in lowercase
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str())
+    }
+
+    /// Value of the query parameter `name`
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(param, _)| param == name).map(|(_, value)| value.as_str())
+    }
+
+    /// The non-empty components of the path
+    pub fn segments(&self) -> Vec<&str> {
+        self.path.split('/').filter(|segment| !segment.is_empty()).collect()
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// `%2F` and friends; in a `form`, such as the query string, `+` is a space
+fn percent_decode(text: &str, form: bool) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = |byte: u8| (byte as char).to_digit(16);
+                match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        decoded.push((high * 16 + low) as u8);
+                        i += 3;
+                        continue;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            b'+' if form => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A response, written with `Connection: close`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body: body.into(),
+        }
+    }
+
+    pub fn json<T: Serialize + ?Sized>(status: u16, value: &T) -> Self {
+        match serde_json::to_vec_pretty(value) {
+            Ok(body) => Response::new(status, "application/json", body),
+            Err(e) => Response::error(500, &format!("cannot serialize response: {}", e)),
+        }
+    }
+
+    /// A JSON body of the form `{"error": message}`
+    pub fn error(status: u16, message: &str) -> Self {
+        Response::json(status, &serde_json::json!({ "error": message }))
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(writer, "HTTP/1.1 {} {}\r\n", self.status, reason(self.status))?;
+        for (name, value) in &self.headers {
+            write!(writer, "{}: {}\r\n", name, value)?;
+        }
+        write!(writer, "Content-Length: {}\r\nConnection: close\r\n\r\n", self.body.len())?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Entity",
+        426 => "Upgrade Required",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+/// Largest payload accepted in a frame from a WebSocket client
+pub const MAX_FRAME_BYTES: usize = 64 * 1024;
+
+/// WebSocket opcodes the server sends and answers
+pub mod opcode {
+    pub const TEXT: u8 = 0x1;
+    pub const CLOSE: u8 = 0x8;
+    pub const PING: u8 = 0x9;
+    pub const PONG: u8 = 0xA;
+}
+
+/// `Sec-WebSocket-Accept` for a client's `Sec-WebSocket-Key` (RFC 6455)
+pub fn websocket_accept(key: &str) -> String {
+    base64(&sha1(format!("{}258EAFA5-E914-47DA-95CA-C5AB0DC85B11", key.trim()).as_bytes()))
+}
+
+/// A complete, unmasked WebSocket frame, as servers send them
+pub fn websocket_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length @ 0..=125 => frame.push(length as u8),
+        length @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Reads one frame from a WebSocket client and returns its opcode and
+/// unmasked payload. Fragments come back one by one, as sent.
+pub fn read_websocket_frame<R: Read>(reader: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head)?;
+    if head[1] & 0x80 == 0 {
+        return Err(invalid("client frames must be masked"));
+    }
+    let length = match head[1] & 0x7F {
+        126 => {
+            let mut length = [0u8; 2];
+            reader.read_exact(&mut length)?;
+            u16::from_be_bytes(length) as u64
+        }
+        127 => {
+            let mut length = [0u8; 8];
+            reader.read_exact(&mut length)?;
+            u64::from_be_bytes(length)
+        }
+        length => length as u64,
+    };
+    if length > MAX_FRAME_BYTES as u64 {
+        return Err(invalid("WebSocket frame too large"));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((head[0] & 0x0F, payload))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            words[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let next = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = next;
+        }
+        for (value, added) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(added);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (chunk, value) in digest.chunks_mut(4).zip(state) {
+        chunk.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], chunk.get(1).copied().unwrap_or(0), chunk.get(2).copied().unwrap_or(0)];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_request() {
+        let text = "POST /runs/a%20b?limit=5&workflow=deploy+prod HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\n{}";
+        let request = Request::read(&mut text.as_bytes()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.segments(), vec!["runs", "a b"]);
+        assert_eq!(request.query("limit"), Some("5"));
+        assert_eq!(request.query("workflow"), Some("deploy prod"));
+        assert_eq!(request.header("content-length"), Some("2"));
+        assert_eq!(request.body, b"{}");
+
+        let error = Request::read(&mut "GET / HTTP/1.1\r\nHost".as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "unexpected end of request");
+        let error = Request::read(&mut "GET /\r\n\r\n".as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "malformed request line");
+        assert_eq!(percent_decode("100%", false), "100%");
+        assert_eq!(percent_decode("%zz%41+", false), "%zzA+");
+    }
+
+    #[test]
+    fn test_write_response() {
+        let mut written = Vec::new();
+        Response::new(404, "text/plain", "gone").with_header("X-Run", "r1").write_to(&mut written).unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nX-Run: r1\r\nContent-Length: 4\r\nConnection: close\r\n\r\ngone"
+        );
+    }
+
+    #[test]
+    fn test_websocket_handshake_and_frames() {
+        // The example from RFC 6455, section 1.3
+        assert_eq!(websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"a"), "YQ==");
+        assert_eq!(websocket_frame(opcode::TEXT, b"hi"), vec![0x81, 2, b'h', b'i']);
+        assert_eq!(&websocket_frame(opcode::TEXT, &[0; 300])[..4], &[0x81, 126, 1, 44]);
+        assert_eq!(websocket_frame(opcode::CLOSE, &[]), vec![0x88, 0]);
+    }
+
+    #[test]
+    fn test_read_websocket_frame() {
+        // A masked "Hello" ping, as in RFC 6455, section 5.7
+        let frame = [0x89, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        assert_eq!(read_websocket_frame(&mut &frame[..]).unwrap(), (opcode::PING, b"Hello".to_vec()));
+        let error = read_websocket_frame(&mut &[0x89, 0x05, b'H'][..]).unwrap_err();
+        assert_eq!(error.to_string(), "client frames must be masked");
+        let error = read_websocket_frame(&mut &[0x81, 0xFF, 0, 0, 0, 0, 0, 0x10, 0, 0][..]).unwrap_err();
+        assert_eq!(error.to_string(), "WebSocket frame too large");
+    }
+}
@@ -0,0 +1,368 @@
+// src/inputs.rs
+/*
+ * Typed workflow inputs: a subset of JSON Schema, and values given when starting a run
+ */
+
+use crate::expr::Type;
+use crate::format::WorkflowFormat;
+use crate::workflow::Workflow;
+use crate::{Result, WorkflowError};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+/// Keywords `input_schema` understands. Anything else is rejected rather than
+/// ignored, so a constraint is never silently left unchecked.
+const KEYWORDS: &[&str] = &[
+    "$schema",
+    "title",
+    "description",
+    "examples",
+    "default",
+    "type",
+    "enum",
+    "const",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "minItems",
+    "maxItems",
+    "minimum",
+    "maximum",
+    "exclusiveMinimum",
+    "exclusiveMaximum",
+    "minLength",
+    "maxLength",
+];
+
+const TYPES: [&str; 7] = ["null", "boolean", "object", "array", "number", "string", "integer"];
+
+/// A value that does not match its schema
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaError {
+    /// Where in the value, e.g. `servers[1].host`; empty for the value itself
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+/// Checks that `schema` only uses supported keywords, with values of the
+/// right shape, and that every `default` matches the schema it sits in.
+pub fn check_schema(schema: &Value) -> std::result::Result<(), SchemaError> {
+    check_schema_at(schema, "")
+}
+
+fn check_schema_at(schema: &Value, path: &str) -> std::result::Result<(), SchemaError> {
+    let fail = |message: String| Err(SchemaError { path: path.to_string(), message });
+    let fields = match schema {
+        Value::Bool(_) => return Ok(()),
+        Value::Object(fields) => fields,
+        other => return fail(format!("a schema must be an object, got {}", Type::of(other))),
+    };
+    let non_negative = |value: &Value| value.as_u64().is_some();
+    for (key, value) in fields {
+        let valid = match key.as_str() {
+            "type" => match value {
+                Value::String(name) => TYPES.contains(&name.as_str()),
+                Value::Array(names) => names.iter().all(|name| name.as_str().is_some_and(|name| TYPES.contains(&name))),
+                _ => false,
+            },
+            "enum" => value.is_array(),
+            "required" => value.as_array().is_some_and(|names| names.iter().all(Value::is_string)),
+            "properties" => match value {
+                Value::Object(properties) => {
+                    for (name, property) in properties {
+                        check_schema_at(property, &join(path, name))?;
+                    }
+                    true
+                }
+                _ => false,
+            },
+            "additionalProperties" => {
+                check_schema_at(value, path)?;
+                true
+            }
+            "items" => {
+                check_schema_at(value, &format!("{}[]", path))?;
+                true
+            }
+            "minimum" | "maximum" | "exclusiveMinimum" | "exclusiveMaximum" => value.is_number(),
+            "minLength" | "maxLength" | "minItems" | "maxItems" => non_negative(value),
+            "title" | "description" => value.is_string(),
+            key if KEYWORDS.contains(&key) => true,
+            _ => return fail(format!("unsupported schema keyword '{}'", key)),
+        };
+        if !valid {
+            return fail(format!("invalid value for '{}': {}", key, value));
+        }
+    }
+    if let Some(default) = fields.get("default") {
+        validate(schema, default).map_err(|e| SchemaError {
+            path: path.to_string(),
+            message: format!("default does not match the schema: {}", e),
+        })?;
+    }
+    Ok(())
+}
+
+/// Checks `value` against `schema`, reporting the first mismatch
+pub fn validate(schema: &Value, value: &Value) -> std::result::Result<(), SchemaError> {
+    validate_at(schema, value, "")
+}
+
+fn validate_at(schema: &Value, value: &Value, path: &str) -> std::result::Result<(), SchemaError> {
+    let fail = |message: String| Err(SchemaError { path: path.to_string(), message });
+    let fields = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => return fail("is not allowed".to_string()),
+        Value::Object(fields) => fields,
+        _ => return Ok(()),
+    };
+
+    if let Some(expected) = fields.get("type") {
+        let names: Vec<&str> = match expected {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !names.is_empty() && !names.iter().any(|name| has_type(value, name)) {
+            return fail(format!("must be {}, got {}", names.join(" or "), Type::of(value)));
+        }
+    }
+    if let Some(Value::Array(allowed)) = fields.get("enum") {
+        if !allowed.contains(value) {
+            let allowed: Vec<String> = allowed.iter().map(Value::to_string).collect();
+            return fail(format!("must be one of {}, got {}", allowed.join(", "), value));
+        }
+    }
+    if let Some(expected) = fields.get("const") {
+        if expected != value {
+            return fail(format!("must be {}, got {}", expected, value));
+        }
+    }
+
+    let number = |key: &str| fields.get(key).and_then(Value::as_f64);
+    let count = |key: &str| fields.get(key).and_then(Value::as_u64).map(|n| n as usize);
+    match value {
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or_default();
+            let bounds = [
+                (number("minimum").filter(|min| n < *min), "at least"),
+                (number("maximum").filter(|max| n > *max), "at most"),
+                (number("exclusiveMinimum").filter(|min| n <= *min), "greater than"),
+                (number("exclusiveMaximum").filter(|max| n >= *max), "less than"),
+            ];
+            if let Some((Some(bound), words)) = bounds.iter().find(|(broken, _)| broken.is_some()) {
+                return fail(format!("must be {} {}, got {}", words, bound, n));
+            }
+        }
+        Value::String(text) => {
+            let length = text.chars().count();
+            if let Some(min) = count("minLength").filter(|min| length < *min) {
+                return fail(format!("must be at least {} characters long", min));
+            }
+            if let Some(max) = count("maxLength").filter(|max| length > *max) {
+                return fail(format!("must be at most {} characters long", max));
+            }
+        }
+        Value::Array(items) => {
+            if let Some(min) = count("minItems").filter(|min| items.len() < *min) {
+                return fail(format!("must have at least {} items", min));
+            }
+            if let Some(max) = count("maxItems").filter(|max| items.len() > *max) {
+                return fail(format!("must have at most {} items", max));
+            }
+            if let Some(schema) = fields.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    validate_at(schema, item, &format!("{}[{}]", path, index))?;
+                }
+            }
+        }
+        Value::Object(object) => {
+            if let Some(Value::Array(required)) = fields.get("required") {
+                if let Some(missing) = required.iter().filter_map(Value::as_str).find(|name| !object.contains_key(*name)) {
+                    return Err(SchemaError {
+                        path: join(path, missing),
+                        message: "is required".to_string(),
+                    });
+                }
+            }
+            let properties = fields.get("properties").and_then(Value::as_object);
+            for (name, item) in object {
+                let path = join(path, name);
+                match (properties.and_then(|properties| properties.get(name)), fields.get("additionalProperties")) {
+                    (Some(schema), _) => validate_at(schema, item, &path)?,
+                    (None, Some(schema)) => validate_at(schema, item, &path)?,
+                    (None, None) => {}
+                }
+            }
+        }
+        Value::Null | Value::Bool(_) => {}
+    }
+    Ok(())
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "integer" => value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        "boolean" => value.is_boolean(),
+        name => Type::of(value).to_string() == name,
+    }
+}
+
+fn join(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+/// The schema of property `name` of an object schema
+pub fn property<'a>(schema: &'a Value, name: &str) -> Option<&'a Value> {
+    schema.get("properties")?.get(name)
+}
+
+/// What expressions can assume about a value matching `schema`
+pub fn schema_type(schema: &Value) -> Type {
+    match schema.get("type").and_then(Value::as_str) {
+        Some("null") => Type::Null,
+        Some("boolean") => Type::Bool,
+        Some("number" | "integer") => Type::Number,
+        Some("string") => Type::String,
+        Some("array") => Type::Array,
+        Some("object") => Type::Object,
+        _ => Type::Any,
+    }
+}
+
+/// Input values given when starting a run, e.g. with `--params-file` and `--param`
+#[derive(Debug, Clone, Default)]
+pub struct Params {
+    /// JSON, YAML or TOML file holding an object of input values
+    pub file: Option<PathBuf>,
+    /// `name=value` pairs; they win over the file
+    pub values: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn is_empty(&self) -> bool {
+        self.file.is_none() && self.values.is_empty()
+    }
+
+    /// The input values for `workflow`.
+    ///
+    /// A `name=value` pair is read as JSON, so `replicas=3` is a number and
+    /// `tags=["a","b"]` an array, unless the input is declared as a string or
+    /// the value is not valid JSON; then it is kept as text.
+    pub fn resolve(&self, workflow: &Workflow) -> Result<Map<String, Value>> {
+        let mut params = match &self.file {
+            Some(path) => {
+                let origin = format!("params file {}", path.display());
+                let fail = |message: String| WorkflowError::Config { origin: origin.clone(), message };
+                let text = fs::read_to_string(path).map_err(|e| WorkflowError::from(e).with_path(path))?;
+                let value: Value = match WorkflowFormat::from_path(path) {
+                    WorkflowFormat::Json => serde_json::from_str(&text).map_err(|e| fail(e.to_string()))?,
+                    WorkflowFormat::Yaml => serde_yaml::from_str(&text).map_err(|e| fail(e.to_string()))?,
+                    WorkflowFormat::Toml => toml::from_str(&text).map_err(|e: toml::de::Error| fail(e.message().to_string()))?,
+                };
+                match value {
+                    Value::Object(params) => params,
+                    other => return Err(fail(format!("expected an object of input values, got {}", Type::of(&other)))),
+                }
+            }
+            None => Map::new(),
+        };
+        for (name, raw) in &self.values {
+            let value = match workflow.input_type(name) {
+                Some(Type::String) => Value::String(raw.clone()),
+                _ => serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.clone())),
+            };
+            params.insert(name.clone(), value);
+        }
+        Ok(params)
+    }
+}
+
+/// Splits `name=value`, as given to `--param`
+pub fn parse_param(text: &str) -> std::result::Result<(String, String), String> {
+    match text.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => Ok((name.trim().to_string(), value.to_string())),
+        _ => Err(format!("expected name=value, got '{}'", text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate() {
+        let schema = json!({
+            "type": "object",
+            "required": ["environment"],
+            "additionalProperties": false,
+            "properties": {
+                "environment": {"type": "string", "enum": ["staging", "production"]},
+                "replicas": {"type": "integer", "minimum": 1, "default": 2},
+                "hosts": {"type": "array", "items": {"type": "string", "minLength": 1}}
+            }
+        });
+        assert_eq!(check_schema(&schema), Ok(()));
+        assert_eq!(validate(&schema, &json!({"environment": "staging", "replicas": 3})), Ok(()));
+
+        let error = |value: Value| validate(&schema, &value).unwrap_err().to_string();
+        assert_eq!(error(json!({})), "environment: is required");
+        assert_eq!(
+            error(json!({"environment": "dev"})),
+            r#"environment: must be one of "staging", "production", got "dev""#
+        );
+        assert_eq!(error(json!({"environment": "staging", "replicas": 1.5})), "replicas: must be integer, got number");
+        assert_eq!(error(json!({"environment": "staging", "replicas": 0})), "replicas: must be at least 1, got 0");
+        assert_eq!(error(json!({"environment": "staging", "hosts": ["a", ""]})), "hosts[1]: must be at least 1 characters long");
+        assert_eq!(error(json!({"environment": "staging", "debug": true})), "debug: is not allowed");
+    }
+
+    #[test]
+    fn test_check_schema() {
+        let error = |schema: Value| check_schema(&schema).unwrap_err().to_string();
+        assert_eq!(error(json!({"properties": {"a": {"pattern": "^x"}}})), "a: unsupported schema keyword 'pattern'");
+        assert_eq!(error(json!({"type": "text"})), r#"invalid value for 'type': "text""#);
+        assert_eq!(
+            error(json!({"properties": {"n": {"type": "integer", "default": "two"}}})),
+            "n: default does not match the schema: must be integer, got string"
+        );
+    }
+
+    #[test]
+    fn test_params() {
+        let workflow = Workflow::from_json(
+            r#"{"name": "deploy", "inputs": {"version": "1.0", "replicas": 1}, "steps": [{"id": "a"}]}"#,
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("params.yaml");
+        fs::write(&file, "replicas: 2\nregion: eu\n").unwrap();
+        let params = Params {
+            file: Some(file),
+            values: vec![parse_param("version=2.10").unwrap(), parse_param("replicas=3").unwrap()],
+        };
+        let values = params.resolve(&workflow).unwrap();
+        assert_eq!(Value::Object(values), json!({"version": "2.10", "replicas": 3, "region": "eu"}));
+        assert!(parse_param("version").is_err());
+    }
+}
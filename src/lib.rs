@@ -3,115 +3,1378 @@
  * Core library for WorkflowEngine
  */
 
-use log::{info, error, debug};
+#[cfg(feature = "async")]
+pub mod async_processor;
+pub mod cancel;
+pub mod command;
+pub mod config;
+pub mod daemon;
+pub mod error;
+pub mod events;
+pub mod expr;
+pub mod format;
+pub mod graph;
+pub mod http;
+pub mod inputs;
+pub mod metrics;
+pub mod output;
+pub mod retry;
+mod run;
+pub mod schedule;
+mod scheduler;
+pub mod server;
+pub mod state;
+pub mod watch;
+pub mod workflow;
+
+use chrono::{DateTime, Utc};
+use log::{info, warn, error, debug};
 use serde::{Serialize, Deserialize};
+use run::RunScope;
+use scheduler::{Scheduler, WorkerSlots};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+pub use async_processor::AsyncProcessor;
+pub use cancel::{CancelReason, CancellationToken};
+pub use command::CommandSpec;
+pub use config::{ConfigLoader, EngineConfig, LogFormat};
+pub use daemon::Daemon;
+pub use error::WorkflowError;
+pub use events::{EventSink, ParentRun, RunEvent};
+pub use expr::{ExprError, Expression};
+pub use format::{ParseError, WorkflowFormat};
+pub use graph::GraphFormat;
+pub use inputs::Params;
+pub use metrics::Metrics;
+pub use output::OutputFormat;
+pub use retry::{Attempt, RetryFilter, RetryPolicy};
+pub use schedule::{CatchUp, Schedule};
+pub use server::ApiServer;
+pub use state::{RunState, RunStore};
+pub use watch::{Watch, Watcher};
+pub use workflow::{MapMode, Step, StepAction, TriggerRule, ValidationError, Workflow};
 
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+pub type Result<T> = std::result::Result<T, WorkflowError>;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProcessResult {
     pub success: bool,
     pub message: String,
     pub data: Option<serde_json::Value>,
+    /// Set when the step was stopped because a step or workflow timeout expired
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub timed_out: bool,
+}
+
+/// Final state of a single step within a workflow run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    Succeeded,
+    Failed,
+    /// Not run because a dependency did not succeed
+    Skipped,
+    /// Stopped by the step or workflow timeout
+    TimedOut,
+    /// Stopped or never started because the run was cancelled
+    Cancelled,
+}
+
+/// Overall outcome of a workflow run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Succeeded,
+    Failed,
+    TimedOut,
+    Cancelled,
+}
+
+impl fmt::Display for StepStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StepStatus::Succeeded => "succeeded",
+            StepStatus::Failed => "failed",
+            StepStatus::Skipped => "skipped",
+            StepStatus::TimedOut => "timed_out",
+            StepStatus::Cancelled => "cancelled",
+        })
+    }
+}
+
+impl fmt::Display for RunStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RunStatus::Succeeded => "succeeded",
+            RunStatus::Failed => "failed",
+            RunStatus::TimedOut => "timed_out",
+            RunStatus::Cancelled => "cancelled",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepResult {
+    pub id: String,
+    pub status: StepStatus,
+    pub result: Option<ProcessResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// Every attempt made, including retries; `result` is the last one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attempts: Vec<Attempt>,
+}
+
+impl StepResult {
+    fn not_run(id: &str, status: StepStatus) -> Self {
+        Self {
+            id: id.to_string(),
+            status,
+            result: None,
+            started_at: None,
+            duration_ms: None,
+            attempts: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkflowResult {
+    pub run_id: String,
+    pub workflow: String,
+    pub success: bool,
+    pub status: RunStatus,
+    /// Step results in the order the steps finished
+    pub steps: Vec<StepResult>,
+    /// The workflow's `outputs`, evaluated at the end of the run
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub outputs: serde_json::Map<String, serde_json::Value>,
+}
+
+impl WorkflowResult {
+    pub fn step(&self, id: &str) -> Option<&StepResult> {
+        self.steps.iter().find(|step| step.id == id)
+    }
+
+    /// The error this run amounts to, or `None` if it succeeded.
+    ///
+    /// A run that failed because a step timed out is reported as a timeout.
+    pub fn error(&self) -> Option<WorkflowError> {
+        let first = |status: StepStatus| self.steps.iter().find(|step| step.status == status);
+        match self.status {
+            RunStatus::Succeeded => None,
+            RunStatus::Failed => {
+                let step = self.steps.iter()
+                    .find(|step| matches!(step.status, StepStatus::Failed | StepStatus::TimedOut));
+                if let Some(step) = step.filter(|step| step.status == StepStatus::TimedOut) {
+                    return Some(WorkflowError::Timeout { step: Some(step.id.clone()) });
+                }
+                Some(WorkflowError::step_failed(
+                    step.map(|step| step.id.clone()).unwrap_or_default(),
+                    step.and_then(|step| step.result.as_ref())
+                        .map(|result| result.message.clone())
+                        .unwrap_or_else(|| "workflow did not complete successfully".to_string()),
+                ))
+            }
+            RunStatus::TimedOut => Some(WorkflowError::Timeout {
+                step: first(StepStatus::TimedOut).map(|step| step.id.clone()),
+            }),
+            RunStatus::Cancelled => Some(WorkflowError::Cancelled),
+        }
+    }
 }
 
-#[derive(Debug)]
 pub struct WorkflowEngineProcessor {
     verbose: bool,
-    processed_count: usize,
+    workers: usize,
+    processed_count: AtomicUsize,
+    steps_succeeded: AtomicUsize,
+    steps_failed: AtomicUsize,
+    steps_skipped: AtomicUsize,
+    default_retry: Option<RetryPolicy>,
+    default_timeout_ms: Option<u64>,
+    sinks: Vec<Arc<dyn EventSink>>,
+    metrics: Arc<Metrics>,
+    /// Shared by every run and nested body, so `workers` bounds them all
+    slots: WorkerSlots,
+    /// Workflows `workflow` steps can call by name
+    workflows: HashMap<String, Arc<Workflow>>,
+    /// Directories searched for `<name>.yaml` and friends when a name is not registered
+    library: Vec<PathBuf>,
+}
+
+impl fmt::Debug for WorkflowEngineProcessor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WorkflowEngineProcessor")
+            .field("verbose", &self.verbose)
+            .field("workers", &self.workers)
+            .field("processed_count", &self.processed_count)
+            .field("sinks", &self.sinks.len())
+            .field("workflows", &self.workflows.keys().collect::<Vec<_>>())
+            .field("library", &self.library)
+            .finish()
+    }
 }
 
 impl WorkflowEngineProcessor {
     pub fn new(verbose: bool) -> Self {
+        let metrics = Arc::new(Metrics::new());
         Self {
             verbose,
-            processed_count: 0,
+            workers: default_workers(),
+            processed_count: AtomicUsize::new(0),
+            steps_succeeded: AtomicUsize::new(0),
+            steps_failed: AtomicUsize::new(0),
+            steps_skipped: AtomicUsize::new(0),
+            default_retry: None,
+            default_timeout_ms: None,
+            sinks: Vec::new(),
+            slots: WorkerSlots::new(default_workers(), Arc::clone(&metrics)),
+            metrics,
+            workflows: HashMap::new(),
+            library: Vec::new(),
         }
     }
 
-    pub fn process(&mut self, data: &str) -> Result<ProcessResult> {
+    /// A processor with the workers and step defaults of `config`
+    pub fn from_config(config: &EngineConfig) -> Self {
+        let mut processor = Self::new(config.verbose).with_workers(config.workers);
+        processor.default_retry = config.retry.clone();
+        processor.default_timeout_ms = config.timeout_ms;
+        processor.library = config.library.clone();
+        processor
+    }
+
+    /// Retry policy for steps that do not declare one
+    pub fn with_default_retry(mut self, retry: Option<RetryPolicy>) -> Self {
+        self.default_retry = retry;
+        self
+    }
+
+    /// Timeout for steps that do not declare one
+    pub fn with_default_timeout_ms(mut self, timeout_ms: Option<u64>) -> Self {
+        self.default_timeout_ms = timeout_ms;
+        self
+    }
+
+    /// Sends the lifecycle events of every run to `sink`, e.g. a [`RunStore`]
+    pub fn with_event_sink(mut self, sink: Arc<dyn EventSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Lets `workflow` steps call `workflow` by its name
+    pub fn with_workflow(mut self, workflow: Workflow) -> Self {
+        self.workflows.insert(workflow.name.clone(), Arc::new(workflow));
+        self
+    }
+
+    /// Searches `dir` for `<name>.yaml`, `.yml`, `.json` or `.toml` when a
+    /// `workflow` step names a workflow that is not registered
+    pub fn with_library<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.library.push(dir.into());
+        self
+    }
+
+    /// Sets how many steps may run at the same time, counting the steps
+    /// nested in branches, maps, loops and child workflows of every run
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self.slots = WorkerSlots::new(self.workers, Arc::clone(&self.metrics));
+        self
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    pub fn processed_count(&self) -> usize {
+        self.processed_count.load(Ordering::SeqCst)
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn slots(&self) -> &WorkerSlots {
+        &self.slots
+    }
+
+    pub fn process(&self, data: &str) -> Result<ProcessResult> {
         if self.verbose {
             debug!("Processing data of length: {}", data.len());
         }
 
         // Simulate processing
-        self.processed_count += 1;
+        let item_number = self.processed_count.fetch_add(1, Ordering::SeqCst) + 1;
         
         let result = ProcessResult {
             success: true,
-            message: format!("Successfully processed item #{}", self.processed_count),
+            message: format!("Successfully processed item #{}", item_number),
             data: Some(serde_json::json!({
                 "length": data.len(),
                 "processed_at": chrono::Utc::now().to_rfc3339(),
-                "item_number": self.processed_count
+                "item_number": item_number
             })),
+            timed_out: false,
         };
 
         Ok(result)
     }
 
+    /// Runs `workflow`, starting each step as soon as its dependencies have succeeded.
+    ///
+    /// Independent steps run concurrently on the worker pool. A failed step does
+    /// not stop independent branches; its dependents are skipped.
+    pub fn run_workflow(&self, workflow: &Workflow) -> Result<WorkflowResult> {
+        self.run_workflow_cancellable(workflow, &CancellationToken::new())
+    }
+
+    /// Like [`run_workflow`](Self::run_workflow), but stops early when `token` does.
+    ///
+    /// Running steps are told to stop through their own child token, steps that
+    /// have not started yet are marked cancelled, and the partial result is returned.
+    pub fn run_workflow_cancellable(&self, workflow: &Workflow, token: &CancellationToken) -> Result<WorkflowResult> {
+        self.run_workflow_with_inputs(workflow, &serde_json::Map::new(), token)
+    }
+
+    /// Like [`run_workflow_cancellable`](Self::run_workflow_cancellable), with
+    /// `inputs` overriding the workflow's input defaults.
+    ///
+    /// The inputs are checked against the workflow's `input_schema` before
+    /// any step runs.
+    pub fn run_workflow_with_inputs(
+        &self,
+        workflow: &Workflow,
+        inputs: &serde_json::Map<String, serde_json::Value>,
+        token: &CancellationToken,
+    ) -> Result<WorkflowResult> {
+        self.run_workflow_as(&self.new_run_id(), workflow, inputs, token)
+    }
+
+    /// Like [`run_workflow_with_inputs`](Self::run_workflow_with_inputs),
+    /// under a run id chosen by the caller, e.g. one handed out before the
+    /// run starts. The id must be unused: take it from
+    /// [`new_run_id`](Self::new_run_id), which reserves it with the event
+    /// sinks. A [`RunStore`] logs an error instead of journaling the start of
+    /// a run whose journal already holds another one.
+    pub fn run_workflow_as(
+        &self,
+        run_id: &str,
+        workflow: &Workflow,
+        inputs: &serde_json::Map<String, serde_json::Value>,
+        token: &CancellationToken,
+    ) -> Result<WorkflowResult> {
+        let workflow = workflow.with_inputs(inputs)?;
+        let workflow = self.apply_defaults(&workflow);
+        let scheduler = Scheduler::new(&workflow)?;
+        let scope = RunScope::new(run_id, &workflow);
+        self.execute(&workflow, scope, scheduler, Vec::new(), token)
+    }
+
+    /// Continues an interrupted or failed run from its journal.
+    ///
+    /// Steps that already succeeded keep their recorded results and are not run
+    /// again; everything else runs under the same run id.
+    pub fn resume_run(&self, state: &RunState, token: &CancellationToken) -> Result<WorkflowResult> {
+        if state.status == Some(RunStatus::Succeeded) {
+            return Err(WorkflowError::persistence(&state.run_id, "run already succeeded, nothing to resume"));
+        }
+        let workflow = self.apply_defaults(&state.workflow);
+        let completed: Vec<StepResult> = state.completed_steps().into_iter().cloned().collect();
+        let ids: HashSet<&str> = completed.iter().map(|step| step.id.as_str()).collect();
+        let scheduler = Scheduler::resume(&workflow, &ids)?;
+        info!("Resuming run {}: {} of {} steps already completed",
+            state.run_id, completed.len(), workflow.steps.len());
+        let scope = RunScope::new(&state.run_id, &workflow).with_parent(state.parent.clone());
+        self.execute(&workflow, scope, scheduler, completed, token)
+    }
+
+    /// Fills in the default retry policy and timeout on steps without their own.
+    ///
+    /// `if`, `switch` and `map` steps are left alone; the defaults apply to
+    /// the steps nested in them instead.
+    pub(crate) fn apply_defaults<'a>(&self, workflow: &'a Workflow) -> Cow<'a, Workflow> {
+        let leaves = || workflow.all_steps().into_iter().filter(|step| step.action.nested().is_empty());
+        let needs_retry = self.default_retry.is_some() && leaves().any(|step| step.retry.is_none());
+        let needs_timeout = self.default_timeout_ms.is_some() && leaves().any(|step| step.timeout_ms.is_none());
+        if !needs_retry && !needs_timeout {
+            return Cow::Borrowed(workflow);
+        }
+        let mut workflow = workflow.clone();
+        self.fill_defaults(&mut workflow.steps);
+        Cow::Owned(workflow)
+    }
+
+    fn fill_defaults(&self, steps: &mut [Step]) {
+        for step in steps {
+            let nested = step.action.nested_mut();
+            if !nested.is_empty() {
+                for steps in nested {
+                    self.fill_defaults(steps);
+                }
+                continue;
+            }
+            if step.retry.is_none() {
+                step.retry = self.default_retry.clone();
+            }
+            if step.timeout_ms.is_none() {
+                step.timeout_ms = self.default_timeout_ms;
+            }
+        }
+    }
+
+    fn execute(
+        &self,
+        workflow: &Workflow,
+        scope: RunScope,
+        scheduler: Scheduler,
+        completed: Vec<StepResult>,
+        token: &CancellationToken,
+    ) -> Result<WorkflowResult> {
+        info!("Running workflow '{}' with {} steps (run {})", workflow.name, workflow.steps.len(), scope.run_id());
+        self.start_run(workflow, &scope, !completed.is_empty());
+        let token = token.child(workflow.timeout_ms.map(Duration::from_millis));
+
+        for step in completed {
+            scope.record(step);
+        }
+        self.run_steps(&scope, workflow, scheduler, &token);
+        Ok(self.finish(workflow, &scope, &token))
+    }
+
+    // Drives `workflow` (the whole run, or one branch of it) to completion,
+    // recording every outcome in `scope`.
+    fn run_steps(&self, scope: &RunScope, workflow: &Workflow, scheduler: Scheduler, token: &CancellationToken) {
+        scheduler::run_parallel(
+            workflow,
+            scheduler,
+            self.workers,
+            &self.slots,
+            token,
+            |step, token| self.run_step(scope, step, token),
+            |step, progress| self.emit_progress(scope, step, progress),
+            |step| self.check_condition(scope, step).map(|result| scope.record(result)),
+            |step, completion| scope.record(self.record_completion(scope, step, completion)),
+            |step, status| {
+                scope.record(self.record_unrun(scope, step, status));
+            },
+        );
+    }
+
+    /// A fresh id from [`state::new_run_id`] that none of the event sinks,
+    /// such as a [`RunStore`], has a run by; the sinks reserve it
+    pub fn new_run_id(&self) -> String {
+        loop {
+            let run_id = state::new_run_id();
+            if self.sinks.iter().all(|sink| sink.reserve(&run_id)) {
+                return run_id;
+            }
+            warn!("Run id {} is taken; picking another", run_id);
+        }
+    }
+
+    fn emit(&self, event: RunEvent) {
+        for sink in &self.sinks {
+            sink.emit(&event);
+        }
+    }
+
+    pub(crate) fn start_run(&self, workflow: &Workflow, scope: &RunScope, resumed: bool) {
+        self.metrics.run_started(&workflow.name);
+        if self.sinks.is_empty() {
+            return;
+        }
+        self.emit(RunEvent::RunStarted {
+            run_id: scope.run_id().to_string(),
+            workflow: Box::new(workflow.clone()),
+            at: Utc::now(),
+            resumed,
+            parent: scope.parent().cloned(),
+        });
+    }
+
+    pub(crate) fn finish(&self, workflow: &Workflow, scope: &RunScope, token: &CancellationToken) -> WorkflowResult {
+        let run_id = scope.run_id();
+        let outputs = scope.outputs(&workflow.outputs);
+        let steps = scope.take_results();
+        // Steps skip either because a dependency failed or because their
+        // condition was false; only the first counts against the run
+        let success = steps.iter().all(|step| matches!(step.status, StepStatus::Succeeded | StepStatus::Skipped));
+        let status = match token.reason() {
+            _ if success => RunStatus::Succeeded,
+            Some(CancelReason::TimedOut) => RunStatus::TimedOut,
+            Some(CancelReason::Cancelled) => RunStatus::Cancelled,
+            None => RunStatus::Failed,
+        };
+        if status != RunStatus::Succeeded {
+            warn!("Workflow '{}' finished with status {:?}", workflow.name, status);
+        }
+        self.metrics.run_finished(&workflow.name, status);
+        self.emit(RunEvent::RunFinished {
+            run_id: run_id.to_string(),
+            status,
+            at: Utc::now(),
+            outputs: outputs.clone(),
+        });
+        WorkflowResult {
+            run_id: run_id.to_string(),
+            workflow: workflow.name.clone(),
+            success,
+            status,
+            steps,
+            outputs,
+        }
+    }
+
+    pub(crate) fn record_completion(&self, scope: &RunScope, step: &Step, completion: scheduler::Completion) -> StepResult {
+        let attempts = completion.attempts;
+        let started_at = attempts.first().map(|a| a.started_at).unwrap_or_else(Utc::now);
+        let result = match attempts.last() {
+            Some(attempt) => attempt.result.clone(),
+            None => ProcessResult {
+                success: false,
+                message: format!("Step '{}' was never attempted", step.id),
+                data: None,
+                timed_out: false,
+            },
+        };
+        let status = match completion.interrupted {
+            _ if result.success => StepStatus::Succeeded,
+            Some(CancelReason::TimedOut) => StepStatus::TimedOut,
+            Some(CancelReason::Cancelled) => StepStatus::Cancelled,
+            None => StepStatus::Failed,
+        };
+        if status == StepStatus::Succeeded {
+            self.steps_succeeded.fetch_add(1, Ordering::SeqCst);
+        } else {
+            error!("Step '{}' failed: {}", step.id, result.message);
+            self.steps_failed.fetch_add(1, Ordering::SeqCst);
+        }
+        if attempts.len() > 1 {
+            info!("Step '{}' finished after {} attempts", step.id, attempts.len());
+        }
+        let duration_ms = (Utc::now() - started_at).num_milliseconds().max(0) as u64;
+        self.metrics.step_finished(scope.workflow(), status, duration_ms);
+        let result = StepResult {
+            id: step.id.clone(),
+            status,
+            result: Some(result),
+            started_at: Some(started_at),
+            duration_ms: Some(duration_ms),
+            attempts,
+        };
+        self.emit_step(scope, &result);
+        result
+    }
+
+    /// Evaluates the step's `when` condition before it is queued.
+    ///
+    /// Returns the recorded result when the step must not run: skipped when
+    /// the condition is false, failed when it cannot be evaluated.
+    pub(crate) fn check_condition(&self, scope: &RunScope, step: &Step) -> Option<StepResult> {
+        let result = match scope.condition(step) {
+            Ok(true) => return None,
+            Ok(false) => {
+                info!("Skipping step '{}': condition is false", step.id);
+                self.steps_skipped.fetch_add(1, Ordering::SeqCst);
+                StepResult::not_run(&step.id, StepStatus::Skipped)
+            }
+            Err(e) => {
+                error!("Step '{}' failed: cannot evaluate condition: {}", step.id, e);
+                self.steps_failed.fetch_add(1, Ordering::SeqCst);
+                StepResult {
+                    result: Some(ProcessResult {
+                        success: false,
+                        message: format!("Step '{}' failed: cannot evaluate condition: {}", step.id, e),
+                        data: None,
+                        timed_out: false,
+                    }),
+                    ..StepResult::not_run(&step.id, StepStatus::Failed)
+                }
+            }
+        };
+        self.emit_step(scope, &result);
+        Some(result)
+    }
+
+    /// Records a step that never ran, either skipped or cancelled
+    pub(crate) fn record_unrun(&self, scope: &RunScope, step: &Step, status: StepStatus) -> StepResult {
+        if status == StepStatus::Skipped {
+            warn!("Skipping step '{}': a dependency did not succeed", step.id);
+            self.steps_skipped.fetch_add(1, Ordering::SeqCst);
+        } else {
+            warn!("Step '{}' was not started: run cancelled", step.id);
+        }
+        let result = StepResult::not_run(&step.id, status);
+        self.emit_step(scope, &result);
+        result
+    }
+
+    fn emit_step(&self, scope: &RunScope, step: &StepResult) {
+        if !self.sinks.is_empty() && scope.is_journaled() {
+            self.emit(RunEvent::StepFinished {
+                run_id: scope.run_id().to_string(),
+                step: step.clone(),
+            });
+        }
+    }
+
+    pub(crate) fn emit_progress(&self, scope: &RunScope, step: &Step, progress: scheduler::Progress) {
+        if let scheduler::Progress::RetryScheduled(_) = progress {
+            self.metrics.retry_scheduled(scope.workflow());
+        }
+        if self.sinks.is_empty() || !scope.is_journaled() {
+            return;
+        }
+        let run_id = scope.run_id().to_string();
+        self.emit(match progress {
+            scheduler::Progress::Started(attempt) => RunEvent::StepStarted {
+                run_id,
+                step: step.id.clone(),
+                attempt,
+                at: Utc::now(),
+            },
+            scheduler::Progress::RetryScheduled(attempt) => RunEvent::RetryScheduled {
+                run_id,
+                step: step.id.clone(),
+                attempt: attempt.number,
+                delay_ms: attempt.retry_delay_ms.unwrap_or_default(),
+                error: attempt.result.message.clone(),
+                at: Utc::now(),
+            },
+        });
+    }
+
+    pub(crate) fn run_step(&self, scope: &RunScope, step: &Step, token: &CancellationToken) -> Result<ProcessResult> {
+        let step = &*scope.interpolate(step)?;
+        debug!("Running step '{}' ({})", step.id, step.action.kind());
+
+        let mut result = match &step.action {
+            StepAction::Process {} => {
+                let payload = serde_json::to_string(&step.inputs)?;
+                let mut result = self.process(&payload)?;
+                // Echo the inputs so downstream steps and declared outputs can see them
+                if let Some(serde_json::Value::Object(data)) = result.data.as_mut() {
+                    for (key, value) in &step.inputs {
+                        data.entry(key.clone()).or_insert_with(|| value.clone());
+                    }
+                }
+                result.message = format!("Step '{}': {}", step.id, result.message);
+                result
+            }
+            StepAction::Command(spec) => command::run_command(&step.id, spec, token)?,
+            StepAction::If { .. } | StepAction::Switch { .. } => self.run_branch(scope, step, token)?,
+            StepAction::Loop { .. } => self.run_loop(scope, step, token)?,
+            StepAction::SubWorkflow { .. } => self.run_sub_workflow(scope, step, token)?,
+            StepAction::Map { .. } => self.run_map(scope, step, token)?,
+        };
+
+        if result.success {
+            let missing = step.outputs.iter().find(|output| {
+                result
+                    .data
+                    .as_ref()
+                    .and_then(|data| data.get(output.as_str()))
+                    .is_none()
+            });
+            if let Some(output) = missing {
+                result.success = false;
+                result.message = format!("Step '{}' did not produce declared output '{}'", step.id, output);
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Picks the branch of an `if` or `switch` step and runs it to completion.
+    // The steps of the other branches are recorded as skipped.
+    fn run_branch(&self, scope: &RunScope, step: &Step, token: &CancellationToken) -> Result<ProcessResult> {
+        let fail = |e: ExprError| WorkflowError::step_failed(&step.id, "cannot evaluate expression").with_source(e);
+        let (taken, value) = match &step.action {
+            StepAction::If { condition, .. } => {
+                let holds = scope.holds(condition).map_err(fail)?;
+                (if holds { 0 } else { 1 }, serde_json::Value::Bool(holds))
+            }
+            StepAction::Switch { value, cases, .. } => {
+                let value = scope.evaluate(value).map_err(fail)?;
+                let key = expr::display(&value);
+                (cases.keys().position(|case| *case == key).unwrap_or(cases.len()), value)
+            }
+            _ => return Err(WorkflowError::step_failed(&step.id, "not a control-flow step")),
+        };
+
+        let branches = step.action.branches();
+        for (name, steps) in branches.iter().enumerate().filter(|(i, _)| *i != taken).map(|(_, branch)| *branch) {
+            for skipped in steps.iter().flat_map(|s| std::iter::once(s).chain(s.descendants())) {
+                info!("Skipping step '{}': branch '{}' of '{}' was not taken", skipped.id, name, step.id);
+                self.steps_skipped.fetch_add(1, Ordering::SeqCst);
+                let result = StepResult::not_run(&skipped.id, StepStatus::Skipped);
+                self.emit_step(scope, &result);
+                scope.record(result);
+            }
+        }
+
+        let (name, steps) = branches[taken];
+        info!("Step '{}' takes branch '{}'", step.id, name);
+        let mut success = true;
+        if !steps.is_empty() {
+            let branch = Workflow::of_steps(format!("{}.{}", step.id, name), steps.to_vec());
+            let succeeded = scope.succeeded();
+            let done: HashSet<&str> = succeeded.iter().map(String::as_str).collect();
+            self.run_steps(scope, &branch, Scheduler::resume(&branch, &done)?, token);
+            success = branch
+                .recorded_steps()
+                .iter()
+                .all(|nested| matches!(scope.status(&nested.id), Some(StepStatus::Succeeded | StepStatus::Skipped)));
+        }
+
+        Ok(ProcessResult {
+            success,
+            message: if success {
+                format!("Step '{}' took branch '{}'", step.id, name)
+            } else {
+                format!("Step '{}': branch '{}' did not succeed", step.id, name)
+            },
+            data: Some(serde_json::json!({ "branch": name, "value": value })),
+            timed_out: false,
+        })
+    }
+
+    // Runs the template of a `map` step once per item, at most `concurrency`
+    // items at a time, and gathers the outcome of every item in order.
+    fn run_map(&self, scope: &RunScope, step: &Step, token: &CancellationToken) -> Result<ProcessResult> {
+        let (items, steps, concurrency, mode) = match &step.action {
+            StepAction::Map { items, steps, concurrency, mode } => (items, steps, *concurrency, *mode),
+            _ => return Err(WorkflowError::step_failed(&step.id, "not a map step")),
+        };
+        let items = match scope.evaluate(items) {
+            Ok(serde_json::Value::Array(items)) => items,
+            Ok(other) => {
+                let message = format!("items must be an array, got {}", expr::Type::of(&other));
+                return Err(WorkflowError::step_failed(&step.id, message));
+            }
+            Err(e) => return Err(WorkflowError::step_failed(&step.id, "cannot evaluate expression").with_source(e)),
+        };
+        let template = Workflow::of_steps(format!("{}[]", step.id), steps.clone());
+        let scheduler = Scheduler::new(&template)?;
+        let limit = concurrency.unwrap_or(self.workers).clamp(1, items.len().max(1));
+        info!("Step '{}' mapping {} items, {} at a time", step.id, items.len(), limit);
+
+        // Stopped on the first failure in fail-fast mode
+        let map_token = token.child(None);
+        let next = AtomicUsize::new(0);
+        let outcomes: Vec<Mutex<Option<serde_json::Value>>> = items.iter().map(|_| Mutex::new(None)).collect();
+        thread::scope(|threads| {
+            for _ in 0..limit {
+                threads.spawn(|| loop {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let item = match items.get(index) {
+                        Some(item) => item,
+                        None => break,
+                    };
+                    let outcome = if map_token.is_cancelled() {
+                        serde_json::json!({ "index": index, "item": item, "status": StepStatus::Cancelled })
+                    } else {
+                        let element = scope.child([("item", item.clone()), ("index", serde_json::Value::from(index))]);
+                        self.run_steps(&element, &template, scheduler.clone(), &map_token);
+                        let head = serde_json::json!({ "index": index, "item": item });
+                        let outcome = body_outcome(head, element.take_results());
+                        if outcome["status"] != "succeeded" && mode == MapMode::FailFast {
+                            map_token.cancel();
+                        }
+                        outcome
+                    };
+                    if let Ok(mut slot) = outcomes[index].lock() {
+                        *slot = Some(outcome);
+                    }
+                });
+            }
+        });
+
+        let results: Vec<serde_json::Value> = outcomes
+            .into_iter()
+            .map(|slot| slot.into_inner().ok().flatten().unwrap_or_default())
+            .collect();
+        let count = |status: &str| results.iter().filter(|outcome| outcome["status"] == status).count();
+        let (succeeded, failed) = (count("succeeded"), count("failed"));
+        let success = succeeded == results.len();
+        Ok(ProcessResult {
+            success,
+            message: format!(
+                "Step '{}' mapped {} items: {} succeeded, {} failed, {} not run",
+                step.id,
+                results.len(),
+                succeeded,
+                failed,
+                results.len() - succeeded - failed
+            ),
+            data: Some(serde_json::json!({
+                "count": results.len(),
+                "succeeded": succeeded,
+                "failed": failed,
+                "results": results,
+            })),
+            timed_out: false,
+        })
+    }
+
+    // Runs the body of a `loop` step until its condition says to stop, at
+    // most `max_iterations` times. Every iteration is kept in the output.
+    fn run_loop(&self, scope: &RunScope, step: &Step, token: &CancellationToken) -> Result<ProcessResult> {
+        let (repeat_while, until, max_iterations, delay_ms, steps) = match &step.action {
+            StepAction::Loop { repeat_while, until, max_iterations, delay_ms, steps } => {
+                (repeat_while, until, *max_iterations, *delay_ms, steps)
+            }
+            _ => return Err(WorkflowError::step_failed(&step.id, "not a loop step")),
+        };
+        let body = Workflow::of_steps(format!("{}[]", step.id), steps.clone());
+        let scheduler = Scheduler::new(&body)?;
+
+        let mut results = Vec::new();
+        let mut failure = None;
+        for iteration in 1..=max_iterations {
+            debug!("Step '{}' starting iteration {}", step.id, iteration);
+            let scope = scope.child([("iteration", serde_json::Value::from(iteration))]);
+            self.run_steps(&scope, &body, scheduler.clone(), token);
+            let outcome = body_outcome(serde_json::json!({ "iteration": iteration }), scope.take_results());
+            let succeeded = outcome["status"] == "succeeded";
+            results.push(outcome);
+            if !succeeded {
+                failure = Some(format!("Step '{}': iteration {} did not succeed", step.id, iteration));
+                break;
+            }
+            let done = match (repeat_while, until) {
+                (Some(condition), _) => scope.holds(condition).map(|holds| !holds),
+                (_, Some(condition)) => scope.holds(condition),
+                (None, None) => Ok(true),
+            }
+            .map_err(|e| WorkflowError::step_failed(&step.id, "cannot evaluate expression").with_source(e))?;
+            if done {
+                break;
+            }
+            if iteration == max_iterations {
+                failure = Some(format!("Step '{}' did not finish after {} iterations", step.id, max_iterations));
+            } else if !token.sleep(Duration::from_millis(delay_ms.unwrap_or(0))) {
+                failure = Some(format!("Step '{}' was cancelled after {} iterations", step.id, iteration));
+                break;
+            }
+        }
+
+        let last = results.last().map(|outcome| outcome["output"].clone()).unwrap_or_default();
+        Ok(ProcessResult {
+            success: failure.is_none(),
+            message: failure.unwrap_or_else(|| format!("Step '{}' finished after {} iterations", step.id, results.len())),
+            data: Some(serde_json::json!({
+                "iterations": results.len(),
+                "last": last,
+                "results": results,
+            })),
+            timed_out: false,
+        })
+    }
+
+    // Runs the workflow a `workflow` step points to as a child run with its
+    // own run id, and hands back the outputs of the child's steps.
+    fn run_sub_workflow(&self, scope: &RunScope, step: &Step, token: &CancellationToken) -> Result<ProcessResult> {
+        let fail = |message: String| WorkflowError::step_failed(&step.id, message);
+        let mut child = match &step.action {
+            StepAction::SubWorkflow { path: Some(path), .. } => load_workflow(Path::new(path))
+                .map_err(|e| fail(format!("cannot load workflow {}", path)).with_source(e))?,
+            StepAction::SubWorkflow { name: Some(name), .. } => self.find_workflow(name).map_err(|e| match e {
+                Some(e) => fail(format!("cannot load workflow '{}'", name)).with_source(e),
+                None => fail(format!("no workflow named '{}' is registered or in the library", name)),
+            })?,
+            _ => return Err(fail("not a workflow step".to_string())),
+        };
+        if scope.callers().contains(&child.name) {
+            let chain: Vec<&str> = scope.callers().iter().map(String::as_str).chain([child.name.as_str()]).collect();
+            return Err(fail(format!("workflow '{}' calls itself: {}", child.name, chain.join(" -> "))));
+        }
+        let inputs = child.resolve_inputs(&step.inputs).map_err(|e| {
+            fail(format!("invalid inputs for workflow '{}'", child.name)).with_source(e)
+        })?;
+        child.inputs = inputs;
+
+        let child = self.apply_defaults(&child).into_owned();
+        let run_id = self.new_run_id();
+        info!("Step '{}' runs workflow '{}' as run {}", step.id, child.name, run_id);
+        let scheduler = Scheduler::new(&child)?;
+        let result = self.execute(&child, scope.called(&run_id, &child, &step.id), scheduler, Vec::new(), token)?;
+
+        // Without declared outputs, the caller sees the output of every step
+        let outputs = if child.outputs.is_empty() {
+            result
+                .steps
+                .iter()
+                .map(|step| (step.id.clone(), step.result.as_ref().and_then(|result| result.data.clone()).unwrap_or_default()))
+                .collect()
+        } else {
+            result.outputs.clone()
+        };
+        Ok(ProcessResult {
+            success: result.success,
+            message: match result.error() {
+                None => format!("Step '{}' ran workflow '{}' (run {})", step.id, child.name, run_id),
+                Some(e) => format!("Step '{}': workflow '{}' (run {}) {}: {}", step.id, child.name, run_id, result.status, e),
+            },
+            data: Some(serde_json::json!({
+                "run_id": run_id,
+                "workflow": child.name,
+                "status": result.status,
+                "outputs": outputs,
+            })),
+            timed_out: false,
+        })
+    }
+
+    // A registered workflow, or the first `<name>.<ext>` file in the library.
+    // `Err(None)` means there is no such workflow.
+    pub(crate) fn find_workflow(&self, name: &str) -> std::result::Result<Workflow, Option<WorkflowError>> {
+        if let Some(workflow) = self.workflows.get(name) {
+            return Ok(Workflow::clone(workflow));
+        }
+        let path = self
+            .library
+            .iter()
+            .flat_map(|dir| ["yaml", "yml", "json", "toml"].map(|ext| dir.join(format!("{}.{}", name, ext))))
+            .find(|path| path.is_file())
+            .ok_or(None)?;
+        debug!("Loading workflow '{}' from {}", name, path.display());
+        load_workflow(&path).map_err(Some)
+    }
+
     pub fn get_stats(&self) -> serde_json::Value {
         serde_json::json!({
-            "processed_count": self.processed_count,
-            "verbose": self.verbose
+            "processed_count": self.processed_count(),
+            "verbose": self.verbose,
+            "workers": self.workers,
+            "steps_succeeded": self.steps_succeeded.load(Ordering::SeqCst),
+            "steps_failed": self.steps_failed.load(Ordering::SeqCst),
+            "steps_skipped": self.steps_skipped.load(Ordering::SeqCst),
+            "queue_depth": self.metrics.queue_depth(),
+            "workers_busy": self.metrics.busy_workers()
         })
     }
 }
 
+// Sums up one map item or loop iteration: adds to `head` its overall status,
+// the output of each body step, and the first error
+fn body_outcome(mut head: serde_json::Value, steps: Vec<StepResult>) -> serde_json::Value {
+    let failed = steps.iter().find(|step| matches!(step.status, StepStatus::Failed | StepStatus::TimedOut));
+    let status = match failed {
+        Some(_) => StepStatus::Failed,
+        None if steps.iter().any(|step| step.status == StepStatus::Cancelled) => StepStatus::Cancelled,
+        None => StepStatus::Succeeded,
+    };
+    let output: serde_json::Map<String, serde_json::Value> = steps
+        .iter()
+        .map(|step| (step.id.clone(), step.result.as_ref().and_then(|result| result.data.clone()).unwrap_or_default()))
+        .collect();
+    head["status"] = serde_json::json!(status);
+    head["output"] = serde_json::Value::Object(output);
+    if let Some(result) = failed.and_then(|step| step.result.as_ref()) {
+        head["error"] = serde_json::Value::String(result.message.clone());
+    }
+    head
+}
+
+fn default_workers() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(4)
+}
+
 /// Main processing function
-pub fn run(verbose: bool, input: Option<String>, output: Option<String>) -> Result<()> {
-    if verbose {
-        env_logger::Builder::from_default_env()
-            .filter_level(log::LevelFilter::Debug)
-            .init();
-    } else {
-        env_logger::init();
+///
+/// `workers` caps how many steps run at once; `None` uses one per available CPU.
+pub fn run(verbose: bool, input: Option<String>, output: Option<String>, workers: Option<usize>) -> Result<()> {
+    let mut config = EngineConfig::default();
+    config.verbose = verbose;
+    if let Some(workers) = workers {
+        config.workers = workers;
     }
+    run_cancellable(&config, input, &Params::default(), output, &CancellationToken::new())
+}
+
+/// Like [`run`], but with explicit settings and input values, stopping the
+/// workflow when `token` is cancelled.
+///
+/// The run is journaled in the configured state directory so it can be
+/// resumed later. The partial result is still written to `output` before
+/// returning an error.
+pub fn run_cancellable(
+    config: &EngineConfig,
+    input: Option<String>,
+    params: &Params,
+    output: Option<String>,
+    token: &CancellationToken,
+) -> Result<()> {
+    init_logging(config);
     
     info!("Starting WorkflowEngine processing");
     
-    let mut processor = WorkflowEngineProcessor::new(verbose);
+    let Some(path) = input else {
+        return Err(WorkflowError::Config {
+            origin: "command line".to_string(),
+            message: "no workflow given; pass a workflow file, e.g. `workflowengine run deploy.yaml`".to_string(),
+        });
+    };
+    let processor = build_processor(config);
     
-    // Read input
-    let input_data = match input {
-        Some(path) => {
-            info!("Reading from file: {}", path);
-            fs::read_to_string(&path)?
-        },
-        None => {
-            info!("Using default test data");
-            "Sample data for processing".to_string()
+    info!("Reading workflow from file: {}", path);
+    let workflow = load_workflow(Path::new(&path))?;
+    let inputs = params.resolve(&workflow)?;
+    let result = processor.run_workflow_with_inputs(&workflow, &inputs, token)?;
+    info!("Run id: {}", result.run_id);
+    if result.status != RunStatus::Succeeded {
+        warn!("Continue this run with: workflowengine resume {}", result.run_id);
+    }
+    
+    if config.verbose {
+        debug!("Workflow result: {:#?}", result);
+    }
+    write_output(output, &output::render_workflow(&result, config.output_format)?)?;
+    write_metrics(config, processor.metrics())?;
+    
+    let stats = processor.get_stats();
+    info!("Processing complete. Stats: {}", stats);
+    
+    result.error().map_or(Ok(()), Err)
+}
+
+/// Picks up an earlier run from its journal in the configured state directory.
+///
+/// Steps that succeeded before are not run again; their recorded results are
+/// part of the output.
+pub fn resume(config: &EngineConfig, run_id: &str, output: Option<String>, token: &CancellationToken) -> Result<()> {
+    init_logging(config);
+    
+    let state = RunStore::new(&config.state_dir).load(run_id)?;
+    let processor = build_processor(config);
+    let result = processor.resume_run(&state, token)?;
+    
+    if config.verbose {
+        debug!("Workflow result: {:#?}", result);
+    }
+    write_output(output, &output::render_workflow(&result, config.output_format)?)?;
+    write_metrics(config, processor.metrics())?;
+    
+    info!("Processing complete. Stats: {}", processor.get_stats());
+    
+    result.error().map_or(Ok(()), Err)
+}
+
+/// Starts runs of the workflows in `inputs`, files or directories of them,
+/// at the times their `schedule` gives, until `token` is cancelled.
+///
+/// Workflows without a schedule are ignored. When each workflow was last due
+/// is kept in `schedule.json` in the configured state directory.
+pub fn run_schedule(config: &EngineConfig, inputs: &[String], token: &CancellationToken) -> Result<()> {
+    init_logging(config);
+
+    let workflows = load_triggered(inputs, "schedule", |workflow| workflow.schedule.is_some())?;
+    info!("Scheduling {} workflows", workflows.len());
+    let processor = build_processor(config);
+    let metrics = Arc::clone(processor.metrics());
+    let mut daemon = Daemon::new(processor, workflows)?.with_state_file(config.state_dir.join("schedule.json"))?;
+    export_metrics(config, &metrics, || daemon.run(token))
+}
+
+/// Starts runs of the workflows in `inputs`, files or directories of them,
+/// when files covered by their `watch` appear or change, until `token` is
+/// cancelled.
+///
+/// Workflows without a watch are ignored. The files already run for are kept
+/// in `watch.json` in the configured state directory.
+pub fn run_watch(config: &EngineConfig, inputs: &[String], token: &CancellationToken) -> Result<()> {
+    init_logging(config);
+
+    let workflows = load_triggered(inputs, "watch", |workflow| workflow.watch.is_some())?;
+    info!("Watching files for {} workflows", workflows.len());
+    let processor = build_processor(config);
+    let metrics = Arc::clone(processor.metrics());
+    let mut watcher = Watcher::new(processor, workflows)?.with_state_file(config.state_dir.join("watch.json"))?;
+    export_metrics(config, &metrics, || watcher.run(token))
+}
+
+// The workflows in `inputs`, files or directories of them, that have the
+// `trigger` field, warning about the others
+fn load_triggered(inputs: &[String], trigger: &str, triggered: impl Fn(&Workflow) -> bool) -> Result<Vec<Workflow>> {
+    let mut workflows = Vec::new();
+    for input in inputs {
+        let path = Path::new(input);
+        let files = if path.is_dir() {
+            let mut files: Vec<PathBuf> = fs::read_dir(path)
+                .map_err(|e| WorkflowError::from(e).with_path(path))?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|file| {
+                    let extension = file.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+                    file.is_file() && ["yaml", "yml", "json", "toml"].contains(&extension)
+                })
+                .collect();
+            files.sort();
+            files
+        } else {
+            vec![path.to_path_buf()]
+        };
+        for file in files {
+            let workflow = load_workflow(&file)?;
+            if !triggered(&workflow) {
+                warn!("Ignoring {}: workflow '{}' has no {}", file.display(), workflow.name, trigger);
+                continue;
+            }
+            workflows.push(workflow);
         }
-    };
+    }
+    if workflows.is_empty() {
+        return Err(WorkflowError::Config {
+            origin: "command line".to_string(),
+            message: format!("none of the given workflows has a {}", trigger),
+        });
+    }
+    Ok(workflows)
+}
+
+/// Serves the run API (see [`ApiServer`]) on `listen`, e.g. `127.0.0.1:8080`,
+/// until `token` is cancelled.
+///
+/// Runs are journaled in the configured state directory, and workflows can
+/// be submitted by name from the configured library, or also inline with
+/// `allow_inline`.
+pub fn serve(config: &EngineConfig, listen: &str, allow_inline: bool, token: &CancellationToken) -> Result<()> {
+    init_logging(config);
+
+    let listener = std::net::TcpListener::bind(listen).map_err(|e| WorkflowError::Config {
+        origin: "--listen".to_string(),
+        message: format!("cannot listen on {}: {}", listen, e),
+    })?;
+    let processor = WorkflowEngineProcessor::from_config(config);
+    let metrics = Arc::clone(processor.metrics());
+    let server = Arc::new(ApiServer::new(processor, RunStore::new(&config.state_dir)).with_allow_inline(allow_inline));
+    export_metrics(config, &metrics, || server.serve(listener, token))
+}
+
+/// Checks workflow files without running them.
+///
+/// Every file is checked and the valid ones are listed. The failure of a
+/// single file is returned as it is; several come back together as
+/// [`WorkflowError::Multiple`], in the order of `inputs`.
+pub fn validate(config: &EngineConfig, inputs: &[String]) -> Result<()> {
+    init_logging(config);
+    
+    let mut errors = Vec::new();
+    for input in inputs {
+        match load_workflow(Path::new(input)) {
+            Ok(workflow) => println!("ok: {} (workflow '{}', {} steps)", input, workflow.name, workflow.steps.len()),
+            Err(e) => errors.push(e),
+        }
+    }
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.remove(0)),
+        _ => Err(WorkflowError::Multiple(errors)),
+    }
+}
+
+/// Prints a workflow's dependency graph in `format`.
+///
+/// With `run_id`, the statuses and durations of that run are drawn on the
+/// steps, and the workflow file can be left out to draw the workflow as the
+/// run journaled it.
+pub fn graph(
+    config: &EngineConfig,
+    input: Option<&str>,
+    format: GraphFormat,
+    run_id: Option<&str>,
+    output: Option<String>,
+) -> Result<()> {
+    init_logging(config);
     
-    // Process data
-    let result = processor.process(&input_data)?;
+    let run = run_id.map(|run_id| RunStore::new(&config.state_dir).load(run_id)).transpose()?;
+    let workflow = match (input, &run) {
+        (Some(input), _) => load_workflow(Path::new(input))?,
+        (None, Some(run)) => run.workflow.clone(),
+        (None, None) => {
+            return Err(WorkflowError::Config {
+                origin: "command line".to_string(),
+                message: "give a workflow file or --run".to_string(),
+            })
+        }
+    };
+    let results = run.as_ref().map_or(&[][..], |run| run.steps.as_slice());
+    write_output(output, &graph::render(&workflow, format, results)?)
+}
+
+/// Prints the state of one run as recorded in its journal
+pub fn status(config: &EngineConfig, run_id: &str, output: Option<String>) -> Result<()> {
+    init_logging(config);
     
-    if verbose {
-        debug!("Processing result: {:#?}", result);
+    let state = RunStore::new(&config.state_dir).load(run_id)?;
+    let mut text = format!(
+        "Run:      {}\nWorkflow: {}\nStatus:   {}\nStarted:  {}\n",
+        state.run_id,
+        state.workflow.name,
+        state.status_label(),
+        state.started_at.to_rfc3339(),
+    );
+    if let Some(parent) = &state.parent {
+        text.push_str(&format!("Parent:   {} (step '{}')\n", parent.run_id, parent.step));
+    }
+    if let Some(finished_at) = state.finished_at {
+        text.push_str(&format!("Finished: {}\n", finished_at.to_rfc3339()));
     }
     
-    // Save output
-    let output_json = serde_json::to_string_pretty(&result)?;
+    let rows: Vec<Vec<String>> = state.workflow.recorded_steps().into_iter()
+        .map(|step| match state.step(&step.id) {
+            Some(result) => vec![
+                step.id.clone(),
+                result.status.to_string(),
+                result.duration_ms.map(|ms| format!("{}ms", ms)).unwrap_or_default(),
+                result.attempts.len().to_string(),
+                result.result.as_ref().map(|r| r.message.clone()).unwrap_or_default(),
+            ],
+            None => vec![step.id.clone(), "pending".to_string(), String::new(), String::new(), String::new()],
+        })
+        .collect();
+    text.push('\n');
+    text.push_str(&output::render_table(&["STEP", "STATUS", "DURATION", "ATTEMPTS", "MESSAGE"], &rows));
+    write_output(output, &text)
+}
+
+/// Prints a summary of every run in the state directory, most recent first
+pub fn list_runs(config: &EngineConfig, output: Option<String>) -> Result<()> {
+    init_logging(config);
     
+    let runs = RunStore::new(&config.state_dir).list()?;
+    if runs.is_empty() {
+        return write_output(output, "No runs recorded");
+    }
+    let rows: Vec<Vec<String>> = runs.iter()
+        .map(|run| vec![
+            run.run_id.clone(),
+            run.workflow.name.clone(),
+            run.status_label(),
+            run.started_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+            format!("{}/{}", run.completed_steps().len(), run.workflow.recorded_steps().len()),
+        ])
+        .collect();
+    write_output(output, &output::render_table(&["RUN ID", "WORKFLOW", "STATUS", "STARTED", "SUCCEEDED"], &rows))
+}
+
+/// Prints every effective setting with the layer it came from, and on stderr
+/// any warnings from loading the config
+pub fn show_config(config: &EngineConfig, output: Option<String>) -> Result<()> {
+    for warning in config.warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+    let rows: Vec<Vec<String>> = config.entries()
+        .into_iter()
+        .map(|(key, value, source)| vec![key, value, source.to_string()])
+        .collect();
+    write_output(output, &output::render_table(&["KEY", "VALUE", "SOURCE"], &rows))
+}
+
+fn init_logging(config: &EngineConfig) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if config.verbose {
+        builder.filter_level(log::LevelFilter::Debug);
+    }
+    if config.log_format == LogFormat::Json {
+        builder.format(|buf, record| {
+            use std::io::Write;
+            let line = serde_json::json!({
+                "timestamp": Utc::now().to_rfc3339(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    // Embedders and tests may call into the library more than once
+    let _ = builder.try_init();
+    for warning in config.warnings() {
+        warn!("{}", warning);
+    }
+}
+
+fn build_processor(config: &EngineConfig) -> WorkflowEngineProcessor {
+    WorkflowEngineProcessor::from_config(config)
+        .with_event_sink(Arc::new(RunStore::new(&config.state_dir)))
+}
+
+/// How often commands that keep running rewrite the metrics file
+const METRICS_INTERVAL: Duration = Duration::from_secs(15);
+
+// Writes `metrics` to the configured metrics file, if there is one. It is
+// written next to it and renamed, so a scraper never reads half a file.
+fn write_metrics(config: &EngineConfig, metrics: &Metrics) -> Result<()> {
+    let Some(path) = &config.metrics_file else {
+        return Ok(());
+    };
+    let mut temporary = path.clone().into_os_string();
+    temporary.push(".tmp");
+    debug!("Writing metrics to: {}", path.display());
+    fs::write(&temporary, metrics.render())
+        .and_then(|_| fs::rename(&temporary, path))
+        .map_err(|e| WorkflowError::from(e).with_path(path))
+}
+
+// Runs `command`, rewriting the metrics file every METRICS_INTERVAL while it
+// runs and once more when it returns
+fn export_metrics(config: &EngineConfig, metrics: &Arc<Metrics>, command: impl FnOnce() -> Result<()>) -> Result<()> {
+    if config.metrics_file.is_none() {
+        return command();
+    }
+    let stop = CancellationToken::new();
+    let exporter = {
+        let (config, metrics, stop) = (config.clone(), Arc::clone(metrics), stop.clone());
+        thread::spawn(move || {
+            while stop.sleep(METRICS_INTERVAL) {
+                if let Err(e) = write_metrics(&config, &metrics) {
+                    warn!("Could not write metrics: {}", e.full_message());
+                }
+            }
+        })
+    };
+    let result = command();
+    stop.cancel();
+    let _ = exporter.join();
+    result.and(write_metrics(config, metrics))
+}
+
+fn write_output(output: Option<String>, json: &str) -> Result<()> {
     match output {
         Some(path) => {
             info!("Writing results to: {}", path);
-            fs::write(&path, &output_json)?;
+            fs::write(&path, json).map_err(|e| WorkflowError::from(e).with_path(&path))?;
         },
         None => {
-            println!("{}", output_json);
+            println!("{}", json);
         }
     }
-    
-    let stats = processor.get_stats();
-    info!("Processing complete. Stats: {}", stats);
-    
     Ok(())
 }
 
+/// Reads and validates a workflow definition file
+///
+/// The format is picked from the extension: `.yaml`/`.yml`, `.toml`, otherwise JSON.
+pub fn load_workflow(path: &Path) -> Result<Workflow> {
+    let text = fs::read_to_string(path).map_err(|e| WorkflowError::from(e).with_path(path))?;
+    Workflow::parse(&text, WorkflowFormat::from_path(path)).map_err(|e| e.with_path(path))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -119,23 +1382,539 @@ mod tests {
     #[test]
     fn test_processor_creation() {
         let processor = WorkflowEngineProcessor::new(true);
-        assert_eq!(processor.verbose, true);
-        assert_eq!(processor.processed_count, 0);
+        assert!(processor.verbose);
+        assert_eq!(processor.processed_count(), 0);
     }
 
     #[test]
     fn test_data_processing() {
-        let mut processor = WorkflowEngineProcessor::new(false);
+        let processor = WorkflowEngineProcessor::new(false);
         let result = processor.process("test data").unwrap();
         
         assert!(result.success);
-        assert_eq!(processor.processed_count, 1);
+        assert_eq!(processor.processed_count(), 1);
+    }
+
+    #[test]
+    fn test_run_workflow_in_dependency_order() {
+        let workflow = Workflow::from_json(r#"{"name": "demo", "steps": [
+            {"id": "report", "depends_on": ["fetch"], "outputs": ["item_number"]},
+            {"id": "fetch", "inputs": {"count": 3}, "outputs": ["count"]}
+        ]}"#).unwrap();
+        let processor = WorkflowEngineProcessor::new(false);
+        let result = processor.run_workflow(&workflow).unwrap();
+
+        assert!(result.success);
+        let ids: Vec<&str> = result.steps.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(ids, vec!["fetch", "report"]);
+        let fetched = result.step("fetch").unwrap().result.as_ref().unwrap();
+        assert_eq!(fetched.data.as_ref().unwrap()["count"], 3);
+        assert_eq!(processor.processed_count(), 2);
+    }
+
+    #[test]
+    fn test_when_conditions_and_interpolation() {
+        let workflow = Workflow::from_json(r#"{"name": "demo", "inputs": {"limit": 2}, "steps": [
+            {"id": "fetch", "inputs": {"count": 3, "region": "eu"}},
+            {"id": "big", "depends_on": ["fetch"], "when": "${{ steps.fetch.output.count > inputs.limit }}",
+             "inputs": {"double": "${{ steps.fetch.output.count * 2 }}", "label": "region ${{ steps.fetch.output.region }}"}},
+            {"id": "small", "depends_on": ["fetch"], "when": "steps.fetch.output.count <= inputs.limit"},
+            {"id": "after-small", "depends_on": ["small"]},
+            {"id": "broken", "depends_on": ["fetch"], "when": "steps.fetch.output.region > 1"}
+        ]}"#).unwrap();
+        let result = WorkflowEngineProcessor::new(false).run_workflow(&workflow).unwrap();
+
+        let data = result.step("big").unwrap().result.as_ref().unwrap().data.clone().unwrap();
+        assert_eq!(data["double"], 6);
+        assert_eq!(data["label"], "region eu");
+        assert_eq!(result.step("small").unwrap().status, StepStatus::Skipped);
+        assert_eq!(result.step("after-small").unwrap().status, StepStatus::Skipped);
+        let broken = result.step("broken").unwrap();
+        assert_eq!(broken.status, StepStatus::Failed);
+        assert!(broken.result.as_ref().unwrap().message.contains("cannot compare a string with a number"));
+        assert!(!result.success);
+    }
+
+    #[test]
+    fn test_false_condition_does_not_fail_the_run() {
+        let workflow = Workflow::from_json(r#"{"name": "demo", "steps": [
+            {"id": "a"}, {"id": "b", "depends_on": ["a"], "when": "steps.a.success == false"}
+        ]}"#).unwrap();
+        let result = WorkflowEngineProcessor::new(false).run_workflow(&workflow).unwrap();
+        assert!(result.success);
+        assert_eq!(result.status, RunStatus::Succeeded);
+        assert_eq!(result.step("b").unwrap().status, StepStatus::Skipped);
+    }
+
+    #[test]
+    fn test_if_and_switch_run_one_branch() {
+        let workflow = Workflow::from_json(r#"{"name": "decide", "steps": [
+            {"id": "fetch", "inputs": {"count": 12, "format": "csv"}},
+            {"id": "size", "kind": "if", "depends_on": ["fetch"], "condition": "steps.fetch.output.count > 10",
+             "then": [{"id": "big"}, {"id": "big-report", "depends_on": ["big"], "inputs": {"n": "${{ steps.big.success }}"}}],
+             "else": [{"id": "small"}]},
+            {"id": "route", "kind": "switch", "depends_on": ["fetch"], "value": "${{ steps.fetch.output.format }}",
+             "cases": {"csv": [{"id": "load-csv"}], "json": [{"id": "load-json"}]},
+             "default": [{"id": "unknown-format"}]},
+            {"id": "report", "depends_on": ["size", "route"], "when": "steps.big-report.success"}
+        ]}"#).unwrap();
+        let result = WorkflowEngineProcessor::new(false).run_workflow(&workflow).unwrap();
+
+        assert!(result.success, "{:?}", result.steps);
+        let status = |id: &str| result.step(id).unwrap().status;
+        for id in ["fetch", "size", "big", "big-report", "route", "load-csv", "report"] {
+            assert_eq!(status(id), StepStatus::Succeeded, "{}", id);
+        }
+        for id in ["small", "load-json", "unknown-format"] {
+            assert_eq!(status(id), StepStatus::Skipped, "{}", id);
+        }
+        let size = result.step("size").unwrap().result.as_ref().unwrap();
+        assert_eq!(size.data.as_ref().unwrap()["branch"], "then");
+        let big_report = result.step("big-report").unwrap().result.as_ref().unwrap();
+        assert_eq!(big_report.data.as_ref().unwrap()["n"], true);
+        let order: Vec<&str> = result.steps.iter().map(|step| step.id.as_str()).collect();
+        assert!(order.iter().position(|id| *id == "big").unwrap() < order.iter().position(|id| *id == "size").unwrap());
+    }
+
+    #[test]
+    fn test_failed_branch_fails_control_step_and_triggers_apply() {
+        let workflow = Workflow::from_json(r#"{"name": "decide", "steps": [
+            {"id": "check", "kind": "if", "condition": "true",
+             "then": [{"id": "broken", "outputs": ["missing"]}]},
+            {"id": "other"},
+            {"id": "after", "depends_on": ["check"]},
+            {"id": "join", "depends_on": ["check", "other"], "trigger": "any_success"},
+            {"id": "cleanup", "depends_on": ["after"], "trigger": "always"}
+        ]}"#).unwrap();
+        let result = WorkflowEngineProcessor::new(false).run_workflow(&workflow).unwrap();
+
+        assert!(!result.success);
+        let status = |id: &str| result.step(id).unwrap().status;
+        assert_eq!(status("broken"), StepStatus::Failed);
+        assert_eq!(status("check"), StepStatus::Failed);
+        assert_eq!(status("after"), StepStatus::Skipped);
+        assert_eq!(status("join"), StepStatus::Succeeded);
+        assert_eq!(status("cleanup"), StepStatus::Succeeded);
+    }
+
+    #[test]
+    fn test_map_runs_template_per_item_in_order() {
+        let workflow = Workflow::from_json(r#"{"name": "batch", "steps": [
+            {"id": "list", "inputs": {"files": ["a.csv", "b.csv", "c.csv", "d.csv"]}},
+            {"id": "convert", "kind": "map", "depends_on": ["list"], "items": "${{ steps.list.output.files }}",
+             "concurrency": 2, "steps": [
+                {"id": "load", "inputs": {"file": "${{ item }}", "position": "${{ index + 1 }}"}},
+                {"id": "store", "depends_on": ["load"], "inputs": {"name": "out-${{ steps.load.output.file }}"}}
+             ]},
+            {"id": "report", "depends_on": ["convert"], "inputs": {"total": "${{ steps.convert.output.succeeded }}"}}
+        ]}"#).unwrap();
+        let result = WorkflowEngineProcessor::new(false).run_workflow(&workflow).unwrap();
+
+        assert!(result.success, "{:?}", result.steps);
+        let data = result.step("convert").unwrap().result.as_ref().unwrap().data.clone().unwrap();
+        assert_eq!(data["count"], 4);
+        let results = data["results"].as_array().unwrap();
+        for (index, file) in ["a.csv", "b.csv", "c.csv", "d.csv"].iter().enumerate() {
+            assert_eq!(results[index]["item"], *file);
+            assert_eq!(results[index]["status"], "succeeded");
+            assert_eq!(results[index]["output"]["load"]["position"], index + 1);
+            assert_eq!(results[index]["output"]["store"]["name"], format!("out-{}", file));
+        }
+        assert!(result.step("load").is_none());
+        let report = result.step("report").unwrap().result.as_ref().unwrap();
+        assert_eq!(report.data.as_ref().unwrap()["total"], 4);
+    }
+
+    #[test]
+    fn test_map_fail_fast_and_collect_errors() {
+        let definition = |mode: &str| format!(r#"{{"name": "batch", "steps": [
+            {{"id": "list", "inputs": {{"numbers": [1, 2, 3, 4, 5, 6]}}}},
+            {{"id": "check", "kind": "map", "depends_on": ["list"], "items": "steps.list.output.numbers",
+              "concurrency": 1, "mode": "{}", "steps": [
+                {{"id": "even", "when": "item % 2 == 0", "outputs": ["missing"]}}
+              ]}}
+        ]}}"#, mode);
+
+        let workflow = Workflow::from_json(&definition("collect_errors")).unwrap();
+        let result = WorkflowEngineProcessor::new(false).run_workflow(&workflow).unwrap();
+        let check = result.step("check").unwrap();
+        assert_eq!(check.status, StepStatus::Failed);
+        let data = check.result.as_ref().unwrap().data.clone().unwrap();
+        assert_eq!((data["succeeded"].clone(), data["failed"].clone()), (3.into(), 3.into()));
+        assert!(data["results"][1]["error"].as_str().unwrap().contains("missing"));
+
+        let workflow = Workflow::from_json(&definition("fail_fast")).unwrap();
+        let result = WorkflowEngineProcessor::new(false).run_workflow(&workflow).unwrap();
+        let data = result.step("check").unwrap().result.as_ref().unwrap().data.clone().unwrap();
+        let statuses: Vec<&str> = data["results"].as_array().unwrap().iter().map(|r| r["status"].as_str().unwrap()).collect();
+        assert_eq!(statuses, vec!["succeeded", "failed", "cancelled", "cancelled", "cancelled", "cancelled"]);
+    }
+
+    #[test]
+    fn test_loop_repeats_until_condition_holds() {
+        let workflow = Workflow::from_json(r#"{"name": "poll", "steps": [
+            {"id": "wait", "kind": "loop", "until": "steps.check.output.n >= 3", "steps": [
+                {"id": "check", "inputs": {"n": "${{ iteration }}"}}
+            ]},
+            {"id": "after", "depends_on": ["wait"], "inputs": {"n": "${{ steps.wait.output.last.check.n }}"}}
+        ]}"#).unwrap();
+        let result = WorkflowEngineProcessor::new(false).run_workflow(&workflow).unwrap();
+        assert!(result.success);
+        let data = result.step("wait").unwrap().result.as_ref().unwrap().data.clone().unwrap();
+        assert_eq!(data["iterations"], 3);
+        assert_eq!(data["results"][0]["output"]["check"]["n"], 1);
+        assert_eq!(result.step("after").unwrap().result.as_ref().unwrap().data.as_ref().unwrap()["n"], 3);
+        assert!(result.step("check").is_none());
+
+        let workflow = Workflow::from_json(r#"{"name": "poll", "steps": [
+            {"id": "wait", "kind": "loop", "while": "true", "max_iterations": 2, "steps": [{"id": "check"}]}
+        ]}"#).unwrap();
+        let result = WorkflowEngineProcessor::new(false).run_workflow(&workflow).unwrap();
+        let wait = result.step("wait").unwrap();
+        assert_eq!(wait.status, StepStatus::Failed);
+        assert!(wait.result.as_ref().unwrap().message.contains("did not finish after 2 iterations"));
+    }
+
+    #[test]
+    fn test_concurrent_child_runs_get_their_own_journals() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(RunStore::new(dir.path()));
+        let notify = Workflow::from_json(r#"{"name": "notify", "steps": [{"id": "send"}]}"#).unwrap();
+        let processor = WorkflowEngineProcessor::new(false).with_workflow(notify).with_event_sink(store.clone());
+        let items: Vec<usize> = (0..200).collect();
+        let workflow = Workflow::from_json(&serde_json::json!({
+            "name": "fan-out",
+            "steps": [
+                {"id": "list", "inputs": {"items": items}},
+                {"id": "each", "kind": "map", "depends_on": ["list"], "items": "steps.list.output.items",
+                 "concurrency": 16, "steps": [{"id": "call", "kind": "workflow", "name": "notify"}]}
+            ]
+        }).to_string()).unwrap();
+        let result = processor.run_workflow(&workflow).unwrap();
+
+        assert!(result.success, "{:?}", result.steps);
+        let runs = store.list().unwrap();
+        assert_eq!(runs.len(), 201);
+        assert!(runs.iter().all(|run| run.status == Some(RunStatus::Succeeded)));
+    }
+
+    #[test]
+    fn test_sub_workflow_runs_as_linked_child_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let notify = Workflow::from_json(r#"{"name": "notify", "inputs": {"channel": "ops", "count": 0}, "steps": [
+            {"id": "send", "inputs": {"to": "${{ inputs.channel }}", "count": "${{ inputs.count }}"}}
+        ]}"#).unwrap();
+        fs::write(dir.path().join("backup.json"), r#"{"name": "backup", "steps": [{"id": "copy", "outputs": ["missing"]}]}"#).unwrap();
+        let store = Arc::new(RunStore::new(dir.path().join("runs")));
+        let processor = WorkflowEngineProcessor::new(false)
+            .with_workflow(notify)
+            .with_library(dir.path())
+            .with_event_sink(store.clone());
+
+        let workflow = Workflow::from_json(r#"{"name": "release", "steps": [
+            {"id": "announce", "kind": "workflow", "name": "notify", "inputs": {"channel": "releases", "count": 2}},
+            {"id": "after", "depends_on": ["announce"], "inputs": {"to": "${{ steps.announce.output.outputs.send.to }}"}},
+            {"id": "archive", "kind": "workflow", "name": "backup"}
+        ]}"#).unwrap();
+        let result = processor.run_workflow(&workflow).unwrap();
+
+        let announce = result.step("announce").unwrap().result.clone().unwrap();
+        let data = announce.data.unwrap();
+        assert_eq!(data["outputs"]["send"]["count"], 2);
+        assert_eq!(result.step("after").unwrap().result.as_ref().unwrap().data.as_ref().unwrap()["to"], "releases");
+        let child = store.load(data["run_id"].as_str().unwrap()).unwrap();
+        assert_eq!(child.workflow.name, "notify");
+        assert_eq!(child.parent, Some(ParentRun { run_id: result.run_id.clone(), step: "announce".to_string() }));
+        assert_eq!(child.status, Some(RunStatus::Succeeded));
+
+        let archive = result.step("archive").unwrap();
+        assert_eq!(archive.status, StepStatus::Failed);
+        assert!(archive.result.as_ref().unwrap().message.contains("workflow 'backup'"));
+        assert!(!result.success);
+
+        let failure = |json: &str| {
+            let result = processor.run_workflow(&Workflow::from_json(json).unwrap()).unwrap();
+            result.steps[0].result.as_ref().unwrap().message.clone()
+        };
+        let message = failure(r#"{"name": "a", "steps": [{"id": "s", "kind": "workflow", "name": "notify", "inputs": {"count": "two"}}]}"#);
+        assert!(message.contains("input 'count': must be number, got string"), "{}", message);
+        let message = failure(r#"{"name": "a", "steps": [{"id": "s", "kind": "workflow", "name": "notify", "inputs": {"level": 1}}]}"#);
+        assert!(message.contains("input 'level': workflow 'notify' has no such input"), "{}", message);
+        let message = failure(r#"{"name": "a", "steps": [{"id": "s", "kind": "workflow", "name": "deploy"}]}"#);
+        assert!(message.contains("no workflow named 'deploy'"), "{}", message);
+
+        let looping = dir.path().join("looping.json");
+        let definition = format!(r#"{{"name": "looping", "steps": [{{"id": "again", "kind": "workflow", "path": {:?}}}]}}"#, looping);
+        fs::write(&looping, &definition).unwrap();
+        let result = processor.run_workflow(&Workflow::from_json(&definition).unwrap()).unwrap();
+        let message = &result.steps[0].result.as_ref().unwrap().message;
+        assert!(message.contains("calls itself: looping -> looping"), "{}", message);
+    }
+
+    #[test]
+    fn test_inputs_are_checked_and_outputs_evaluated() {
+        let workflow = Workflow::from_json(r#"{"name": "deploy",
+            "input_schema": {"type": "object", "required": ["environment"], "properties": {
+                "environment": {"type": "string", "enum": ["staging", "production"]},
+                "replicas": {"type": "integer", "minimum": 1, "default": 2}
+            }},
+            "outputs": {"target": "${{ steps.roll.output.env }}", "replicas": "steps.roll.output.count"},
+            "steps": [{"id": "roll", "inputs": {"env": "${{ inputs.environment }}", "count": "${{ inputs.replicas }}"}}]
+        }"#).unwrap();
+        let processor = WorkflowEngineProcessor::new(false);
+        let token = CancellationToken::new();
+
+        let inputs = serde_json::json!({"environment": "production"});
+        let result = processor.run_workflow_with_inputs(&workflow, inputs.as_object().unwrap(), &token).unwrap();
+        assert!(result.success);
+        assert_eq!(serde_json::Value::Object(result.outputs), serde_json::json!({"target": "production", "replicas": 2}));
+
+        let error = processor.run_workflow(&workflow).unwrap_err();
+        assert_eq!(error.exit_code(), 4);
+        assert_eq!(error.full_message(), "invalid workflow: input 'environment': is required");
+        let inputs = serde_json::json!({"environment": "production", "replicas": 0});
+        let error = processor.run_workflow_with_inputs(&workflow, inputs.as_object().unwrap(), &token).unwrap_err();
+        assert!(error.full_message().contains("input 'replicas': must be at least 1"), "{}", error.full_message());
+        assert_eq!(processor.processed_count(), 1);
+    }
+
+    #[test]
+    fn test_failed_step_skips_dependents() {
+        let workflow = Workflow::from_json(r#"{"name": "demo", "steps": [
+            {"id": "broken", "outputs": ["missing"]},
+            {"id": "after", "depends_on": ["broken"]},
+            {"id": "independent"}
+        ]}"#).unwrap();
+        let processor = WorkflowEngineProcessor::new(false);
+        let result = processor.run_workflow(&workflow).unwrap();
+
+        assert!(!result.success);
+        assert_eq!(result.step("broken").unwrap().status, StepStatus::Failed);
+        assert_eq!(result.step("after").unwrap().status, StepStatus::Skipped);
+        assert_eq!(result.step("independent").unwrap().status, StepStatus::Succeeded);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_workers_bound_nested_steps() {
+        let dir = tempfile::tempdir().unwrap();
+        // Every step counts the steps running next to it, itself included
+        let work = |id: &str| {
+            serde_json::json!({
+                "id": id,
+                "kind": "command",
+                "program": "sh",
+                "args": ["-c", "touch running.$$; ls running.* 2>/dev/null | wc -l >> peaks; sleep 0.05; rm running.$$"],
+                "working_dir": dir.path()
+            })
+        };
+        let map = |id: &str| {
+            let items = "steps.list.output.items";
+            serde_json::json!({"id": id, "kind": "map", "items": items, "steps": [work(&format!("{}-work", id))]})
+        };
+        let workflow = Workflow::from_json(&serde_json::json!({
+            "name": "nested",
+            "steps": [
+                {"id": "list", "inputs": {"items": [1, 2, 3, 4]}},
+                {"id": "fan", "kind": "if", "depends_on": ["list"], "condition": "true", "then": [map("left"), map("right")]},
+                {"id": "branch", "kind": "if", "depends_on": ["list"], "condition": "true", "then": [
+                    map("inner"),
+                    {"id": "again", "kind": "loop", "until": "iteration >= 2", "steps": [work("again-work")]}
+                ]}
+            ]
+        }).to_string()).unwrap();
+        let processor = WorkflowEngineProcessor::new(false).with_workers(2);
+        let result = processor.run_workflow(&workflow).unwrap();
+
+        assert!(result.success, "{:?}", result.steps);
+        let peaks = fs::read_to_string(dir.path().join("peaks")).unwrap();
+        let counts: Vec<usize> = peaks.lines().map(|line| line.trim().parse().unwrap()).collect();
+        assert_eq!(counts.len(), 14);
+        assert!(counts.iter().all(|&count| count <= 2), "{:?}", counts);
+        assert_eq!(processor.metrics().busy_workers(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_flaky_step_is_retried_with_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let workflow = Workflow::from_json(&serde_json::json!({
+            "name": "flaky",
+            "steps": [{
+                "id": "fetch",
+                "kind": "command",
+                "program": "sh",
+                "args": ["-c", "n=$(cat count 2>/dev/null || echo 0); n=$((n+1)); echo $n > count; [ $n -ge 3 ]"],
+                "working_dir": dir.path(),
+                "retry": {"max_attempts": 5, "initial_delay_ms": 1, "max_delay_ms": 5}
+            }]
+        }).to_string()).unwrap();
+        let processor = WorkflowEngineProcessor::new(false);
+        let result = processor.run_workflow(&workflow).unwrap();
+
+        assert!(result.success);
+        let fetch = result.step("fetch").unwrap();
+        assert_eq!(fetch.attempts.len(), 3);
+        assert!(!fetch.attempts[0].result.success);
+        assert_eq!(fetch.attempts[0].result.data.as_ref().unwrap()["exit_code"], 1);
+        assert!(fetch.attempts[2].result.success);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_step_timeout_marks_result() {
+        let workflow = Workflow::from_json(r#"{"name": "slow", "steps": [
+            {"id": "sleepy", "kind": "command", "program": "sleep", "args": ["10"], "timeout_ms": 50},
+            {"id": "after", "depends_on": ["sleepy"]},
+            {"id": "quick"}
+        ]}"#).unwrap();
+        let result = WorkflowEngineProcessor::new(false).run_workflow(&workflow).unwrap();
+
+        assert_eq!(result.status, RunStatus::Failed);
+        let sleepy = result.step("sleepy").unwrap();
+        assert_eq!(sleepy.status, StepStatus::TimedOut);
+        assert!(sleepy.result.as_ref().unwrap().timed_out);
+        assert_eq!(result.step("after").unwrap().status, StepStatus::Skipped);
+        assert_eq!(result.step("quick").unwrap().status, StepStatus::Succeeded);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cancellation_keeps_partial_results() {
+        let workflow = Workflow::from_json(r#"{"name": "long", "steps": [
+            {"id": "first"},
+            {"id": "wait", "kind": "command", "program": "sleep", "args": ["10"], "depends_on": ["first"]},
+            {"id": "last", "depends_on": ["wait"]}
+        ]}"#).unwrap();
+        let token = CancellationToken::new();
+        let canceller = {
+            let token = token.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                token.cancel();
+            })
+        };
+        let result = WorkflowEngineProcessor::new(false)
+            .run_workflow_cancellable(&workflow, &token)
+            .unwrap();
+        canceller.join().unwrap();
+
+        assert_eq!(result.status, RunStatus::Cancelled);
+        assert_eq!(result.step("first").unwrap().status, StepStatus::Succeeded);
+        assert_eq!(result.step("wait").unwrap().status, StepStatus::Cancelled);
+        assert_eq!(result.step("last").unwrap().status, StepStatus::Skipped);
+    }
+
+    #[test]
+    fn test_workflow_timeout_cancels_pending_steps() {
+        let workflow = Workflow::from_json(r#"{"name": "expired", "timeout_ms": 0, "steps": [
+            {"id": "a"}, {"id": "b", "depends_on": ["a"]}
+        ]}"#).unwrap();
+        let result = WorkflowEngineProcessor::new(false).run_workflow(&workflow).unwrap();
+
+        assert_eq!(result.status, RunStatus::TimedOut);
+        assert!(matches!(result.error(), Some(WorkflowError::Timeout { .. })));
+        assert_eq!(result.step("a").unwrap().status, StepStatus::Cancelled);
+        assert_eq!(result.step("b").unwrap().status, StepStatus::Cancelled);
+    }
+
+    #[test]
+    fn test_stats_count_parallel_steps() {
+        let steps: Vec<String> = (0..20).map(|i| format!(r#"{{"id": "s{}"}}"#, i)).collect();
+        let json = format!(r#"{{"name": "wide", "steps": [{}]}}"#, steps.join(","));
+        let workflow = Workflow::from_json(&json).unwrap();
+        let processor = WorkflowEngineProcessor::new(false).with_workers(8);
+        let result = processor.run_workflow(&workflow).unwrap();
+
+        assert!(result.success);
+        assert_eq!(processor.processed_count(), 20);
+        let mut items: Vec<u64> = result.steps.iter()
+            .map(|s| s.result.as_ref().unwrap().data.as_ref().unwrap()["item_number"].as_u64().unwrap())
+            .collect();
+        items.sort();
+        assert_eq!(items, (1..=20).collect::<Vec<u64>>());
+        assert_eq!(processor.get_stats()["steps_succeeded"], 20);
     }
 
     #[test]
     fn test_run_function() {
-        // Test the main run function
-        let result = run(false, None, None);
-        assert!(result.is_ok());
+        // Without a workflow there is nothing to run; no sample data is made up
+        let error = run(false, None, None, None).unwrap_err();
+        assert_eq!(error.exit_code(), 2);
+        assert!(error.to_string().contains("no workflow given"), "{}", error);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resume_skips_completed_steps() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ready");
+        let json = format!(
+            r#"{{"name": "resumable", "steps": [
+                {{"id": "a"}},
+                {{"id": "b", "kind": "command", "program": "test", "args": ["-e", "{}"], "depends_on": ["a"]}},
+                {{"id": "c", "depends_on": ["b"]}}
+            ]}}"#,
+            marker.display()
+        );
+        let workflow = Workflow::from_json(&json).unwrap();
+        let store = Arc::new(RunStore::new(dir.path().join("runs")));
+
+        let processor = WorkflowEngineProcessor::new(false).with_event_sink(store.clone());
+        let first = processor.run_workflow(&workflow).unwrap();
+        assert_eq!(first.status, RunStatus::Failed);
+        assert_eq!(first.error().unwrap().step_id(), Some("b"));
+        assert_eq!(first.step("c").unwrap().status, StepStatus::Skipped);
+
+        let state = store.load(&first.run_id).unwrap();
+        assert_eq!(state.status, Some(RunStatus::Failed));
+        fs::write(&marker, "").unwrap();
+
+        let processor = WorkflowEngineProcessor::new(false).with_event_sink(store.clone());
+        let second = processor.resume_run(&state, &CancellationToken::new()).unwrap();
+        assert_eq!(second.run_id, first.run_id);
+        assert_eq!(second.status, RunStatus::Succeeded);
+        assert_eq!(second.steps.len(), 3);
+        // Only c went through the processor again; a kept its first result
+        assert_eq!(processor.processed_count(), 1);
+
+        let state = store.load(&first.run_id).unwrap();
+        assert_eq!(state.status, Some(RunStatus::Succeeded));
+        let error = processor.resume_run(&state, &CancellationToken::new()).unwrap_err();
+        assert!(matches!(error, WorkflowError::Persistence { .. }));
+    }
+
+    #[test]
+    fn test_validate_returns_error_of_invalid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let valid = dir.path().join("valid.json");
+        let invalid = dir.path().join("invalid.yaml");
+        fs::write(&valid, r#"{"name": "ok", "steps": [{"id": "a"}]}"#).unwrap();
+        fs::write(&invalid, "name: broken\nsteps:\n  - id: a\n    depends_on: [a]\n").unwrap();
+        let path = |p: &Path| p.display().to_string();
+
+        let config = EngineConfig::default();
+        assert!(validate(&config, &[path(&valid)]).is_ok());
+        let error = validate(&config, &[path(&valid), path(&invalid)]).unwrap_err();
+        assert!(matches!(error, WorkflowError::Validation { .. }));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_default_timeout_applies_to_steps_without_one() {
+        let workflow = Workflow::from_json(
+            r#"{"name": "defaults", "steps": [
+                {"id": "slow", "kind": "command", "program": "sleep", "args": ["5"]},
+                {"id": "patient", "kind": "command", "program": "sleep", "args": ["0.2"], "timeout_ms": 5000}
+            ]}"#,
+        ).unwrap();
+        let processor = WorkflowEngineProcessor::new(false).with_default_timeout_ms(Some(50));
+        let result = processor.run_workflow(&workflow).unwrap();
+
+        assert_eq!(result.step("slow").unwrap().status, StepStatus::TimedOut);
+        assert_eq!(result.step("patient").unwrap().status, StepStatus::Succeeded);
+        let error = result.error().unwrap();
+        assert!(matches!(error, WorkflowError::Timeout { .. }));
+        assert_eq!(error.step_id(), Some("slow"));
     }
 }
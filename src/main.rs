@@ -3,26 +3,216 @@
  * Main executable for WorkflowEngine
  */
 
-use clap::Parser;
-use workflowengine::{Result, run};
+use clap::{Parser, Subcommand};
+use log::warn;
+use std::path::PathBuf;
+use std::process;
+use workflowengine::config::config_file;
+use workflowengine::inputs::parse_param;
+use workflowengine::{
+    CancellationToken, ConfigLoader, EngineConfig, GraphFormat, LogFormat, OutputFormat, Params, Result, graph,
+    list_runs, resume, run_cancellable, run_schedule, run_watch, serve, show_config, status, validate,
+};
 
 #[derive(Parser)]
 #[command(version, about = "WorkflowEngine - A Rust implementation")]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+    
     /// Enable verbose output
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     verbose: bool,
     
-    /// Input file path
+    /// Workflow definition file to run when no subcommand is given (same as `run <FILE>`)
     #[arg(short, long)]
     input: Option<String>,
     
     /// Output file path
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     output: Option<String>,
+    
+    /// Result format: json, ndjson, csv, xml or table [default: json]
+    #[arg(short, long, global = true)]
+    format: Option<OutputFormat>,
+    
+    /// Log line format: text or json [default: text]
+    #[arg(long, global = true)]
+    log_format: Option<LogFormat>,
+    
+    /// Maximum number of steps to run at the same time (default: number of CPUs)
+    #[arg(short, long, global = true, value_parser = clap::value_parser!(u64).range(1..))]
+    workers: Option<u64>,
+    
+    /// Directory where run journals are kept for resuming [default: .workflowengine/runs]
+    #[arg(long, global = true)]
+    state_dir: Option<String>,
+    
+    /// Directory of workflows that `workflow` steps can call by name; may be repeated
+    #[arg(long, global = true)]
+    library: Vec<String>,
+    
+    /// File to write metrics to in the Prometheus text format, at the end of a run
+    /// or every 15 seconds while schedule, watch or serve keep running
+    #[arg(long, global = true)]
+    metrics_file: Option<PathBuf>,
+    
+    /// Config file [default: $WORKFLOWENGINE_CONFIG, else ./workflowengine.toml if present]
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
 }
 
-fn main() -> Result<()> {
+#[derive(Subcommand)]
+enum Commands {
+    /// Run a workflow
+    Run {
+        /// Workflow definition file (JSON, YAML or TOML)
+        workflow: String,
+        /// Input value as name=value; values are read as JSON unless the input is a string
+        #[arg(short, long = "param", value_name = "NAME=VALUE", value_parser = parse_param)]
+        params: Vec<(String, String)>,
+        /// JSON, YAML or TOML file with input values; --param wins over it
+        #[arg(long)]
+        params_file: Option<PathBuf>,
+    },
+    /// Check workflow files without running them
+    Validate {
+        /// Workflow definition files (JSON, YAML or TOML)
+        #[arg(required = true)]
+        workflows: Vec<String>,
+    },
+    /// Print the dependency graph of a workflow
+    Graph {
+        /// Workflow definition file (JSON, YAML or TOML); defaults to the workflow of --run
+        #[arg(required_unless_present = "run")]
+        workflow: Option<String>,
+        /// Graph format: text, dot or mermaid
+        #[arg(long, default_value_t = GraphFormat::Text)]
+        to: GraphFormat,
+        /// Id of a run whose step statuses and durations are drawn on the graph
+        #[arg(long)]
+        run: Option<String>,
+    },
+    /// Show the state of a run
+    Status {
+        /// Id of the run, as printed when it started
+        run_id: String,
+    },
+    /// List past and unfinished runs, most recent first
+    List,
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// Continue an interrupted or failed run, skipping steps that already succeeded
+    Resume {
+        /// Id of the run, as printed when it started
+        run_id: String,
+    },
+    /// Keep running and start workflows at the times their `schedule` gives
+    Schedule {
+        /// Workflow definition files, or directories of them
+        #[arg(required = true)]
+        workflows: Vec<String>,
+    },
+    /// Serve an HTTP API for submitting, inspecting and cancelling runs
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+        /// Also accept workflow definitions in POST /runs, not just library names
+        #[arg(long)]
+        allow_inline: bool,
+    },
+    /// Keep running and start workflows when the files they `watch` appear or change
+    Watch {
+        /// Workflow definition files, or directories of them
+        #[arg(required = true)]
+        workflows: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Print the effective settings and where each one came from
+    Show,
+}
+
+fn main() {
     let args = Cli::parse();
-    run(args.verbose, args.input, args.output)
+    
+    // First Ctrl-C stops the run gracefully, a second one exits immediately
+    let token = CancellationToken::new();
+    let handler_token = token.clone();
+    let handler = ctrlc::set_handler(move || {
+        if handler_token.is_cancelled() {
+            process::exit(130);
+        }
+        warn!("Interrupted, cancelling run (press Ctrl-C again to exit immediately)");
+        handler_token.cancel();
+    });
+    if let Err(e) = handler {
+        warn!("Could not install Ctrl-C handler: {}", e);
+    }
+    
+    if let Err(e) = dispatch(args, &token) {
+        for error in e.errors() {
+            eprintln!("Error: {}", error.full_message());
+        }
+        process::exit(e.exit_code());
+    }
+}
+
+fn dispatch(args: Cli, token: &CancellationToken) -> Result<()> {
+    let config = load_config(&args)?;
+    match args.command {
+        Some(Commands::Run { workflow, params, params_file }) => {
+            let params = Params { file: params_file, values: params };
+            run_cancellable(&config, Some(workflow), &params, args.output, token)
+        }
+        Some(Commands::Validate { workflows }) => validate(&config, &workflows),
+        Some(Commands::Graph { workflow, to, run }) => {
+            graph(&config, workflow.as_deref(), to, run.as_deref(), args.output)
+        }
+        Some(Commands::Status { run_id }) => status(&config, &run_id, args.output),
+        Some(Commands::List) => list_runs(&config, args.output),
+        Some(Commands::Resume { run_id }) => resume(&config, &run_id, args.output, token),
+        Some(Commands::Schedule { workflows }) => run_schedule(&config, &workflows, token),
+        Some(Commands::Watch { workflows }) => run_watch(&config, &workflows, token),
+        Some(Commands::Serve { listen, allow_inline }) => serve(&config, &listen, allow_inline, token),
+        Some(Commands::Config { command: ConfigCommands::Show }) => show_config(&config, args.output),
+        None => run_cancellable(&config, args.input, &Params::default(), args.output, token),
+    }
+}
+
+// Defaults, then the config file, then WORKFLOWENGINE_* variables, then flags
+fn load_config(args: &Cli) -> Result<EngineConfig> {
+    let mut loader = ConfigLoader::new();
+    if let Some(file) = config_file(args.config.clone()) {
+        loader = loader.with_file(file)?;
+    }
+    loader = loader.with_env(std::env::vars());
+    if args.verbose {
+        loader = loader.with_override("verbose", true, "--verbose");
+    }
+    if let Some(workers) = args.workers {
+        loader = loader.with_override("workers", workers as i64, "--workers");
+    }
+    if let Some(state_dir) = &args.state_dir {
+        loader = loader.with_override("state_dir", state_dir.as_str(), "--state-dir");
+    }
+    if !args.library.is_empty() {
+        loader = loader.with_override("library", args.library.clone(), "--library");
+    }
+    if let Some(metrics_file) = &args.metrics_file {
+        loader = loader.with_override("metrics_file", metrics_file.display().to_string(), "--metrics-file");
+    }
+    if let Some(format) = args.format {
+        loader = loader.with_override("output_format", format.to_string(), "--format");
+    }
+    if let Some(log_format) = args.log_format {
+        loader = loader.with_override("log_format", log_format.to_string(), "--log-format");
+    }
+    loader.load()
 }
@@ -0,0 +1,222 @@
+// src/metrics.rs
+/*
+ * Run, step and worker metrics in the Prometheus text format
+ */
+
+use crate::{RunStatus, StepStatus};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Upper bounds of the step duration buckets, in seconds
+const DURATION_BUCKETS: [f64; 14] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+
+/// What a processor has done since it was created.
+///
+/// Counters are kept per workflow: runs started and finished by status,
+/// step durations by status, and retries. The gauges tell how many steps
+/// wait for a worker, how many workers are busy and how many the processor
+/// has, counting the steps of every run in progress, nested ones included.
+/// [`Metrics::render`] writes it all in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counters: Mutex<Counters>,
+    queued: AtomicUsize,
+    busy: AtomicUsize,
+    workers: AtomicUsize,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    runs_started: BTreeMap<String, u64>,
+    runs_finished: BTreeMap<(String, String), u64>,
+    step_durations: BTreeMap<(String, String), Histogram>,
+    retries: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn run_started(&self, workflow: &str) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters.runs_started.entry(workflow.to_string()).or_default() += 1;
+    }
+
+    pub(crate) fn run_finished(&self, workflow: &str, status: RunStatus) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters.runs_finished.entry((workflow.to_string(), status.to_string())).or_default() += 1;
+    }
+
+    pub(crate) fn step_finished(&self, workflow: &str, status: StepStatus, duration_ms: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        counters
+            .step_durations
+            .entry((workflow.to_string(), status.to_string()))
+            .or_default()
+            .observe(duration_ms as f64 / 1000.0);
+    }
+
+    pub(crate) fn retry_scheduled(&self, workflow: &str) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters.retries.entry(workflow.to_string()).or_default() += 1;
+    }
+
+    /// The processor runs at most `workers` steps at once
+    pub(crate) fn set_workers(&self, workers: usize) {
+        self.workers.store(workers, Ordering::SeqCst);
+    }
+
+    /// A step starts waiting for a worker
+    pub(crate) fn step_queued(&self) {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// A step stops waiting for a worker
+    pub(crate) fn step_dequeued(&self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// A worker starts running a step or, with `started` false, is done with it
+    pub(crate) fn worker_busy(&self, started: bool) {
+        match started {
+            true => self.busy.fetch_add(1, Ordering::SeqCst),
+            false => self.busy.fetch_sub(1, Ordering::SeqCst),
+        };
+    }
+
+    /// Steps waiting for a worker
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Workers running a step
+    pub fn busy_workers(&self) -> usize {
+        self.busy.load(Ordering::SeqCst)
+    }
+
+    /// The Prometheus text exposition of every metric
+    pub fn render(&self) -> String {
+        let counters = self.counters.lock().unwrap();
+        let mut out = String::new();
+
+        header(&mut out, "runs_started_total", "counter", "Workflow runs started, including resumed runs");
+        for (workflow, count) in &counters.runs_started {
+            let _ = writeln!(out, "workflowengine_runs_started_total{{workflow=\"{}\"}} {}", escape(workflow), count);
+        }
+        header(&mut out, "runs_finished_total", "counter", "Workflow runs finished, by status");
+        for ((workflow, status), count) in &counters.runs_finished {
+            let _ = writeln!(
+                out,
+                "workflowengine_runs_finished_total{{workflow=\"{}\",status=\"{}\"}} {}",
+                escape(workflow),
+                status,
+                count
+            );
+        }
+        header(&mut out, "step_duration_seconds", "histogram", "Time steps took, retries included, by status");
+        for ((workflow, status), histogram) in &counters.step_durations {
+            let labels = format!("workflow=\"{}\",status=\"{}\"", escape(workflow), status);
+            let mut cumulative = 0;
+            for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(out, "workflowengine_step_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, cumulative);
+            }
+            let _ = writeln!(out, "workflowengine_step_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, histogram.count);
+            let _ = writeln!(out, "workflowengine_step_duration_seconds_sum{{{}}} {}", labels, histogram.sum);
+            let _ = writeln!(out, "workflowengine_step_duration_seconds_count{{{}}} {}", labels, histogram.count);
+        }
+        header(&mut out, "step_retries_total", "counter", "Failed step attempts that were retried");
+        for (workflow, count) in &counters.retries {
+            let _ = writeln!(out, "workflowengine_step_retries_total{{workflow=\"{}\"}} {}", escape(workflow), count);
+        }
+
+        let workers = self.workers.load(Ordering::SeqCst);
+        let busy = self.busy_workers();
+        let utilization = if workers == 0 { 0.0 } else { busy as f64 / workers as f64 };
+        header(&mut out, "queue_depth", "gauge", "Steps ready to run and waiting for a worker");
+        let _ = writeln!(out, "workflowengine_queue_depth {}", self.queue_depth());
+        header(&mut out, "workers", "gauge", "Steps the processor runs at once at most");
+        let _ = writeln!(out, "workflowengine_workers {}", workers);
+        header(&mut out, "workers_busy", "gauge", "Workers running a step");
+        let _ = writeln!(out, "workflowengine_workers_busy {}", busy);
+        header(&mut out, "worker_utilization", "gauge", "Share of the workers that are running a step");
+        let _ = writeln!(out, "workflowengine_worker_utilization {}", utilization);
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP workflowengine_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE workflowengine_{} {}", name, kind);
+}
+
+// Label values are quoted, so backslashes, quotes and newlines are escaped
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.run_started("etl");
+        metrics.run_started("etl");
+        metrics.run_finished("etl", RunStatus::Succeeded);
+        metrics.run_finished("etl", RunStatus::Failed);
+        metrics.step_finished("etl", StepStatus::Succeeded, 40);
+        metrics.step_finished("etl", StepStatus::Succeeded, 1500);
+        metrics.retry_scheduled("say \"hi\"");
+        metrics.set_workers(4);
+        metrics.step_queued();
+        metrics.step_queued();
+        metrics.step_dequeued();
+        metrics.worker_busy(true);
+
+        let text = metrics.render();
+        let lines: Vec<&str> = text.lines().filter(|line| !line.starts_with('#')).collect();
+        for expected in [
+            "workflowengine_runs_started_total{workflow=\"etl\"} 2",
+            "workflowengine_runs_finished_total{workflow=\"etl\",status=\"failed\"} 1",
+            "workflowengine_runs_finished_total{workflow=\"etl\",status=\"succeeded\"} 1",
+            "workflowengine_step_duration_seconds_bucket{workflow=\"etl\",status=\"succeeded\",le=\"0.05\"} 1",
+            "workflowengine_step_duration_seconds_bucket{workflow=\"etl\",status=\"succeeded\",le=\"2.5\"} 2",
+            "workflowengine_step_duration_seconds_bucket{workflow=\"etl\",status=\"succeeded\",le=\"+Inf\"} 2",
+            "workflowengine_step_duration_seconds_sum{workflow=\"etl\",status=\"succeeded\"} 1.54",
+            "workflowengine_step_retries_total{workflow=\"say \\\"hi\\\"\"} 1",
+            "workflowengine_queue_depth 1",
+            "workflowengine_workers 4",
+            "workflowengine_workers_busy 1",
+            "workflowengine_worker_utilization 0.25",
+        ] {
+            assert!(lines.contains(&expected), "missing {}", expected);
+        }
+        assert!(text.contains("# TYPE workflowengine_step_duration_seconds histogram\n"));
+
+        metrics.worker_busy(false);
+        assert!(metrics.render().ends_with("workflowengine_worker_utilization 0\n"));
+    }
+}
@@ -0,0 +1,492 @@
+// src/output.rs
+/*
+ * Result serialization: JSON, NDJSON, CSV, XML and plain-text tables
+ */
+
+use crate::{ProcessResult, Result, StepResult, WorkflowResult};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// How results are written by the CLI
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// The whole result as one pretty-printed document
+    #[default]
+    Json,
+    /// One compact JSON object per step
+    Ndjson,
+    /// One row per step, with `data` and the workflow's `outputs` flattened
+    /// into `data.*` and `outputs.*` columns
+    Csv,
+    Xml,
+    /// Aligned columns for reading in a terminal, flattened like CSV
+    Table,
+}
+
+impl OutputFormat {
+    pub const ALL: [OutputFormat; 5] = [
+        OutputFormat::Json,
+        OutputFormat::Ndjson,
+        OutputFormat::Csv,
+        OutputFormat::Xml,
+        OutputFormat::Table,
+    ];
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutputFormat::Json => "json",
+            OutputFormat::Ndjson => "ndjson",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Xml => "xml",
+            OutputFormat::Table => "table",
+        })
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        OutputFormat::ALL
+            .into_iter()
+            .find(|format| format.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown output format '{}' (expected json, ndjson, csv, xml or table)", s))
+    }
+}
+
+/// Renders the result of a workflow run.
+///
+/// Tabular formats and NDJSON get one record per step; every record carries
+/// the run id, workflow name and, when the workflow declares any, its
+/// `outputs`, so rows can be combined across runs.
+pub fn render_workflow(result: &WorkflowResult, format: OutputFormat) -> Result<String> {
+    match format {
+        OutputFormat::Json => Ok(serde_json::to_string_pretty(result)?),
+        OutputFormat::Xml => Ok(workflow_xml(result)),
+        _ => render_records(step_records(result), format),
+    }
+}
+
+/// Renders a single [`ProcessResult`], as one record
+pub fn render_process(result: &ProcessResult, format: OutputFormat) -> Result<String> {
+    match format {
+        OutputFormat::Json => Ok(serde_json::to_string_pretty(result)?),
+        OutputFormat::Xml => {
+            let mut out = String::from(XML_DECLARATION);
+            out.push_str(&format!("<process_result success=\"{}\">\n", result.success));
+            process_xml(&mut out, result, 1);
+            out.push_str("</process_result>");
+            Ok(out)
+        }
+        _ => render_records(vec![process_record(result)], format),
+    }
+}
+
+fn render_records(records: Vec<Map<String, Value>>, format: OutputFormat) -> Result<String> {
+    if format == OutputFormat::Ndjson {
+        let lines: serde_json::Result<Vec<String>> = records.iter().map(serde_json::to_string).collect();
+        return Ok(lines?.join("\n"));
+    }
+
+    let flat: Vec<Map<String, Value>> = records.into_iter().map(flatten_record).collect();
+    let columns = columns(&flat);
+    let rows: Vec<Vec<String>> = flat
+        .iter()
+        .map(|record| columns.iter().map(|column| cell(record.get(column))).collect())
+        .collect();
+    let headers: Vec<&str> = columns.iter().map(String::as_str).collect();
+    Ok(match format {
+        OutputFormat::Csv => render_csv(&headers, &rows),
+        _ => render_table(&headers, &rows),
+    })
+}
+
+fn step_records(result: &WorkflowResult) -> Vec<Map<String, Value>> {
+    result.steps.iter().map(|step| step_record(result, step)).collect()
+}
+
+fn step_record(run: &WorkflowResult, step: &StepResult) -> Map<String, Value> {
+    let mut record = Map::new();
+    record.insert("run_id".to_string(), Value::from(run.run_id.as_str()));
+    record.insert("workflow".to_string(), Value::from(run.workflow.as_str()));
+    record.insert("step".to_string(), Value::from(step.id.as_str()));
+    record.insert("status".to_string(), Value::from(step.status.to_string()));
+    record.insert("started_at".to_string(), step.started_at.map(|at| Value::from(at.to_rfc3339())).unwrap_or(Value::Null));
+    record.insert("duration_ms".to_string(), step.duration_ms.map(Value::from).unwrap_or(Value::Null));
+    record.insert("attempts".to_string(), Value::from(step.attempts.len()));
+    match &step.result {
+        Some(result) => record.extend(process_record(result)),
+        None => {
+            record.insert("success".to_string(), Value::from(false));
+            record.insert("timed_out".to_string(), Value::from(false));
+            record.insert("message".to_string(), Value::Null);
+            record.insert("data".to_string(), Value::Null);
+        }
+    }
+    if !run.outputs.is_empty() {
+        record.insert("outputs".to_string(), Value::Object(run.outputs.clone()));
+    }
+    record
+}
+
+fn process_record(result: &ProcessResult) -> Map<String, Value> {
+    let mut record = Map::new();
+    record.insert("success".to_string(), Value::from(result.success));
+    record.insert("timed_out".to_string(), Value::from(result.timed_out));
+    record.insert("message".to_string(), Value::from(result.message.as_str()));
+    record.insert("data".to_string(), result.data.clone().unwrap_or(Value::Null));
+    record
+}
+
+// Replaces `data` and `outputs` by one `data.<path>` or `outputs.<path>`
+// entry per leaf; arrays stay whole.
+fn flatten_record(mut record: Map<String, Value>) -> Map<String, Value> {
+    for prefix in FLATTENED {
+        if let Some(value) = record.remove(prefix) {
+            flatten_into(&mut record, prefix, value);
+        }
+    }
+    record
+}
+
+fn flatten_into(record: &mut Map<String, Value>, prefix: &str, value: Value) {
+    match value {
+        Value::Object(fields) if !fields.is_empty() => {
+            for (key, value) in fields {
+                flatten_into(record, &format!("{}.{}", prefix, key), value);
+            }
+        }
+        Value::Null if FLATTENED.contains(&prefix) => {}
+        value => {
+            record.insert(prefix.to_string(), value);
+        }
+    }
+}
+
+/// Fields spread over one column per leaf, in the order their columns appear
+const FLATTENED: [&str; 2] = ["data", "outputs"];
+
+/// Order of the other columns in tabular output
+const COLUMNS: [&str; 10] = [
+    "run_id", "workflow", "step", "status", "started_at", "duration_ms", "attempts", "success", "timed_out", "message",
+];
+
+// The known columns present in any record, then every `data` column and
+// every `outputs` column, each sorted by name
+fn columns(records: &[Map<String, Value>]) -> Vec<String> {
+    let mut columns: Vec<String> = COLUMNS
+        .iter()
+        .filter(|column| records.iter().any(|record| record.contains_key(**column)))
+        .map(|column| column.to_string())
+        .collect();
+    for prefix in FLATTENED {
+        let flattened: BTreeSet<&String> = records
+            .iter()
+            .flat_map(|record| record.keys())
+            .filter(|key| key.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('.')))
+            .collect();
+        columns.extend(flattened.into_iter().cloned());
+    }
+    columns
+}
+
+fn cell(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(text)) => text.clone(),
+        Some(value) => value.to_string(),
+    }
+}
+
+fn render_csv(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut out = String::new();
+    let header_row: Vec<String> = headers.iter().map(|header| header.to_string()).collect();
+    for row in std::iter::once(&header_row).chain(rows) {
+        let fields: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+        out.push_str(&fields.join(","));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+// Quotes fields as RFC 4180 requires
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Left-aligned columns separated by two spaces, without trailing whitespace
+pub(crate) fn render_table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|header| header.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let header_row: Vec<String> = headers.iter().map(|header| header.to_string()).collect();
+    std::iter::once(&header_row)
+        .chain(rows)
+        .map(|row| {
+            let line: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect();
+            line.join("  ").trim_end().to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+const XML_DECLARATION: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n";
+
+fn workflow_xml(result: &WorkflowResult) -> String {
+    let mut out = String::from(XML_DECLARATION);
+    out.push_str(&format!(
+        "<workflow_result run_id=\"{}\" workflow=\"{}\" status=\"{}\" success=\"{}\">\n",
+        escape_xml(&result.run_id),
+        escape_xml(&result.workflow),
+        result.status,
+        result.success
+    ));
+    for step in &result.steps {
+        out.push_str(&format!("  <step id=\"{}\" status=\"{}\"", escape_xml(&step.id), step.status));
+        if let Some(started_at) = step.started_at {
+            out.push_str(&format!(" started_at=\"{}\"", started_at.to_rfc3339()));
+        }
+        if let Some(duration_ms) = step.duration_ms {
+            out.push_str(&format!(" duration_ms=\"{}\"", duration_ms));
+        }
+        out.push_str(&format!(" attempts=\"{}\"", step.attempts.len()));
+        match &step.result {
+            Some(result) => {
+                out.push_str(">\n");
+                process_xml(&mut out, result, 2);
+                out.push_str("  </step>\n");
+            }
+            None => out.push_str("/>\n"),
+        }
+    }
+    if !result.outputs.is_empty() {
+        value_xml(&mut out, "outputs", None, &Value::Object(result.outputs.clone()), 1);
+    }
+    out.push_str("</workflow_result>");
+    out
+}
+
+fn process_xml(out: &mut String, result: &ProcessResult, depth: usize) {
+    let indent = "  ".repeat(depth);
+    out.push_str(&format!("{}<success>{}</success>\n", indent, result.success));
+    if result.timed_out {
+        out.push_str(&format!("{}<timed_out>true</timed_out>\n", indent));
+    }
+    out.push_str(&format!("{}<message>{}</message>\n", indent, escape_xml(&result.message)));
+    if let Some(data) = &result.data {
+        value_xml(out, "data", None, data, depth);
+    }
+}
+
+// Object keys become element names when they are valid XML names, and
+// `<entry key="...">` otherwise; array items become `<item>` elements.
+fn value_xml(out: &mut String, name: &str, key: Option<&str>, value: &Value, depth: usize) {
+    let indent = "  ".repeat(depth);
+    let open = match key {
+        Some(key) => format!("{}<{} key=\"{}\"", indent, name, escape_xml(key)),
+        None => format!("{}<{}", indent, name),
+    };
+    match value {
+        Value::Null => out.push_str(&format!("{}/>\n", open)),
+        Value::Object(fields) => {
+            out.push_str(&format!("{}>\n", open));
+            for (field, value) in fields {
+                if is_xml_name(field) {
+                    value_xml(out, field, None, value, depth + 1);
+                } else {
+                    value_xml(out, "entry", Some(field), value, depth + 1);
+                }
+            }
+            out.push_str(&format!("{}</{}>\n", indent, name));
+        }
+        Value::Array(items) => {
+            out.push_str(&format!("{}>\n", open));
+            for item in items {
+                value_xml(out, "item", None, item, depth + 1);
+            }
+            out.push_str(&format!("{}</{}>\n", indent, name));
+        }
+        Value::String(text) => out.push_str(&format!("{}>{}</{}>\n", open, escape_xml(text), name)),
+        value => out.push_str(&format!("{}>{}</{}>\n", open, value, name)),
+    }
+}
+
+fn is_xml_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        && !name.to_ascii_lowercase().starts_with("xml")
+}
+
+// Characters XML 1.0 does not allow at all, such as the ESC of ANSI colour
+// codes, become U+FFFD so the document stays well-formed.
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\t' | '\n' | '\r' => escaped.push(c),
+            '\u{0}'..='\u{1f}' | '\u{fffe}' | '\u{ffff}' => escaped.push(char::REPLACEMENT_CHARACTER),
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RunStatus, StepStatus};
+    use serde_json::json;
+
+    fn sample() -> WorkflowResult {
+        let step = |id: &str, status: StepStatus, data: Option<Value>| StepResult {
+            duration_ms: data.as_ref().map(|_| 5),
+            id: id.to_string(),
+            status,
+            result: data.map(|data| ProcessResult {
+                success: status == StepStatus::Succeeded,
+                message: format!("{} done, \"quoted\"", id),
+                data: Some(data),
+                timed_out: false,
+            }),
+            started_at: None,
+            attempts: Vec::new(),
+        };
+        WorkflowResult {
+            run_id: "r1".to_string(),
+            workflow: "etl".to_string(),
+            success: false,
+            status: RunStatus::Failed,
+            steps: vec![
+                step("fetch", StepStatus::Succeeded, Some(json!({"count": 3, "stats": {"rows": 10}, "ids": [1, 2]}))),
+                step("load", StepStatus::Failed, Some(json!({"exit_code": 1, "stderr": "a,b\n"}))),
+                step("report", StepStatus::Skipped, None),
+            ],
+            outputs: serde_json::Map::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_format() {
+        assert_eq!("CSV".parse::<OutputFormat>().unwrap(), OutputFormat::Csv);
+        for format in OutputFormat::ALL {
+            assert_eq!(format.to_string().parse::<OutputFormat>().unwrap(), format);
+        }
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_csv_flattens_data_columns() {
+        let csv = render_workflow(&sample(), OutputFormat::Csv).unwrap();
+        let lines: Vec<&str> = csv.split("\r\n").collect();
+        assert_eq!(
+            lines[0],
+            "run_id,workflow,step,status,started_at,duration_ms,attempts,success,timed_out,message,\
+             data.count,data.exit_code,data.ids,data.stats.rows,data.stderr"
+        );
+        assert_eq!(lines[1], "r1,etl,fetch,succeeded,,5,0,true,false,\"fetch done, \"\"quoted\"\"\",3,,\"[1,2]\",10,");
+        assert_eq!(lines[2], "r1,etl,load,failed,,5,0,false,false,\"load done, \"\"quoted\"\"\",,1,,,\"a,b\n\"");
+        assert_eq!(lines[3], "r1,etl,report,skipped,,,0,false,false,,,,,,");
+    }
+
+    #[test]
+    fn test_ndjson_has_one_object_per_step() {
+        let ndjson = render_workflow(&sample(), OutputFormat::Ndjson).unwrap();
+        let records: Vec<Value> = ndjson.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["data"]["stats"]["rows"], 10);
+        assert_eq!(records[2]["status"], "skipped");
+    }
+
+    #[test]
+    fn test_xml_escapes_and_nests_data() {
+        let xml = render_workflow(&sample(), OutputFormat::Xml).unwrap();
+        assert!(xml.starts_with("<?xml"));
+        assert!(xml.contains("<message>fetch done, &quot;quoted&quot;</message>"));
+        assert!(xml.contains("<stats>\n        <rows>10</rows>\n      </stats>"));
+        assert!(xml.contains("<ids>\n        <item>1</item>"));
+        assert!(xml.contains("<step id=\"report\" status=\"skipped\" attempts=\"0\"/>"));
+
+        let process = ProcessResult {
+            success: true,
+            message: "ok".to_string(),
+            data: Some(json!({"weird key": "<x>"})),
+            timed_out: false,
+        };
+        let xml = render_process(&process, OutputFormat::Xml).unwrap();
+        assert!(xml.contains("<entry key=\"weird key\">&lt;x&gt;</entry>"));
+
+        let process = ProcessResult {
+            success: true,
+            message: "bell\u{7}".to_string(),
+            data: Some(json!({"stdout": "\u{1b}[31mred\u{1b}[0m\tline\n"})),
+            timed_out: false,
+        };
+        let xml = render_process(&process, OutputFormat::Xml).unwrap();
+        assert!(!xml.chars().any(|c| c < ' ' && !matches!(c, '\t' | '\n' | '\r')));
+        assert!(xml.contains("<stdout>\u{fffd}[31mred\u{fffd}[0m\tline\n</stdout>"));
+        assert!(xml.contains("<message>bell\u{fffd}</message>"));
+    }
+
+    #[test]
+    fn test_outputs_appear_in_every_format() {
+        let mut result = sample();
+        result.outputs = json!({"url": "https://example.com", "stats": {"rows": 10}, "none": null})
+            .as_object()
+            .unwrap()
+            .clone();
+
+        let xml = render_workflow(&result, OutputFormat::Xml).unwrap();
+        assert!(xml.contains(
+            "  <outputs>\n    <none/>\n    <stats>\n      <rows>10</rows>\n    </stats>\n    \
+             <url>https://example.com</url>\n  </outputs>\n</workflow_result>"
+        ));
+
+        let ndjson = render_workflow(&result, OutputFormat::Ndjson).unwrap();
+        for line in ndjson.lines() {
+            let record: Value = serde_json::from_str(line).unwrap();
+            assert_eq!(record["outputs"]["stats"]["rows"], 10);
+        }
+
+        let csv = render_workflow(&result, OutputFormat::Csv).unwrap();
+        let lines: Vec<&str> = csv.split("\r\n").collect();
+        assert!(lines[0].ends_with(",data.stderr,outputs.none,outputs.stats.rows,outputs.url"), "{}", lines[0]);
+        assert!(lines[3].ends_with(",,,10,https://example.com"), "{}", lines[3]);
+
+        let table = render_workflow(&result, OutputFormat::Table).unwrap();
+        assert!(table.lines().next().unwrap().ends_with("outputs.stats.rows  outputs.url"));
+
+        // Without declared outputs the shape stays as it was
+        assert!(!render_workflow(&sample(), OutputFormat::Csv).unwrap().contains("outputs"));
+        assert!(!render_workflow(&sample(), OutputFormat::Xml).unwrap().contains("<outputs"));
+    }
+
+    #[test]
+    fn test_render_table_aligns_columns() {
+        let rows = vec![vec!["a".to_string(), "succeeded".to_string()], vec!["long-id".to_string(), String::new()]];
+        assert_eq!(render_table(&["STEP", "STATUS"], &rows), "STEP     STATUS\na        succeeded\nlong-id");
+    }
+}
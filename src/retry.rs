@@ -0,0 +1,315 @@
+// src/retry.rs
+/*
+ * Per-step retry policies with exponential backoff and jitter
+ */
+
+use crate::cancel::CancellationToken;
+use crate::ProcessResult;
+use chrono::{DateTime, Utc};
+use log::warn;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How often and how patiently a failed step is re-run
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the second attempt
+    #[serde(default = "default_initial_delay_ms")]
+    pub initial_delay_ms: u64,
+    /// Factor applied to the delay after every attempt
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+    /// Upper bound for the delay between two attempts
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+    /// Random spread applied to each delay, as a fraction between 0 and 1
+    #[serde(default)]
+    pub jitter: f64,
+    /// Which failures are worth retrying; by default all of them are
+    #[serde(default)]
+    pub retry_on: RetryFilter,
+}
+
+/// Narrows retries down to specific failures.
+///
+/// A failure is retryable when it matches any of the listed exit codes or
+/// message fragments. An empty filter matches every failure.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryFilter {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exit_codes: Vec<i32>,
+    /// Matched against the result message and a command's stderr
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub message_contains: Vec<String>,
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_initial_delay_ms() -> u64 {
+    1000
+}
+
+fn default_multiplier() -> f64 {
+    2.0
+}
+
+fn default_max_delay_ms() -> u64 {
+    60_000
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            initial_delay_ms: default_initial_delay_ms(),
+            multiplier: default_multiplier(),
+            max_delay_ms: default_max_delay_ms(),
+            jitter: 0.0,
+            retry_on: RetryFilter::default(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Describes the first invalid setting, if any.
+    pub fn check(&self) -> std::result::Result<(), String> {
+        if self.max_attempts == 0 {
+            return Err("retry.max_attempts must be at least 1".to_string());
+        }
+        if !self.multiplier.is_finite() || self.multiplier < 1.0 {
+            return Err("retry.multiplier must be at least 1.0".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err("retry.jitter must be between 0 and 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// Backoff before attempt number `attempt` (2 for the first retry), without jitter.
+    pub fn base_delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(2).min(i32::MAX as u32) as i32;
+        let delay = self.initial_delay_ms as f64 * self.multiplier.powi(exponent);
+        Duration::from_millis(delay.min(self.max_delay_ms as f64) as u64)
+    }
+
+    /// Backoff before attempt number `attempt`, spread by `jitter` and capped at `max_delay_ms`.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let base = self.base_delay(attempt).as_millis() as f64;
+        if self.jitter <= 0.0 || base <= 0.0 {
+            return Duration::from_millis(base as u64);
+        }
+        let spread = rand::thread_rng().gen_range(-self.jitter..=self.jitter);
+        let delay = (base * (1.0 + spread)).clamp(0.0, self.max_delay_ms as f64);
+        Duration::from_millis(delay as u64)
+    }
+
+    pub fn is_retryable(&self, result: &ProcessResult) -> bool {
+        if result.success {
+            return false;
+        }
+        let filter = &self.retry_on;
+        if filter.exit_codes.is_empty() && filter.message_contains.is_empty() {
+            return true;
+        }
+
+        let data = result.data.as_ref();
+        let exit_code = data
+            .and_then(|data| data.get("exit_code"))
+            .and_then(|code| code.as_i64());
+        if exit_code.is_some_and(|code| filter.exit_codes.iter().any(|&c| c as i64 == code)) {
+            return true;
+        }
+
+        let stderr = data
+            .and_then(|data| data.get("stderr"))
+            .and_then(|stderr| stderr.as_str())
+            .unwrap_or("");
+        filter
+            .message_contains
+            .iter()
+            .any(|fragment| result.message.contains(fragment.as_str()) || stderr.contains(fragment.as_str()))
+    }
+}
+
+/// One execution of a step
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attempt {
+    pub number: u32,
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    #[serde(flatten)]
+    pub result: ProcessResult,
+    /// Backoff waited before the next attempt, if there was one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_delay_ms: Option<u64>,
+}
+
+/// Runs `run_once` with the attempt number until it succeeds, fails with a
+/// non-retryable error or runs out of attempts. Without a policy the step is
+/// run exactly once. `on_retry` sees every failed attempt that is followed by
+/// another one, before the backoff.
+///
+/// No further attempts are made once `token` stops, and the backoff between
+/// attempts is cut short when it does.
+pub(crate) fn run_with_retry<F, R>(
+    step_id: &str,
+    policy: Option<&RetryPolicy>,
+    token: &CancellationToken,
+    mut run_once: F,
+    mut on_retry: R,
+) -> Vec<Attempt>
+where
+    F: FnMut(u32) -> ProcessResult,
+    R: FnMut(&Attempt),
+{
+    let max_attempts = policy.map(|p| p.max_attempts.max(1)).unwrap_or(1);
+    let mut attempts: Vec<Attempt> = Vec::new();
+
+    for number in 1..=max_attempts {
+        let started_at = Utc::now();
+        let result = run_once(number);
+        let duration_ms = (Utc::now() - started_at).num_milliseconds().max(0) as u64;
+        let retry = number < max_attempts
+            && !token.is_cancelled()
+            && policy.is_some_and(|p| p.is_retryable(&result));
+        attempts.push(Attempt {
+            number,
+            started_at,
+            duration_ms,
+            result,
+            retry_delay_ms: None,
+        });
+        if !retry {
+            break;
+        }
+
+        let delay = policy.map(|p| p.delay(number + 1)).unwrap_or_default();
+        if let Some(last) = attempts.last_mut() {
+            warn!(
+                "Step '{}' attempt {}/{} failed ({}); retrying in {:?}",
+                step_id, number, max_attempts, last.result.message, delay
+            );
+            last.retry_delay_ms = Some(delay.as_millis() as u64);
+            on_retry(last);
+        }
+        if !token.sleep(delay) {
+            break;
+        }
+    }
+
+    attempts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(message: &str, exit_code: Option<i32>) -> ProcessResult {
+        ProcessResult {
+            success: false,
+            message: message.to_string(),
+            data: exit_code.map(|code| serde_json::json!({"exit_code": code, "stderr": "connection reset"})),
+            timed_out: false,
+        }
+    }
+
+    fn quick_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_delay_ms: 1,
+            max_delay_ms: 5,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn test_exponential_backoff_is_capped() {
+        let policy = RetryPolicy {
+            initial_delay_ms: 100,
+            multiplier: 3.0,
+            max_delay_ms: 1000,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.base_delay(2), Duration::from_millis(100));
+        assert_eq!(policy.base_delay(3), Duration::from_millis(300));
+        assert_eq!(policy.base_delay(4), Duration::from_millis(900));
+        assert_eq!(policy.base_delay(5), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_jitter_stays_within_bounds() {
+        let policy = RetryPolicy {
+            initial_delay_ms: 1000,
+            jitter: 0.5,
+            ..RetryPolicy::default()
+        };
+        for _ in 0..100 {
+            let delay = policy.delay(2).as_millis();
+            assert!((500..=1500).contains(&delay), "{}", delay);
+        }
+    }
+
+    #[test]
+    fn test_retry_filter() {
+        let mut policy = RetryPolicy::default();
+        assert!(policy.is_retryable(&failure("boom", None)));
+
+        policy.retry_on.exit_codes = vec![75];
+        assert!(policy.is_retryable(&failure("exit", Some(75))));
+        assert!(!policy.is_retryable(&failure("exit", Some(1))));
+
+        policy.retry_on.message_contains = vec!["connection reset".to_string()];
+        assert!(policy.is_retryable(&failure("exit", Some(1))));
+        assert!(!policy.is_retryable(&failure("bad input", None)));
+    }
+
+    #[test]
+    fn test_retries_until_success() {
+        let mut retried = Vec::new();
+        let attempts = run_with_retry(
+            "flaky",
+            Some(&quick_policy(5)),
+            &CancellationToken::new(),
+            |number| ProcessResult {
+                success: number == 3,
+                message: format!("call {}", number),
+                data: None,
+                timed_out: false,
+            },
+            |attempt| retried.push(attempt.number),
+        );
+        assert_eq!(attempts.len(), 3);
+        assert!(attempts[2].result.success);
+        assert!(attempts[0].retry_delay_ms.is_some());
+        assert!(attempts[2].retry_delay_ms.is_none());
+        assert_eq!(retried, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_stops_on_non_retryable_failure_or_exhaustion() {
+        let mut policy = quick_policy(4);
+        policy.retry_on.exit_codes = vec![75];
+        let token = CancellationToken::new();
+        let attempts = run_with_retry("s", Some(&policy), &token, |_| failure("exit", Some(2)), |_| {});
+        assert_eq!(attempts.len(), 1);
+
+        let attempts = run_with_retry("s", Some(&quick_policy(4)), &token, |_| failure("boom", None), |_| {});
+        assert_eq!(attempts.len(), 4);
+        assert_eq!(attempts.iter().map(|a| a.number).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+
+        let attempts = run_with_retry("s", None, &token, |_| failure("boom", None), |_| {});
+        assert_eq!(attempts.len(), 1);
+
+        token.cancel();
+        let attempts = run_with_retry("s", Some(&quick_policy(4)), &token, |_| failure("boom", None), |_| {});
+        assert_eq!(attempts.len(), 1);
+    }
+}
@@ -0,0 +1,228 @@
+// src/run.rs
+/*
+ * Per-run bookkeeping shared by the coordinating thread and the workers
+ */
+
+use crate::events::ParentRun;
+use crate::expr::{self, ExprError, ExprResult, Expression, Template};
+use crate::workflow::{Step, StepAction, Workflow};
+use crate::{StepResult, StepStatus, WorkflowError};
+use log::warn;
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
+
+/// The results of one run so far, and what its expressions can see.
+///
+/// Results are recorded as steps finish, including steps nested in branches,
+/// which run on worker threads. A step recorded twice, e.g. a branch step
+/// that runs again on resume, keeps only its latest result.
+#[derive(Debug)]
+pub(crate) struct RunScope {
+    run_id: String,
+    /// Set in the run of a sub-workflow
+    parent: Option<ParentRun>,
+    /// Names of the workflows running this one, outermost first, ending
+    /// with this one; used to refuse workflows that call themselves
+    callers: Vec<String>,
+    inputs: Value,
+    /// `item` and `index` while running one item of a map, `iteration` in a loop
+    locals: Map<String, Value>,
+    /// `steps.<id>` as seen by expressions
+    outputs: RwLock<Map<String, Value>>,
+    results: Mutex<Vec<StepResult>>,
+    /// Whether results go to the event sinks; map items and loop iterations
+    /// only report through the map or loop step's own result
+    journaled: bool,
+}
+
+impl RunScope {
+    pub fn new(run_id: &str, workflow: &Workflow) -> Self {
+        Self {
+            run_id: run_id.to_string(),
+            parent: None,
+            callers: vec![workflow.name.clone()],
+            inputs: Value::Object(workflow.inputs.clone()),
+            locals: Map::new(),
+            outputs: RwLock::new(Map::new()),
+            results: Mutex::new(Vec::new()),
+            journaled: true,
+        }
+    }
+
+    /// A scope for one item of a map or one iteration of a loop: it sees
+    /// everything this one does, plus `locals`, and records results of its own.
+    pub fn child<'a>(&self, locals: impl IntoIterator<Item = (&'a str, Value)>) -> Self {
+        let mut own = self.locals.clone();
+        own.extend(locals.into_iter().map(|(name, value)| (name.to_string(), value)));
+        let locals = own;
+        Self {
+            run_id: self.run_id.clone(),
+            parent: self.parent.clone(),
+            callers: self.callers.clone(),
+            inputs: self.inputs.clone(),
+            locals,
+            outputs: RwLock::new(self.outputs.read().map(|outputs| outputs.clone()).unwrap_or_default()),
+            results: Mutex::new(Vec::new()),
+            journaled: false,
+        }
+    }
+
+    /// Links the run to the run that started it, e.g. when resuming a sub-workflow run
+    pub fn with_parent(mut self, parent: Option<ParentRun>) -> Self {
+        self.parent = parent;
+        self
+    }
+
+    /// The scope of a sub-workflow run started by `step` of this run
+    pub fn called(&self, run_id: &str, workflow: &Workflow, step: &str) -> Self {
+        let mut scope = Self::new(run_id, workflow).with_parent(Some(ParentRun {
+            run_id: self.run_id.clone(),
+            step: step.to_string(),
+        }));
+        scope.callers = self.callers.iter().cloned().chain(scope.callers).collect();
+        scope
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    pub fn parent(&self) -> Option<&ParentRun> {
+        self.parent.as_ref()
+    }
+
+    /// Name of the workflow this scope runs steps of
+    pub fn workflow(&self) -> &str {
+        self.callers.last().map_or("", String::as_str)
+    }
+
+    /// The chain of workflows that led here, e.g. `["release", "deploy"]`
+    pub fn callers(&self) -> &[String] {
+        &self.callers
+    }
+
+    pub fn is_journaled(&self) -> bool {
+        self.journaled
+    }
+
+    /// Records a finished step and returns its status
+    pub fn record(&self, step: StepResult) -> StepStatus {
+        let status = step.status;
+        let result = step.result.as_ref();
+        let entry = serde_json::json!({
+            "status": status,
+            "success": status == StepStatus::Succeeded,
+            "message": result.map(|result| result.message.as_str()).unwrap_or_default(),
+            "output": result.and_then(|result| result.data.clone()),
+        });
+        if let Ok(mut outputs) = self.outputs.write() {
+            outputs.insert(step.id.clone(), entry);
+        }
+        if let Ok(mut results) = self.results.lock() {
+            results.retain(|existing| existing.id != step.id);
+            results.push(step);
+        }
+        status
+    }
+
+    pub fn status(&self, id: &str) -> Option<StepStatus> {
+        let results = self.results.lock().ok()?;
+        results.iter().find(|step| step.id == id).map(|step| step.status)
+    }
+
+    /// Ids of the steps that succeeded so far
+    pub fn succeeded(&self) -> HashSet<String> {
+        self.results
+            .lock()
+            .map(|results| {
+                results
+                    .iter()
+                    .filter(|step| step.status == StepStatus::Succeeded)
+                    .map(|step| step.id.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Takes the recorded results, in the order the steps finished
+    pub fn take_results(&self) -> Vec<StepResult> {
+        self.results.lock().map(|mut results| std::mem::take(&mut *results)).unwrap_or_default()
+    }
+
+    fn expression_scope(&self) -> Value {
+        let mut scope = self.locals.clone();
+        let steps = self.outputs.read().map(|outputs| outputs.clone()).unwrap_or_default();
+        scope.insert("steps".to_string(), Value::Object(steps));
+        scope.insert("inputs".to_string(), self.inputs.clone());
+        Value::Object(scope)
+    }
+
+    /// Evaluates an expression written bare or as `${{ ... }}`
+    pub fn evaluate(&self, source: &str) -> ExprResult<Value> {
+        Expression::parse_condition(source)?.evaluate(&self.expression_scope())
+    }
+
+    /// Evaluates a condition written bare or as `${{ ... }}`
+    pub fn holds(&self, source: &str) -> ExprResult<bool> {
+        Expression::parse_condition(source)?.evaluate_condition(&self.expression_scope())
+    }
+
+    /// Evaluates the step's `when` condition; steps without one always run.
+    pub fn condition(&self, step: &Step) -> ExprResult<bool> {
+        match &step.when {
+            Some(when) => self.holds(when),
+            None => Ok(true),
+        }
+    }
+
+    /// Evaluates the workflow's `outputs` mapping. An output that cannot be
+    /// evaluated, e.g. because the step it reads did not run, is null.
+    pub fn outputs(&self, mapping: &BTreeMap<String, String>) -> Map<String, Value> {
+        mapping
+            .iter()
+            .map(|(name, source)| {
+                let value = self.evaluate(source).unwrap_or_else(|e| {
+                    warn!("Output '{}' of run {} is null: {}", name, self.run_id, e);
+                    Value::Null
+                });
+                (name.clone(), value)
+            })
+            .collect()
+    }
+
+    /// The step with every `${{ ... }}` in its inputs and command filled in
+    pub fn interpolate<'a>(&self, step: &'a Step) -> crate::Result<Cow<'a, Step>> {
+        let fail = |e: ExprError| WorkflowError::step_failed(&step.id, "cannot evaluate expression").with_source(e);
+        if step.templates().map_err(fail)?.is_empty() {
+            return Ok(Cow::Borrowed(step));
+        }
+        let scope = self.expression_scope();
+        let render = |text: &str| -> ExprResult<String> {
+            match Template::parse(text)? {
+                Some(template) => template.render(&scope).map(|value| expr::display(&value)),
+                None => Ok(text.to_string()),
+            }
+        };
+
+        let mut step = step.clone();
+        if let Some(Value::Object(inputs)) = expr::interpolate(&Value::Object(step.inputs.clone()), &scope).map_err(fail)? {
+            step.inputs = inputs;
+        }
+        if let StepAction::Command(spec) = &mut step.action {
+            spec.program = render(&spec.program).map_err(fail)?;
+            for arg in &mut spec.args {
+                *arg = render(arg).map_err(fail)?;
+            }
+            for value in spec.env.values_mut() {
+                *value = render(value).map_err(fail)?;
+            }
+            if let Some(dir) = spec.working_dir.as_mut().and_then(|dir| dir.to_str().map(str::to_string)) {
+                spec.working_dir = Some(PathBuf::from(render(&dir).map_err(fail)?));
+            }
+        }
+        Ok(Cow::Owned(step))
+    }
+}
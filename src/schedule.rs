@@ -0,0 +1,316 @@
+// src/schedule.rs
+/*
+ * Cron schedules: five-field expressions, time zones and catch-up policies
+ */
+
+use chrono::{DateTime, Datelike, Duration, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// How far ahead [`CronExpr::next_after`] looks before giving up, e.g. for
+/// `0 0 30 2 *`, which never matches
+const SEARCH_DAYS: i64 = 366 * 5;
+
+/// When a workflow runs by itself, from the `schedule` field of its definition.
+///
+/// Written either as a bare cron expression or as a table:
+///
+/// ```yaml
+/// schedule: "0 */6 * * *"
+/// # or
+/// schedule:
+///   cron: "30 2 * * mon-fri"
+///   timezone: Europe/Berlin
+///   catch_up: run_once
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Schedule {
+    pub cron: String,
+    /// IANA time zone name the expression is read in; UTC when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    #[serde(skip_serializing_if = "CatchUp::is_default")]
+    pub catch_up: CatchUp,
+}
+
+/// What the daemon does about times that passed while it was not running
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CatchUp {
+    /// Forget them and wait for the next time
+    #[default]
+    Skip,
+    /// Run once for all of them together
+    RunOnce,
+    /// Run once for each of them, one after the other
+    RunAll,
+}
+
+impl CatchUp {
+    fn is_default(&self) -> bool {
+        *self == CatchUp::Skip
+    }
+}
+
+impl fmt::Display for CatchUp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CatchUp::Skip => "skip",
+            CatchUp::RunOnce => "run_once",
+            CatchUp::RunAll => "run_all",
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Table {
+            cron: String,
+            #[serde(default)]
+            timezone: Option<String>,
+            #[serde(default)]
+            catch_up: CatchUp,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Form {
+            Cron(String),
+            Table(Table),
+        }
+
+        Ok(match Form::deserialize(deserializer)? {
+            Form::Cron(cron) => Schedule { cron, timezone: None, catch_up: CatchUp::default() },
+            Form::Table(table) => Schedule { cron: table.cron, timezone: table.timezone, catch_up: table.catch_up },
+        })
+    }
+}
+
+impl Schedule {
+    /// Parses the expression and time zone, as [`Workflow::validate`](crate::Workflow::validate) does
+    pub fn parse(&self) -> Result<(CronExpr, Tz), String> {
+        let cron = self.cron.parse::<CronExpr>()?;
+        let timezone = match &self.timezone {
+            Some(name) => name.parse::<Tz>().map_err(|_| format!("unknown time zone '{}'", name))?,
+            None => Tz::UTC,
+        };
+        Ok((cron, timezone))
+    }
+}
+
+/// A parsed five-field cron expression: minute, hour, day of month, month
+/// and day of week.
+///
+/// Fields take `*`, numbers, ranges (`1-5`), steps (`*/15`, `0-30/10`) and
+/// lists of those; months and weekdays also take names (`jan`, `mon-fri`).
+/// Sunday is 0 or 7. When both day fields are restricted, a day matching
+/// either one counts, as in classic cron. `@hourly`, `@daily`, `@weekly`,
+/// `@monthly` and `@yearly` are accepted as shorthands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronExpr {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day-of-month and day-of-week fields were `*`
+    any_day: bool,
+    any_weekday: bool,
+}
+
+const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl FromStr for CronExpr {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let expanded = match source.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "cron expression '{}' needs 5 fields (minute hour day month weekday), got {}",
+                source,
+                fields.len()
+            ));
+        }
+        let invalid = |name: &str, e: String| format!("cron expression '{}': {}: {}", source, name, e);
+        let weekdays = parse_field(fields[4], 0, 7, &WEEKDAYS).map_err(|e| invalid("weekday", e))?;
+        Ok(CronExpr {
+            minutes: parse_field(fields[0], 0, 59, &[]).map_err(|e| invalid("minute", e))?,
+            hours: parse_field(fields[1], 0, 23, &[]).map_err(|e| invalid("hour", e))?,
+            days: parse_field(fields[2], 1, 31, &[]).map_err(|e| invalid("day of month", e))?,
+            months: parse_field(fields[3], 1, 12, &MONTHS).map_err(|e| invalid("month", e))?,
+            // 7 is another name for Sunday
+            weekdays: (weekdays | (weekdays >> 7)) & 0x7f,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+}
+
+// Parses one field into a bit set of the values it allows. `names` spell out
+// the values from `min` up, e.g. months from 1.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let value = |text: &str| -> Result<u32, String> {
+        let lower = text.to_ascii_lowercase();
+        if let Some(position) = names.iter().position(|name| *name == lower) {
+            return Ok(min + position as u32);
+        }
+        match text.parse::<u32>() {
+            Ok(n) if (min..=max).contains(&n) => Ok(n),
+            Ok(n) => Err(format!("{} is outside {}-{}", n, min, max)),
+            Err(_) => Err(format!("'{}' is not a number", text)),
+        }
+    };
+
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("invalid step '{}'", step)),
+            },
+            None => (part, 1),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((first, last)) => (value(first)?, value(last)?),
+                // `5/15` means from 5 to the end in steps of 15
+                None if part.contains('/') => (value(range)?, max),
+                None => {
+                    let n = value(range)?;
+                    (n, n)
+                }
+            },
+        };
+        if first > last {
+            return Err(format!("range {}-{} is backwards", first, last));
+        }
+        for n in (first..=last).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+impl CronExpr {
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first time after `after` that matches, read in `timezone`.
+    ///
+    /// Local times skipped by a daylight saving change never happen; local
+    /// times repeated by one happen once, at their first occurrence.
+    pub fn next_after<Z: TimeZone>(&self, after: &DateTime<Z>, timezone: &Z) -> Option<DateTime<Z>> {
+        let start = after.naive_local().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let limit = start + Duration::days(SEARCH_DAYS);
+        let mut time = start;
+        while time < limit {
+            if self.months & (1 << time.month()) == 0 {
+                let (year, month) = if time.month() == 12 { (time.year() + 1, 1) } else { (time.year(), time.month() + 1) };
+                time = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if !self.day_matches(time.date()) {
+                time = time.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if self.hours & (1 << time.hour()) == 0 {
+                time = next_hour(time)?;
+                continue;
+            }
+            if self.minutes & (1 << time.minute()) == 0 {
+                time += Duration::minutes(1);
+                continue;
+            }
+            match timezone.from_local_datetime(&time) {
+                LocalResult::Single(found) if found > *after => return Some(found),
+                LocalResult::Ambiguous(first, _) if first > *after => return Some(first),
+                _ => {}
+            }
+            time += Duration::minutes(1);
+        }
+        None
+    }
+}
+
+fn next_hour(time: NaiveDateTime) -> Option<NaiveDateTime> {
+    (time + Duration::hours(1)).with_minute(0)
+}
+
+/// The first time after `after` that `cron`, read in `timezone`, matches
+pub fn next_run(cron: &CronExpr, timezone: &Tz, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    cron.next_after(&after.with_timezone(timezone), timezone).map(|time| time.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().with_timezone(&Utc)
+    }
+
+    fn next(cron: &str, timezone: Tz, after: &str) -> String {
+        let cron: CronExpr = cron.parse().unwrap();
+        next_run(&cron, &timezone, utc(after)).unwrap().to_rfc3339()
+    }
+
+    #[test]
+    fn test_next_run() {
+        assert_eq!(next("0 */6 * * *", Tz::UTC, "2024-03-01T05:59:00Z"), "2024-03-01T06:00:00+00:00");
+        assert_eq!(next("0 */6 * * *", Tz::UTC, "2024-03-01T06:00:00Z"), "2024-03-01T12:00:00+00:00");
+        assert_eq!(next("30 2 * * mon-fri", Tz::UTC, "2024-03-01T03:00:00Z"), "2024-03-04T02:30:00+00:00");
+        assert_eq!(next("0 0 29 feb *", Tz::UTC, "2024-03-01T00:00:00Z"), "2028-02-29T00:00:00+00:00");
+        assert_eq!(next("@monthly", Tz::UTC, "2024-12-15T00:00:00Z"), "2025-01-01T00:00:00+00:00");
+        assert_eq!(next("5/1 * * * *", Tz::UTC, "2024-03-01T05:07:00Z"), "2024-03-01T05:08:00+00:00");
+        // Either day field may match when both are given
+        assert_eq!(next("0 0 13 * 5", Tz::UTC, "2024-09-01T00:00:00Z"), "2024-09-06T00:00:00+00:00");
+        assert!(next_run(&"0 0 30 2 *".parse().unwrap(), &Tz::UTC, utc("2024-01-01T00:00:00Z")).is_none());
+    }
+
+    #[test]
+    fn test_time_zones_and_daylight_saving() {
+        let berlin: Tz = "Europe/Berlin".parse().unwrap();
+        assert_eq!(next("0 9 * * *", berlin, "2024-07-01T12:00:00Z"), "2024-07-02T07:00:00+00:00");
+        // 02:30 does not exist on the night clocks go forward
+        assert_eq!(next("30 2 * * *", berlin, "2024-03-30T12:00:00Z"), "2024-04-01T00:30:00+00:00");
+        // and happens only once on the night they go back
+        assert_eq!(next("30 2 * * *", berlin, "2024-10-26T12:00:00Z"), "2024-10-27T00:30:00+00:00");
+        assert_eq!(next("30 2 * * *", berlin, "2024-10-27T00:30:00Z"), "2024-10-28T01:30:00+00:00");
+    }
+
+    #[test]
+    fn test_invalid_expressions() {
+        let error = |cron: &str| cron.parse::<CronExpr>().unwrap_err();
+        assert!(error("0 * * *").contains("needs 5 fields"));
+        assert!(error("60 * * * *").contains("minute: 60 is outside 0-59"));
+        assert!(error("0 0 * foo *").contains("month: 'foo' is not a number"));
+        assert!(error("*/0 * * * *").contains("invalid step '0'"));
+        assert!(error("0 5-1 * * *").contains("backwards"));
+
+        let schedule: Schedule = serde_json::from_str(r#"{"cron": "@daily", "timezone": "Mars/Olympus"}"#).unwrap();
+        assert_eq!(schedule.parse().unwrap_err(), "unknown time zone 'Mars/Olympus'");
+        let schedule: Schedule = serde_json::from_str(r#""@daily""#).unwrap();
+        assert_eq!(schedule.catch_up, CatchUp::Skip);
+    }
+}
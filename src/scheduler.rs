@@ -0,0 +1,608 @@
+// src/scheduler.rs
+/*
+ * DAG scheduling: tracks which steps are ready and runs them on a worker pool
+ */
+
+use crate::workflow::{Step, TriggerRule, ValidationError, Workflow};
+use crate::{ProcessResult, StepStatus};
+use crate::cancel::{CancelReason, CancellationToken, POLL_INTERVAL};
+use crate::metrics::Metrics;
+use crate::retry::{self, Attempt};
+use log::debug;
+use std::collections::{HashSet, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Dependency bookkeeping for one workflow run.
+///
+/// The scheduler never runs anything itself; drivers pull ready steps with
+/// [`Scheduler::next_ready`] and report back through [`Scheduler::complete`].
+#[derive(Debug, Clone)]
+pub(crate) struct Scheduler {
+    dependents: Vec<Vec<usize>>,
+    /// Number of unfinished dependencies per step
+    waiting_on: Vec<usize>,
+    dependency_count: Vec<usize>,
+    /// Number of dependencies per step that succeeded
+    succeeded: Vec<usize>,
+    triggers: Vec<TriggerRule>,
+    status: Vec<Option<StepStatus>>,
+    started: Vec<bool>,
+    ready: VecDeque<usize>,
+    running: usize,
+}
+
+impl Scheduler {
+    pub fn new(workflow: &Workflow) -> std::result::Result<Self, ValidationError> {
+        Self::resume(workflow, &HashSet::new())
+    }
+
+    /// Like [`Scheduler::new`], but treats the `completed` steps as already succeeded.
+    pub fn resume(workflow: &Workflow, completed: &HashSet<&str>) -> std::result::Result<Self, ValidationError> {
+        workflow.check_graph()?;
+
+        let mut dependents = vec![Vec::new(); workflow.steps.len()];
+        for (i, step) in workflow.steps.iter().enumerate() {
+            for dependency in &step.depends_on {
+                if let Some(d) = workflow.steps.iter().position(|s| &s.id == dependency) {
+                    dependents[d].push(i);
+                }
+            }
+        }
+
+        let done: Vec<bool> = workflow.steps.iter().map(|step| completed.contains(step.id.as_str())).collect();
+        let waiting_on: Vec<usize> = workflow
+            .steps
+            .iter()
+            .map(|step| {
+                step.depends_on
+                    .iter()
+                    .filter(|dependency| !completed.contains(dependency.as_str()))
+                    .count()
+            })
+            .collect();
+        // Completed dependencies all succeeded
+        let succeeded = workflow
+            .steps
+            .iter()
+            .zip(&waiting_on)
+            .map(|(step, waiting)| step.depends_on.len() - waiting)
+            .collect();
+        let ready = (0..workflow.steps.len()).filter(|&i| !done[i] && waiting_on[i] == 0).collect();
+        Ok(Self {
+            dependents,
+            waiting_on,
+            dependency_count: workflow.steps.iter().map(|step| step.depends_on.len()).collect(),
+            succeeded,
+            triggers: workflow.steps.iter().map(|step| step.trigger).collect(),
+            status: done.iter().map(|&done| done.then_some(StepStatus::Succeeded)).collect(),
+            started: done,
+            ready,
+            running: 0,
+        })
+    }
+
+    /// Hands out the next step whose dependencies have all succeeded.
+    pub fn next_ready(&mut self) -> Option<usize> {
+        let next = self.ready.pop_front()?;
+        self.started[next] = true;
+        self.running += 1;
+        Some(next)
+    }
+
+    /// Records the outcome of a running step.
+    ///
+    /// Once all dependencies of a step have finished, its trigger rule decides
+    /// whether it runs. Returns the steps that will not run because of this,
+    /// in the order they were resolved; they are already marked as skipped.
+    pub fn complete(&mut self, index: usize, status: StepStatus) -> Vec<usize> {
+        self.running -= 1;
+        self.status[index] = Some(status);
+
+        let mut skipped = Vec::new();
+        let mut finished = vec![index];
+        while let Some(done) = finished.pop() {
+            let succeeded = self.status[done] == Some(StepStatus::Succeeded);
+            for &dependent in &self.dependents[done] {
+                self.waiting_on[dependent] -= 1;
+                if succeeded {
+                    self.succeeded[dependent] += 1;
+                }
+                if self.waiting_on[dependent] > 0 || self.status[dependent].is_some() {
+                    continue;
+                }
+                let runs = match self.triggers[dependent] {
+                    TriggerRule::AllSuccess => self.succeeded[dependent] == self.dependency_count[dependent],
+                    TriggerRule::AnySuccess => self.succeeded[dependent] > 0,
+                    TriggerRule::Always => true,
+                };
+                if runs {
+                    self.ready.push_back(dependent);
+                } else {
+                    self.status[dependent] = Some(StepStatus::Skipped);
+                    skipped.push(dependent);
+                    finished.push(dependent);
+                }
+            }
+        }
+        skipped
+    }
+
+    /// Marks every step that has not started yet as cancelled and returns them.
+    ///
+    /// Steps that are still running are left alone and complete as usual.
+    pub fn cancel_pending(&mut self) -> Vec<usize> {
+        self.ready.clear();
+        let pending: Vec<usize> = (0..self.status.len())
+            .filter(|&i| !self.started[i] && self.status[i].is_none())
+            .collect();
+        for &i in &pending {
+            self.status[i] = Some(StepStatus::Cancelled);
+        }
+        pending
+    }
+
+    /// True once nothing is running and nothing is left to start.
+    pub fn is_finished(&self) -> bool {
+        self.running == 0 && self.ready.is_empty()
+    }
+}
+
+/// The processor-wide limit on steps running at once.
+///
+/// Every attempt of a `process` or `command` step holds a slot while it runs.
+/// Control-flow and `workflow` steps only wait for the steps they start, so
+/// they hold none; the steps of a branch, map item, loop iteration or child
+/// workflow take their slots from the same limit as the top level.
+#[derive(Debug)]
+pub(crate) struct WorkerSlots {
+    capacity: usize,
+    used: Mutex<usize>,
+    freed: Condvar,
+    metrics: Arc<Metrics>,
+}
+
+/// A slot taken from [`WorkerSlots`], given back when dropped
+#[derive(Debug)]
+pub(crate) struct Slot<'a> {
+    slots: &'a WorkerSlots,
+}
+
+impl WorkerSlots {
+    pub fn new(capacity: usize, metrics: Arc<Metrics>) -> Self {
+        let capacity = capacity.max(1);
+        metrics.set_workers(capacity);
+        Self {
+            capacity,
+            used: Mutex::new(0),
+            freed: Condvar::new(),
+            metrics,
+        }
+    }
+
+    /// Waits for a free slot, or returns `None` once `token` stops.
+    pub fn acquire(&self, token: &CancellationToken) -> Option<Slot<'_>> {
+        self.metrics.step_queued();
+        let mut used = self.used.lock().unwrap();
+        while *used >= self.capacity && !token.is_cancelled() {
+            used = self.freed.wait_timeout(used, POLL_INTERVAL).unwrap().0;
+        }
+        self.metrics.step_dequeued();
+        if *used >= self.capacity {
+            return None;
+        }
+        *used += 1;
+        self.metrics.worker_busy(true);
+        Some(Slot { slots: self })
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.slots.used.lock().unwrap() -= 1;
+        self.slots.metrics.worker_busy(false);
+        self.slots.freed.notify_one();
+    }
+}
+
+/// Outcome of one step as reported by a driver
+#[derive(Debug)]
+pub(crate) struct Completion {
+    pub index: usize,
+    /// Every attempt made, the last one decides the step status
+    pub attempts: Vec<Attempt>,
+    /// Set when the last attempt was stopped by a timeout or cancellation
+    pub interrupted: Option<CancelReason>,
+}
+
+/// What a step is up to before it completes, as reported to drivers
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Progress<'a> {
+    /// Attempt number `n` is starting
+    Started(u32),
+    /// The attempt failed and is retried after its `retry_delay_ms`
+    RetryScheduled(&'a Attempt),
+}
+
+/// Runs a step including its retries, telling `on_progress` as attempts
+/// start and fail.
+///
+/// Each attempt of a `process` or `command` step first waits for one of
+/// `slots`, then gets its own child of `token` that also expires after the
+/// step's `timeout_ms`.
+pub(crate) fn execute<F, P>(
+    index: usize,
+    step: &Step,
+    slots: &WorkerSlots,
+    run_step: &F,
+    on_progress: &P,
+    token: &CancellationToken,
+) -> Completion
+where
+    F: Fn(&Step, &CancellationToken) -> crate::Result<ProcessResult>,
+    P: Fn(&Step, Progress),
+{
+    let mut interrupted = None;
+    let on_retry = |attempt: &Attempt| on_progress(step, Progress::RetryScheduled(attempt));
+    let attempts = retry::run_with_retry(&step.id, step.retry.as_ref(), token, |number| {
+        let _slot = match step.action.is_leaf() {
+            true => match slots.acquire(token) {
+                Some(slot) => Some(slot),
+                None => {
+                    interrupted = token.reason();
+                    return ProcessResult {
+                        success: false,
+                        message: format!("Step '{}' stopped before a worker was free", step.id),
+                        data: None,
+                        timed_out: false,
+                    };
+                }
+            },
+            false => None,
+        };
+        on_progress(step, Progress::Started(number));
+        let attempt_token = token.child(step.timeout_ms.map(Duration::from_millis));
+        let mut result = run_guarded(step, run_step, &attempt_token);
+        interrupted = attempt_token.reason();
+        match interrupted {
+            Some(CancelReason::TimedOut) => {
+                result.success = false;
+                result.timed_out = true;
+                result.message = format!("Step '{}' timed out: {}", step.id, result.message);
+            }
+            Some(CancelReason::Cancelled) => {
+                result.success = false;
+                result.message = format!("Step '{}' was cancelled: {}", step.id, result.message);
+            }
+            None => {}
+        }
+        result
+    }, on_retry);
+    Completion { index, attempts, interrupted }
+}
+
+/// Runs one step, turning errors and panics into a failed result.
+pub(crate) fn run_guarded<F>(step: &Step, run_step: &F, token: &CancellationToken) -> ProcessResult
+where
+    F: Fn(&Step, &CancellationToken) -> crate::Result<ProcessResult>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(|| run_step(step, token))) {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => ProcessResult {
+            success: false,
+            message: match e.step_id() {
+                Some(_) => e.full_message(),
+                None => format!("Step '{}' failed: {}", step.id, e.full_message()),
+            },
+            data: None,
+            timed_out: false,
+        },
+        Err(_) => ProcessResult {
+            success: false,
+            message: format!("Step '{}' panicked", step.id),
+            data: None,
+            timed_out: false,
+        },
+    }
+}
+
+/// Runs the workflow on up to `workers` threads.
+///
+/// A step is queued as soon as its last dependency succeeds, unless
+/// `before_start` settles it first by returning its status (e.g. a `when`
+/// condition that is false). `on_progress` is called on the worker running a
+/// step as its attempts start and fail, `on_complete` on the calling thread
+/// for every finished step, and `on_unrun` for every step that never ran:
+/// skipped because a dependency did not succeed, or cancelled because `token`
+/// stopped before it could start. However many threads nested bodies add, the
+/// steps themselves wait for one of `slots` before they run.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn run_parallel<F, P, B, C, U>(
+    workflow: &Workflow,
+    mut scheduler: Scheduler,
+    workers: usize,
+    slots: &WorkerSlots,
+    token: &CancellationToken,
+    run_step: F,
+    on_progress: P,
+    mut before_start: B,
+    mut on_complete: C,
+    mut on_unrun: U,
+)
+where
+    F: Fn(&Step, &CancellationToken) -> crate::Result<ProcessResult> + Sync,
+    P: Fn(&Step, Progress) + Sync,
+    B: FnMut(&Step) -> Option<StepStatus>,
+    C: FnMut(&Step, Completion) -> StepStatus,
+    U: FnMut(&Step, StepStatus),
+{
+    let workers = workers.clamp(1, workflow.steps.len());
+    debug!("Running workflow '{}' on {} workers", workflow.name, workers);
+
+    let (job_tx, job_rx) = mpsc::channel::<usize>();
+    let job_rx = Mutex::new(job_rx);
+    let (done_tx, done_rx) = mpsc::channel::<Completion>();
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let job_rx = &job_rx;
+            let done_tx = done_tx.clone();
+            let run_step = &run_step;
+            let on_progress = &on_progress;
+            scope.spawn(move || loop {
+                let job = job_rx.lock().map_err(|_| ()).and_then(|rx| rx.recv().map_err(|_| ()));
+                let index = match job {
+                    Ok(index) => index,
+                    Err(()) => break,
+                };
+                let completion = execute(index, &workflow.steps[index], slots, run_step, on_progress, token);
+                if done_tx.send(completion).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(done_tx);
+
+        loop {
+            if token.is_cancelled() {
+                for index in scheduler.cancel_pending() {
+                    on_unrun(&workflow.steps[index], StepStatus::Cancelled);
+                }
+            }
+            while let Some(index) = scheduler.next_ready() {
+                if let Some(status) = before_start(&workflow.steps[index]) {
+                    for skipped in scheduler.complete(index, status) {
+                        on_unrun(&workflow.steps[skipped], StepStatus::Skipped);
+                    }
+                    continue;
+                }
+                debug!("Queueing step '{}'", workflow.steps[index].id);
+                // Workers only stop once the sender is dropped below
+                let _ = job_tx.send(index);
+            }
+            if scheduler.is_finished() {
+                break;
+            }
+            let completion = match done_rx.recv() {
+                Ok(completion) => completion,
+                Err(_) => break,
+            };
+            let index = completion.index;
+            let status = on_complete(&workflow.steps[index], completion);
+            for skipped in scheduler.complete(index, status) {
+                on_unrun(&workflow.steps[skipped], StepStatus::Skipped);
+            }
+        }
+        drop(job_tx);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    fn workflow(json: &str) -> Workflow {
+        Workflow::from_json(json).unwrap()
+    }
+
+    #[test]
+    fn test_scheduler_releases_dependents_and_skips() {
+        let wf = workflow(
+            r#"{"name": "demo", "steps": [
+                {"id": "a"}, {"id": "b", "depends_on": ["a"]},
+                {"id": "c", "depends_on": ["b"]}, {"id": "d", "depends_on": ["a"]}
+            ]}"#,
+        );
+        let mut scheduler = Scheduler::new(&wf).unwrap();
+        assert_eq!(scheduler.next_ready(), Some(0));
+        assert_eq!(scheduler.next_ready(), None);
+        assert!(scheduler.complete(0, StepStatus::Succeeded).is_empty());
+        assert_eq!(scheduler.next_ready(), Some(1));
+        assert_eq!(scheduler.next_ready(), Some(3));
+        assert_eq!(scheduler.complete(1, StepStatus::Failed), vec![2]);
+        assert!(!scheduler.is_finished());
+        assert!(scheduler.complete(3, StepStatus::Succeeded).is_empty());
+        assert!(scheduler.is_finished());
+    }
+
+    #[test]
+    fn test_trigger_rules() {
+        let wf = workflow(
+            r#"{"name": "demo", "steps": [
+                {"id": "a"}, {"id": "b"},
+                {"id": "all", "depends_on": ["a", "b"]},
+                {"id": "any", "depends_on": ["a", "b"], "trigger": "any_success"},
+                {"id": "none", "depends_on": ["b"], "trigger": "any_success"},
+                {"id": "cleanup", "depends_on": ["all"], "trigger": "always"}
+            ]}"#,
+        );
+        let mut scheduler = Scheduler::new(&wf).unwrap();
+        assert_eq!(scheduler.next_ready(), Some(0));
+        assert_eq!(scheduler.next_ready(), Some(1));
+        assert!(scheduler.complete(0, StepStatus::Succeeded).is_empty());
+        assert_eq!(scheduler.complete(1, StepStatus::Failed), vec![2, 4]);
+        assert_eq!(scheduler.next_ready(), Some(3));
+        assert_eq!(scheduler.next_ready(), Some(5));
+        assert_eq!(scheduler.next_ready(), None);
+    }
+
+    #[test]
+    fn test_resume_skips_completed_steps() {
+        let wf = workflow(
+            r#"{"name": "demo", "steps": [
+                {"id": "a"}, {"id": "b", "depends_on": ["a"]}, {"id": "c", "depends_on": ["b"]}
+            ]}"#,
+        );
+        let completed: HashSet<&str> = ["a", "b"].into_iter().collect();
+        let mut scheduler = Scheduler::resume(&wf, &completed).unwrap();
+        assert_eq!(scheduler.next_ready(), Some(2));
+        assert_eq!(scheduler.next_ready(), None);
+        scheduler.complete(2, StepStatus::Succeeded);
+        assert!(scheduler.is_finished());
+    }
+
+    #[test]
+    fn test_independent_steps_run_concurrently() {
+        let wf = workflow(
+            r#"{"name": "fan-out", "steps": [
+                {"id": "a"}, {"id": "b"}, {"id": "c"}, {"id": "d"},
+                {"id": "join", "depends_on": ["a", "b", "c", "d"]}
+            ]}"#,
+        );
+        let active = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let mut finished = Vec::new();
+        let started = Instant::now();
+
+        run_parallel(
+            &wf,
+            Scheduler::new(&wf).unwrap(),
+            4,
+            &WorkerSlots::new(4, Arc::new(Metrics::new())),
+            &CancellationToken::new(),
+            |_step: &Step, _token: &CancellationToken| {
+                let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(50));
+                active.fetch_sub(1, Ordering::SeqCst);
+                Ok(ProcessResult { success: true, message: String::new(), data: None, timed_out: false })
+            },
+            |_, _| {},
+            |_| None,
+            |step, _| {
+                finished.push(step.id.clone());
+                StepStatus::Succeeded
+            },
+            |_, _| {},
+        );
+
+        assert_eq!(finished.len(), 5);
+        assert_eq!(finished.last().map(String::as_str), Some("join"));
+        assert!(peak.load(Ordering::SeqCst) > 1);
+        assert!(started.elapsed() < Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_worker_limit_is_respected() {
+        let wf = workflow(r#"{"name": "w", "steps": [{"id": "a"}, {"id": "b"}, {"id": "c"}, {"id": "d"}]}"#);
+        let active = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        run_parallel(
+            &wf,
+            Scheduler::new(&wf).unwrap(),
+            2,
+            &WorkerSlots::new(2, Arc::new(Metrics::new())),
+            &CancellationToken::new(),
+            |_step: &Step, _token: &CancellationToken| {
+                let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(20));
+                active.fetch_sub(1, Ordering::SeqCst);
+                Ok(ProcessResult { success: true, message: String::new(), data: None, timed_out: false })
+            },
+            |_, _| {},
+            |_| None,
+            |_, _| StepStatus::Succeeded,
+            |_, _| {},
+        );
+        assert!(peak.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn test_slots_bound_steps_across_pools() {
+        let wf = workflow(r#"{"name": "w", "steps": [{"id": "a"}, {"id": "b"}, {"id": "c"}, {"id": "d"}]}"#);
+        let metrics = Arc::new(Metrics::new());
+        let slots = WorkerSlots::new(2, Arc::clone(&metrics));
+        let active = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let run_step = |_step: &Step, _token: &CancellationToken| {
+            let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            active.fetch_sub(1, Ordering::SeqCst);
+            Ok(ProcessResult { success: true, message: String::new(), data: None, timed_out: false })
+        };
+        // Two pools of four workers each, as nested bodies would start
+        thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    let token = CancellationToken::new();
+                    run_parallel(
+                        &wf,
+                        Scheduler::new(&wf).unwrap(),
+                        4,
+                        &slots,
+                        &token,
+                        run_step,
+                        |_, _| {},
+                        |_| None,
+                        |_, _| StepStatus::Succeeded,
+                        |_, _| {},
+                    );
+                });
+            }
+        });
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert_eq!(metrics.busy_workers(), 0);
+        assert_eq!(metrics.queue_depth(), 0);
+    }
+
+    #[test]
+    fn test_waiting_for_a_slot_stops_with_the_token() {
+        let wf = workflow(r#"{"name": "w", "steps": [{"id": "a"}]}"#);
+        let slots = WorkerSlots::new(1, Arc::new(Metrics::new()));
+        let _taken = slots.acquire(&CancellationToken::new()).unwrap();
+        let token = CancellationToken::new();
+        token.cancel();
+        let run_step = |_: &Step, _: &CancellationToken| -> crate::Result<ProcessResult> { panic!("ran without a slot") };
+        let completion = execute(0, &wf.steps[0], &slots, &run_step, &|_: &Step, _: Progress| {}, &token);
+        assert_eq!(completion.interrupted, Some(CancelReason::Cancelled));
+        assert!(!completion.attempts[0].result.success);
+    }
+
+    #[test]
+    fn test_panicking_step_fails_without_hanging() {
+        let wf = workflow(r#"{"name": "p", "steps": [{"id": "boom"}, {"id": "after", "depends_on": ["boom"]}]}"#);
+        let mut skipped = Vec::new();
+        run_parallel(
+            &wf,
+            Scheduler::new(&wf).unwrap(),
+            2,
+            &WorkerSlots::new(2, Arc::new(Metrics::new())),
+            &CancellationToken::new(),
+            |_step: &Step, _token: &CancellationToken| -> crate::Result<ProcessResult> { panic!("step exploded") },
+            |_, _| {},
+            |_| None,
+            |_, completion| {
+                assert!(!completion.attempts[0].result.success);
+                StepStatus::Failed
+            },
+            |step, _| skipped.push(step.id.clone()),
+        );
+        assert_eq!(skipped, vec!["after".to_string()]);
+    }
+}
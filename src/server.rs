@@ -0,0 +1,956 @@
+// src/server.rs
+/*
+ * HTTP API for submitting, inspecting and cancelling runs
+ */
+
+use crate::cancel::CancellationToken;
+use crate::events::{EventBroadcast, RunEvent};
+use crate::graph;
+use crate::http::{self, opcode, Request, Response};
+use crate::state::{RunState, RunStore};
+use crate::workflow::Workflow;
+use crate::{Result, WorkflowEngineProcessor};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, ErrorKind, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How long a client may take to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the accept loop and event streams check whether the server
+/// should stop
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+/// The web UI, a single page using the API below
+const UI: &str = include_str!("ui.html");
+
+/// How long an event stream stays quiet before the server checks that the
+/// client is still there
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// How long a WebSocket client has to answer the server closing the stream
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Serves the run API over HTTP:
+///
+/// | Request | Response |
+/// |---------|----------|
+/// | `GET /` | a web UI listing runs and showing their DAG and step results |
+/// | `POST /runs` | starts a run, `202` with its id |
+/// | `GET /runs` | runs recorded in the store, most recent first |
+/// | `GET /runs/<id>` | status, step results and stages of a run |
+/// | `POST /runs/<id>/cancel` | cancels a run in progress |
+/// | `GET /stats` | [`WorkflowEngineProcessor::get_stats`] |
+/// | `GET /metrics` | the processor's [`Metrics`](crate::Metrics) for Prometheus |
+/// | `GET /events` | every [`RunEvent`] as it happens; `?run=<id>` for one run |
+/// | `GET /runs/<id>/events` | the events of one run, until it finishes |
+///
+/// Event streams are Server-Sent Events, or WebSocket text frames when the
+/// request asks to upgrade. Runs are journaled in the [`RunStore`], so runs
+/// from earlier servers and from the CLI can be inspected too.
+///
+/// Web pages must not drive the API from a browser: requests whose `Host` or
+/// `Origin` names another server than the one they reached get `403`, `POST`
+/// requests need `Content-Type: application/json`, and workflow definitions
+/// can only be submitted inline after [`ApiServer::with_allow_inline`].
+#[derive(Debug)]
+pub struct ApiServer {
+    processor: Arc<WorkflowEngineProcessor>,
+    store: RunStore,
+    events: Arc<EventBroadcast>,
+    /// Whether `POST /runs` takes a `workflow` definition, not just a `name`
+    allow_inline: bool,
+    active: Mutex<HashMap<String, ActiveRun>>,
+    runs: Mutex<Vec<JoinHandle<()>>>,
+}
+
+#[derive(Debug)]
+struct ActiveRun {
+    workflow: String,
+    started_at: DateTime<Utc>,
+    token: CancellationToken,
+}
+
+/// Body of `POST /runs`: a workflow definition or the name of one, and inputs
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Submission {
+    #[serde(default)]
+    workflow: Option<serde_json::Value>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    inputs: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ApiServer {
+    /// A server running workflows on `processor`, which it makes journal
+    /// every run in `store`
+    pub fn new(processor: WorkflowEngineProcessor, store: RunStore) -> Self {
+        let events = Arc::new(EventBroadcast::new());
+        let processor = processor.with_event_sink(Arc::new(store.clone())).with_event_sink(events.clone());
+        Self {
+            processor: Arc::new(processor),
+            store,
+            events,
+            allow_inline: false,
+            active: Mutex::new(HashMap::new()),
+            runs: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Lets `POST /runs` start workflows defined in the request, which can
+    /// run any command; by default only workflows of the library, by `name`
+    pub fn with_allow_inline(mut self, allow: bool) -> Self {
+        self.allow_inline = allow;
+        self
+    }
+
+    /// Answers requests on `listener` until `token` is cancelled, then waits
+    /// for the runs in progress, which are cancelled along with it.
+    pub fn serve(self: &Arc<Self>, listener: TcpListener, token: &CancellationToken) -> Result<()> {
+        listener.set_nonblocking(true)?;
+        if let Ok(address) = listener.local_addr() {
+            info!("Serving the run API on http://{}", address);
+        }
+        while !token.is_cancelled() {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    let server = Arc::clone(self);
+                    let token = token.clone();
+                    let spawned = thread::Builder::new()
+                        .name("http".to_string())
+                        .spawn(move || server.answer(stream, peer, &token));
+                    if let Err(e) = spawned {
+                        error!("Could not answer {}: {}", peer, e);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    token.sleep(ACCEPT_POLL);
+                }
+                Err(e) => warn!("Could not accept a connection: {}", e),
+            }
+        }
+        info!("Server stopping; waiting for {} runs in progress", self.active.lock().unwrap().len());
+        let runs: Vec<JoinHandle<()>> = self.runs.lock().unwrap().drain(..).collect();
+        for run in runs {
+            let _ = run.join();
+        }
+        Ok(())
+    }
+
+    fn answer(self: &Arc<Self>, stream: TcpStream, peer: SocketAddr, token: &CancellationToken) {
+        let _ = stream.set_nonblocking(false);
+        let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+        let (mut writer, local) = match (stream.try_clone(), stream.local_addr()) {
+            (Ok(writer), Ok(local)) => (writer, local),
+            (Err(e), _) | (_, Err(e)) => return warn!("Could not answer {}: {}", peer, e),
+        };
+        let response = match Request::read(&mut BufReader::new(stream)) {
+            Ok(request) => {
+                debug!("{} {} from {}", request.method, request.path, peer);
+                let run = match (request.method.as_str(), request.segments().as_slice()) {
+                    ("GET", ["events"]) => Some(request.query("run").map(str::to_string)),
+                    ("GET", ["runs", run_id, "events"]) => Some(Some(run_id.to_string())),
+                    _ => None,
+                };
+                match (foreign(&request, local), run) {
+                    (Some(refusal), _) => {
+                        warn!("Refusing {} {} from {}: not addressed to {}", request.method, request.path, peer, local);
+                        refusal
+                    }
+                    (None, Some(run)) => match self.stream_events(&request, &mut writer, run, token) {
+                        Ok(None) => return,
+                        Ok(Some(response)) => response,
+                        Err(e) => return debug!("Event stream to {} ended: {}", peer, e),
+                    },
+                    (None, None) => self.handle(&request, token),
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::InvalidData => Response::error(400, &e.to_string()),
+            Err(e) => return debug!("Dropping connection from {}: {}", peer, e),
+        };
+        if let Err(e) = response.write_to(&mut writer) {
+            debug!("Could not respond to {}: {}", peer, e);
+        }
+    }
+
+    /// Answers one request; runs it starts stop when `token` is cancelled
+    pub fn handle(self: &Arc<Self>, request: &Request, token: &CancellationToken) -> Response {
+        let method = request.method.as_str();
+        match (method, request.segments().as_slice()) {
+            ("GET", []) => Response::new(200, "text/html; charset=utf-8", UI),
+            // Browsers cannot send this cross-origin without asking first
+            ("POST", ["runs"] | ["runs", _, "cancel"]) if !is_json(request) => {
+                Response::error(415, "send the request with Content-Type: application/json")
+            }
+            ("POST", ["runs"]) => self.submit(request, token),
+            ("GET", ["runs"]) => self.list(request),
+            ("GET", ["runs", run_id]) => self.run(run_id),
+            ("POST", ["runs", run_id, "cancel"]) => self.cancel(run_id),
+            ("GET", ["stats"]) => self.stats(),
+            ("GET", ["metrics"]) => {
+                Response::new(200, "text/plain; version=0.0.4; charset=utf-8", self.processor.metrics().render())
+            }
+            (_, ["runs"]) => not_allowed("GET, POST"),
+            (_, []) | (_, ["runs", _]) | (_, ["stats"]) | (_, ["metrics"]) => not_allowed("GET"),
+            (_, ["runs", _, "cancel"]) => not_allowed("POST"),
+            (_, ["events"]) | (_, ["runs", _, "events"]) => not_allowed("GET"),
+            _ => Response::error(404, &format!("no such endpoint: {}", request.path)),
+        }
+    }
+
+    fn submit(self: &Arc<Self>, request: &Request, token: &CancellationToken) -> Response {
+        let submission: Submission = match serde_json::from_slice(&request.body) {
+            Ok(submission) => submission,
+            Err(e) => return Response::error(400, &format!("invalid request body: {}", e)),
+        };
+        let workflow = match (submission.workflow, submission.name) {
+            (Some(_), None) if !self.allow_inline => {
+                return Response::error(403, "inline workflows are disabled; submit a workflow of the library by `name`")
+            }
+            (Some(definition), None) => match Workflow::from_json(&definition.to_string()) {
+                Ok(workflow) => workflow,
+                Err(e) => return Response::error(422, &e.full_message()),
+            },
+            (None, Some(name)) => match self.processor.find_workflow(&name) {
+                Ok(workflow) => workflow,
+                Err(Some(e)) => return Response::error(422, &e.full_message()),
+                Err(None) => {
+                    return Response::error(404, &format!("no workflow named '{}' is registered or in the library", name))
+                }
+            },
+            _ => return Response::error(400, "give either `workflow`, a definition, or `name`"),
+        };
+        if let Err(e) = workflow.resolve_inputs(&submission.inputs) {
+            return Response::error(422, &format!("invalid inputs for workflow '{}': {}", workflow.name, e));
+        }
+
+        let run_id = self.processor.new_run_id();
+        let token = token.child(None);
+        let started_at = Utc::now();
+        self.active.lock().unwrap().insert(
+            run_id.clone(),
+            ActiveRun { workflow: workflow.name.clone(), started_at, token: token.clone() },
+        );
+        let server = Arc::clone(self);
+        let id = run_id.clone();
+        let name = workflow.name.clone();
+        let spawned = thread::Builder::new().name(format!("run-{}", run_id)).spawn(move || {
+            match server.processor.run_workflow_as(&id, &workflow, &submission.inputs, &token) {
+                Ok(result) => info!("Run {} of workflow '{}' finished: {}", id, workflow.name, result.status),
+                Err(e) => error!("Run {} of workflow '{}' failed: {}", id, workflow.name, e.full_message()),
+            }
+            server.active.lock().unwrap().remove(&id);
+        });
+        match spawned {
+            Ok(run) => {
+                let mut runs = self.runs.lock().unwrap();
+                runs.retain(|run| !run.is_finished());
+                runs.push(run);
+            }
+            Err(e) => {
+                self.active.lock().unwrap().remove(&run_id);
+                return Response::error(503, &format!("cannot start run: {}", e));
+            }
+        }
+        info!("Started run {} of workflow '{}'", run_id, name);
+        let location = format!("/runs/{}", run_id);
+        Response::json(
+            202,
+            &serde_json::json!({ "run_id": run_id, "workflow": name, "status": "running", "url": location }),
+        )
+        .with_header("Location", &location)
+    }
+
+    fn list(&self, request: &Request) -> Response {
+        let limit = match request.query("limit").map(str::parse::<usize>) {
+            Some(Ok(limit)) => limit,
+            Some(Err(_)) => return Response::error(400, "limit must be a number"),
+            None => usize::MAX,
+        };
+        let runs = match self.store.list() {
+            Ok(runs) => runs,
+            Err(e) => return Response::error(500, &e.full_message()),
+        };
+        let summaries: Vec<serde_json::Value> = runs
+            .iter()
+            .filter(|run| request.query("workflow").is_none_or(|name| run.workflow.name == name))
+            .map(|run| self.summary(run))
+            .filter(|summary| request.query("status").is_none_or(|status| summary["status"] == status))
+            .take(limit)
+            .collect();
+        Response::json(200, &summaries)
+    }
+
+    fn run(&self, run_id: &str) -> Response {
+        if !valid_run_id(run_id) {
+            return Response::error(404, &format!("no run '{}'", run_id));
+        }
+        if let Ok(state) = self.store.load(run_id) {
+            let mut run = self.summary(&state);
+            run["parent"] = serde_json::json!(state.parent);
+            run["steps"] = serde_json::json!(state.steps);
+            run["outputs"] = serde_json::Value::Object(state.outputs);
+            run["graph"] = stages(&state.workflow);
+            return Response::json(200, &run);
+        }
+        match self.active.lock().unwrap().get(run_id) {
+            // Started, but the run has not journaled anything yet
+            Some(run) => Response::json(
+                200,
+                &serde_json::json!({
+                    "run_id": run_id,
+                    "workflow": run.workflow,
+                    "status": "running",
+                    "started_at": run.started_at,
+                    "steps": [],
+                }),
+            ),
+            None => Response::error(404, &format!("no run '{}'", run_id)),
+        }
+    }
+
+    fn cancel(&self, run_id: &str) -> Response {
+        if let Some(run) = self.active.lock().unwrap().get(run_id) {
+            info!("Cancelling run {} on request", run_id);
+            run.token.cancel();
+            return Response::json(202, &serde_json::json!({ "run_id": run_id, "status": "cancelling" }));
+        }
+        if valid_run_id(run_id) && self.store.load(run_id).is_ok() {
+            return Response::error(409, &format!("run '{}' is not in progress on this server", run_id));
+        }
+        Response::error(404, &format!("no run '{}'", run_id))
+    }
+
+    // Writes events to `writer` as they happen, only those of `run` if given,
+    // until the client goes away or `token` stops. The events `run` already
+    // journaled are replayed first; when it is not in progress, that is all
+    // there is. Returns the response to send instead when there is nothing
+    // to stream.
+    fn stream_events(
+        &self,
+        request: &Request,
+        writer: &mut TcpStream,
+        run: Option<String>,
+        token: &CancellationToken,
+    ) -> std::io::Result<Option<Response>> {
+        // Subscribing before reading the journal leaves no gap between the
+        // two: the store journals each event before it is broadcast.
+        let events = self.events.subscribe();
+        let mut replay = Vec::new();
+        let mut live = true;
+        if let Some(run_id) = &run {
+            live = self.active.lock().unwrap().contains_key(run_id);
+            match valid_run_id(run_id).then(|| self.store.events(run_id)) {
+                Some(Ok(events)) => replay = events,
+                // A run that is just starting may not have a journal yet
+                _ if live => {}
+                _ => return Ok(Some(Response::error(404, &format!("no run '{}'", run_id)))),
+            }
+        }
+
+        let websocket = request.header("upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+        if websocket {
+            if request.header("sec-websocket-version") != Some("13") {
+                let response = Response::error(426, "only WebSocket version 13 is supported");
+                return Ok(Some(response.with_header("Sec-WebSocket-Version", "13")));
+            }
+            let Some(key) = request.header("sec-websocket-key") else {
+                return Ok(Some(Response::error(400, "missing Sec-WebSocket-Key")));
+            };
+            write!(
+                writer,
+                "HTTP/1.1 101 {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                http::reason(101),
+                http::websocket_accept(key)
+            )?;
+        } else {
+            write!(
+                writer,
+                "HTTP/1.1 200 {}\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+                http::reason(200)
+            )?;
+        }
+        writer.flush()?;
+        // Stops the thread reading client frames once the stream is done
+        let _shutdown = match websocket {
+            true => Some(ShutdownOnDrop(writer.try_clone()?)),
+            false => None,
+        };
+        let frames = match websocket {
+            true => Some(client_frames(writer)?),
+            false => None,
+        };
+        let mut stream = EventStream { writer, frames, closed: false };
+
+        // Events broadcast while the journal was read arrive again live
+        let mut replayed = HashSet::new();
+        for event in &replay {
+            stream.send(event)?;
+            replayed.insert(serde_json::to_string(event)?);
+            live &= !matches!(event, RunEvent::RunFinished { .. });
+        }
+        let mut quiet_since = Instant::now();
+        let mut ended = false;
+        while live && !token.is_cancelled() && stream.answer_client()? {
+            match events.recv_timeout(ACCEPT_POLL) {
+                Ok(event) if run.as_ref().is_none_or(|run_id| event.run_id() == run_id) => {
+                    if !replayed.is_empty() && replayed.remove(&serde_json::to_string(&event)?) {
+                        continue;
+                    }
+                    stream.send(&event)?;
+                    quiet_since = Instant::now();
+                    if run.is_some() && matches!(event, RunEvent::RunFinished { .. }) {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                // A run that failed to start ends without `run_finished`; what
+                // it broadcast before it ended is drained first
+                Err(RecvTimeoutError::Timeout) if ended => break,
+                Err(RecvTimeoutError::Timeout)
+                    if run.as_ref().is_some_and(|run_id| !self.active.lock().unwrap().contains_key(run_id)) =>
+                {
+                    ended = true;
+                }
+                Err(RecvTimeoutError::Timeout) if quiet_since.elapsed() >= KEEP_ALIVE => {
+                    stream.keep_alive()?;
+                    quiet_since = Instant::now();
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                // Dropped for falling behind
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        stream.close()?;
+        Ok(None)
+    }
+
+    fn stats(&self) -> Response {
+        let mut stats = self.processor.get_stats();
+        stats["active_runs"] = serde_json::json!(self.active.lock().unwrap().len());
+        stats["event_subscribers"] = serde_json::json!(self.events.subscribers());
+        Response::json(200, &stats)
+    }
+
+    // What lists of runs show about a run
+    fn summary(&self, run: &RunState) -> serde_json::Value {
+        let status = match self.active.lock().unwrap().contains_key(&run.run_id) {
+            true => "running".to_string(),
+            false => run.status_label(),
+        };
+        let duration_ms = run.finished_at.map(|finished| (finished - run.started_at).num_milliseconds().max(0));
+        serde_json::json!({
+            "run_id": run.run_id,
+            "workflow": run.workflow.name,
+            "status": status,
+            "started_at": run.started_at,
+            "finished_at": run.finished_at,
+            "duration_ms": duration_ms,
+            "steps_succeeded": run.completed_steps().len(),
+            "steps_total": run.workflow.recorded_steps().len(),
+        })
+    }
+}
+
+// The steps of `workflow` by stage, for drawing its DAG
+fn stages(workflow: &Workflow) -> serde_json::Value {
+    let Ok(stages) = graph::stages(workflow) else {
+        return serde_json::Value::Null;
+    };
+    let stages: Vec<Vec<serde_json::Value>> = stages
+        .iter()
+        .map(|stage| {
+            stage
+                .iter()
+                .map(|&index| {
+                    let step = &workflow.steps[index];
+                    serde_json::json!({ "id": step.id, "kind": step.action.kind(), "depends_on": step.depends_on })
+                })
+                .collect()
+        })
+        .collect();
+    serde_json::json!(stages)
+}
+
+// One event per Server-Sent Event, or per WebSocket text frame
+struct EventStream<'a, W: Write> {
+    writer: &'a mut W,
+    /// What a WebSocket client sends; `None` for Server-Sent Events
+    frames: Option<mpsc::Receiver<(u8, Vec<u8>)>>,
+    /// Set once a close frame was sent
+    closed: bool,
+}
+
+impl<W: Write> EventStream<'_, W> {
+    fn websocket(&self) -> bool {
+        self.frames.is_some()
+    }
+
+    fn send(&mut self, event: &RunEvent) -> std::io::Result<()> {
+        let json = serde_json::to_string(event)?;
+        if self.websocket() {
+            self.writer.write_all(&http::websocket_frame(opcode::TEXT, json.as_bytes()))?;
+        } else {
+            write!(self.writer, "event: {}\ndata: {}\n\n", event.name(), json)?;
+        }
+        self.writer.flush()
+    }
+
+    // Answers the pings and close of a WebSocket client. False once the
+    // client closed the stream or went away.
+    fn answer_client(&mut self) -> std::io::Result<bool> {
+        let Some(frames) = &self.frames else {
+            return Ok(true);
+        };
+        loop {
+            match frames.try_recv() {
+                Ok((opcode::PING, payload)) => {
+                    self.writer.write_all(&http::websocket_frame(opcode::PONG, &payload))?;
+                    self.writer.flush()?;
+                }
+                Ok((opcode::CLOSE, payload)) => {
+                    // Echoes the status code, if the client gave one
+                    let status = payload.get(..2).unwrap_or_default();
+                    self.writer.write_all(&http::websocket_frame(opcode::CLOSE, status))?;
+                    self.closed = true;
+                    self.writer.flush()?;
+                    return Ok(false);
+                }
+                Ok(_) => {}
+                Err(TryRecvError::Empty) => return Ok(true),
+                Err(TryRecvError::Disconnected) => return Ok(false),
+            }
+        }
+    }
+
+    fn keep_alive(&mut self) -> std::io::Result<()> {
+        if self.websocket() {
+            self.writer.write_all(&http::websocket_frame(opcode::PING, &[]))?;
+        } else {
+            self.writer.write_all(b": keep-alive\n\n")?;
+        }
+        self.writer.flush()
+    }
+
+    // Ends the stream; a WebSocket client gets a close frame and a moment
+    // to answer it
+    fn close(&mut self) -> std::io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        if let Some(frames) = &self.frames {
+            self.writer.write_all(&http::websocket_frame(opcode::CLOSE, &[]))?;
+            self.writer.flush()?;
+            self.closed = true;
+            let deadline = Instant::now() + CLOSE_TIMEOUT;
+            while let Ok((code, _)) = frames.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                if code == opcode::CLOSE {
+                    break;
+                }
+            }
+        }
+        self.writer.flush()
+    }
+}
+
+// Reads what a WebSocket client sends on a thread of its own, until the
+// client goes away or the connection is shut down
+fn client_frames(stream: &TcpStream) -> std::io::Result<mpsc::Receiver<(u8, Vec<u8>)>> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    // The stream may stay quiet for as long as the client likes
+    reader.get_ref().set_read_timeout(None)?;
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new().name("websocket".to_string()).spawn(move || {
+        while let Ok(frame) = http::read_websocket_frame(&mut reader) {
+            if tx.send(frame).is_err() {
+                break;
+            }
+        }
+    })?;
+    Ok(rx)
+}
+
+struct ShutdownOnDrop(TcpStream);
+
+impl Drop for ShutdownOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.shutdown(Shutdown::Both);
+    }
+}
+
+// The refusal for a request that names another server in its `Host` or
+// `Origin` than `local`, the address it came in on, as a page of another
+// site would when a browser sends it to us, e.g. after DNS rebinding
+fn foreign(request: &Request, local: SocketAddr) -> Option<Response> {
+    let host_ok = request.header("host").is_some_and(|host| names(host, local));
+    let origin_ok = request.header("origin").is_none_or(|origin| {
+        origin.strip_prefix("http://").is_some_and(|host| names(host, local))
+    });
+    match (host_ok, origin_ok) {
+        (true, true) => None,
+        (false, _) => Some(Response::error(403, &format!("Host must be {}", local))),
+        (true, false) => Some(Response::error(403, "cross-origin requests are not allowed")),
+    }
+}
+
+// Whether `host`, as in a `Host` header, is `local` or, for a loopback
+// address, `localhost` on its port
+fn names(host: &str, local: SocketAddr) -> bool {
+    let (name, port) = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => (name, port.parse::<u16>().ok()),
+        _ => (host, Some(80)),
+    };
+    let name_ok = match name.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => ip == local.ip(),
+        Err(_) => name.eq_ignore_ascii_case("localhost") && local.ip().is_loopback(),
+    };
+    name_ok && port == Some(local.port())
+}
+
+fn is_json(request: &Request) -> bool {
+    request.header("content-type").is_some_and(|content_type| {
+        let media_type = content_type.split(';').next().unwrap_or_default();
+        media_type.trim().eq_ignore_ascii_case("application/json")
+    })
+}
+
+fn not_allowed(allow: &str) -> Response {
+    Response::error(405, "method not allowed").with_header("Allow", allow)
+}
+
+// Run ids name journal files, so nothing that could leave the store
+fn valid_run_id(run_id: &str) -> bool {
+    !run_id.is_empty() && run_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, Read, Write};
+
+    fn start(dir: &std::path::Path) -> (SocketAddr, CancellationToken, JoinHandle<()>) {
+        start_with(ApiServer::new(WorkflowEngineProcessor::new(false), RunStore::new(dir)).with_allow_inline(true))
+    }
+
+    fn start_with(server: ApiServer) -> (SocketAddr, CancellationToken, JoinHandle<()>) {
+        let server = Arc::new(server);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let token = CancellationToken::new();
+        let serving = token.clone();
+        let handle = thread::spawn(move || server.serve(listener, &serving).unwrap());
+        (address, token, handle)
+    }
+
+    fn call(address: SocketAddr, method: &str, path: &str, body: &str) -> (u16, serde_json::Value) {
+        send(address, method, path, &format!("Host: {}\r\nContent-Type: application/json\r\n", address), body)
+    }
+
+    // Sends a request with exactly the given `headers`, each ending in CRLF
+    fn send(address: SocketAddr, method: &str, path: &str, headers: &str, body: &str) -> (u16, serde_json::Value) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}", method, path, headers, body.len(), body)
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    // Opens an event stream and returns its status line once the server answered
+    fn subscribe(address: SocketAddr, path: &str, headers: &str) -> (String, BufReader<TcpStream>) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n{}\r\n", path, address, headers).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+        (status.trim_end().to_string(), reader)
+    }
+
+    fn wait_for(address: SocketAddr, run_id: &str, status: &str) -> serde_json::Value {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let (_, run) = call(address, "GET", &format!("/runs/{}", run_id), "");
+            if run["status"] == status || Instant::now() > deadline {
+                return run;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn test_submit_and_inspect_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let (address, token, server) = start(dir.path());
+
+        let body = r#"{
+            "workflow": {"name": "greet", "inputs": {"who": "world"}, "outputs": {"who": "${{ inputs.who }}"},
+                         "steps": [{"id": "a"}, {"id": "b", "depends_on": ["a"]},
+                                   {"id": "check", "kind": "if", "condition": "true", "depends_on": ["b"],
+                                    "then": [{"id": "c"}]}]},
+            "inputs": {"who": "api"}
+        }"#;
+        let (status, submitted) = call(address, "POST", "/runs", body);
+        assert_eq!(status, 202);
+        assert_eq!(submitted["status"], "running");
+        let run_id = submitted["run_id"].as_str().unwrap().to_string();
+
+        let run = wait_for(address, &run_id, "succeeded");
+        assert_eq!(run["status"], "succeeded");
+        assert_eq!(run["workflow"], "greet");
+        assert_eq!(run["outputs"]["who"], "api");
+        let steps: Vec<&str> = run["steps"].as_array().unwrap().iter().map(|step| step["id"].as_str().unwrap()).collect();
+        assert_eq!(steps, vec!["a", "b", "c", "check"]);
+        assert_eq!(run["steps"][0]["result"]["success"], true);
+        assert_eq!(run["graph"][1][0], serde_json::json!({ "id": "b", "kind": "process", "depends_on": ["a"] }));
+
+        let (status, runs) = call(address, "GET", "/runs?workflow=greet&limit=5", "");
+        assert_eq!((status, runs[0]["run_id"].as_str()), (200, Some(run_id.as_str())));
+        assert_eq!((runs[0]["steps_succeeded"].as_u64(), runs[0]["steps_total"].as_u64()), (Some(4), Some(4)));
+        let (_, stats) = call(address, "GET", "/stats", "");
+        assert_eq!((stats["processed_count"].as_u64(), stats["active_runs"].as_u64()), (Some(3), Some(0)));
+        assert_eq!(call(address, "POST", &format!("/runs/{}/cancel", run_id), "").0, 409);
+
+        let mut page = TcpStream::connect(address).unwrap();
+        write!(page, "GET / HTTP/1.1\r\nHost: localhost:{}\r\n\r\n", address.port()).unwrap();
+        let mut html = String::new();
+        page.read_to_string(&mut html).unwrap();
+        assert!(html.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/html"));
+        assert!(html.contains("<title>workflowengine</title>"));
+
+        let mut scrape = TcpStream::connect(address).unwrap();
+        write!(scrape, "GET /metrics HTTP/1.1\r\nHost: {}\r\n\r\n", address).unwrap();
+        let mut metrics = String::new();
+        scrape.read_to_string(&mut metrics).unwrap();
+        assert!(metrics.contains("\r\nContent-Type: text/plain; version=0.0.4"));
+        assert!(metrics.contains("\nworkflowengine_runs_finished_total{workflow=\"greet\",status=\"succeeded\"} 1\n"));
+        assert!(metrics.contains("\nworkflowengine_step_duration_seconds_count{workflow=\"greet\",status=\"succeeded\"} 4\n"));
+
+        token.cancel();
+        server.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cancel_a_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let (address, token, server) = start(dir.path());
+
+        let body = r#"{"workflow": {"name": "slow", "steps": [{"id": "wait", "kind": "command", "program": "sleep", "args": ["10"]}]}}"#;
+        let (_, submitted) = call(address, "POST", "/runs", body);
+        let run_id = submitted["run_id"].as_str().unwrap().to_string();
+        wait_for(address, &run_id, "running");
+        let (status, cancelled) = call(address, "POST", &format!("/runs/{}/cancel", run_id), "");
+        assert_eq!((status, cancelled["status"].as_str()), (202, Some("cancelling")));
+        assert_eq!(wait_for(address, &run_id, "cancelled")["steps"][0]["status"], "cancelled");
+
+        token.cancel();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let (address, token, server) = start(dir.path());
+
+        let (status, error) = call(address, "POST", "/runs", r#"{"workflow": {"name": "empty", "steps": []}}"#);
+        assert_eq!((status, error["error"].as_str()), (422, Some("invalid workflow: workflow has no steps")));
+        let (status, error) = call(address, "POST", "/runs", r#"{"name": "deploy"}"#);
+        assert_eq!((status, error["error"].as_str()), (404, Some("no workflow named 'deploy' is registered or in the library")));
+        let body = r#"{"workflow": {"name": "w", "inputs": {"n": 1}, "steps": [{"id": "a"}]}, "inputs": {"n": "x"}}"#;
+        let (status, error) = call(address, "POST", "/runs", body);
+        assert_eq!(
+            (status, error["error"].as_str()),
+            (422, Some("invalid inputs for workflow 'w': input 'n': must be number, got string"))
+        );
+        assert_eq!(call(address, "POST", "/runs", "not json").0, 400);
+        assert_eq!(call(address, "GET", "/runs/..%2F..%2Fetc", "").0, 404);
+        assert_eq!(call(address, "DELETE", "/runs", "").0, 405);
+        assert_eq!(call(address, "GET", "/nothing", "").0, 404);
+        assert_eq!(call(address, "POST", "/", "").0, 405);
+
+        token.cancel();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_requests_other_sites_could_send_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = ApiServer::new(WorkflowEngineProcessor::new(false), RunStore::new(dir.path()));
+        let (address, token, server) = start_with(server);
+        let host = format!("Host: {}\r\n", address);
+        let inline = r#"{"workflow": {"name": "w", "steps": [{"id": "a"}]}}"#;
+
+        let (status, error) = call(address, "POST", "/runs", inline);
+        assert_eq!(
+            (status, error["error"].as_str()),
+            (403, Some("inline workflows are disabled; submit a workflow of the library by `name`"))
+        );
+        assert_eq!(send(address, "POST", "/runs", &host, inline).0, 415);
+        let form = format!("{}Content-Type: text/plain\r\n", host);
+        assert_eq!(send(address, "POST", "/runs/r1/cancel", &form, "").0, 415);
+        let json = format!("{}Content-Type: Application/JSON; charset=utf-8\r\n", host);
+        assert_eq!(send(address, "POST", "/runs", &json, r#"{"name": "deploy"}"#).0, 404);
+
+        // A page of another site, reaching the server by DNS rebinding or by its address
+        let rebound = format!("Host: evil.example:{}\r\n", address.port());
+        assert_eq!(send(address, "GET", "/runs", &rebound, "").0, 403);
+        assert_eq!(send(address, "GET", "/runs", "", "").0, 403);
+        assert_eq!(send(address, "GET", "/runs", &format!("{}Origin: http://evil.example\r\n", host), "").0, 403);
+        assert_eq!(send(address, "GET", "/runs", &format!("{}Origin: null\r\n", host), "").0, 403);
+        let (status, _) = subscribe(address, "/events", "Origin: https://evil.example\r\n");
+        assert_eq!(status, "HTTP/1.1 403 Forbidden");
+
+        assert_eq!(send(address, "GET", "/runs", &format!("{}Origin: http://{}\r\n", host, address), "").0, 200);
+        assert_eq!(send(address, "GET", "/runs", &format!("Host: localhost:{}\r\n", address.port()), "").0, 200);
+        assert_eq!(send(address, "GET", "/runs", "Host: localhost:1\r\n", "").0, 403);
+
+        token.cancel();
+        server.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_event_streams() {
+        let dir = tempfile::tempdir().unwrap();
+        let (address, token, server) = start(dir.path());
+        let (status, mut events) = subscribe(address, "/events", "");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+
+        let body = r#"{"workflow": {"name": "flaky", "steps": [
+            {"id": "f", "kind": "command", "program": "false", "retry": {"max_attempts": 2, "initial_delay_ms": 1}}
+        ]}}"#;
+        let (_, submitted) = call(address, "POST", "/runs", body);
+        let run_id = submitted["run_id"].as_str().unwrap().to_string();
+        let mut names = Vec::new();
+        let mut line = String::new();
+        while names.last().is_none_or(|name| name != "run_finished") {
+            line.clear();
+            events.read_line(&mut line).unwrap();
+            if let Some(name) = line.strip_prefix("event: ") {
+                names.push(name.trim().to_string());
+            }
+        }
+        assert_eq!(
+            names,
+            vec!["run_started", "step_started", "retry_scheduled", "step_started", "step_finished", "run_finished"]
+        );
+
+        // A finished run is replayed from its journal, then the stream ends
+        let (_, mut replay) = subscribe(address, &format!("/runs/{}/events", run_id), "");
+        let mut text = String::new();
+        replay.read_to_string(&mut text).unwrap();
+        let names: Vec<&str> = text.lines().filter_map(|line| line.strip_prefix("event: ")).collect();
+        assert_eq!(
+            names,
+            vec!["run_started", "step_started", "retry_scheduled", "step_started", "step_finished", "run_finished"]
+        );
+        assert_eq!(call(address, "GET", "/runs/nope/events", "").0, 404);
+
+        let upgrade = "Upgrade: websocket\r\nConnection: Upgrade\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n";
+        let (status, mut socket) = subscribe(address, "/events", upgrade);
+        assert_eq!(status, "HTTP/1.1 101 Switching Protocols");
+        call(address, "POST", "/runs", r#"{"workflow": {"name": "quick", "steps": [{"id": "a"}]}}"#);
+        let mut head = [0u8; 2];
+        socket.read_exact(&mut head).unwrap();
+        assert_eq!(head[0], 0x81);
+        let length = match head[1] {
+            126 => {
+                let mut length = [0u8; 2];
+                socket.read_exact(&mut length).unwrap();
+                u16::from_be_bytes(length) as usize
+            }
+            length => length as usize,
+        };
+        let mut payload = vec![0; length];
+        socket.read_exact(&mut payload).unwrap();
+        let event: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!((event["event"].as_str(), event["workflow"]["name"].as_str()), (Some("run_started"), Some("quick")));
+
+        let old = upgrade.replace("Version: 13", "Version: 8");
+        let (status, _) = subscribe(address, "/events", &old);
+        assert_eq!(status, "HTTP/1.1 426 Upgrade Required");
+        token.cancel();
+        server.join().unwrap();
+    }
+
+    // A frame as clients send them, masked
+    fn masked(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_joining_a_run_in_progress_replays_its_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let (address, token, server) = start(dir.path());
+        let body = r#"{"workflow": {"name": "slow", "steps": [
+            {"id": "first", "kind": "command", "program": "true"},
+            {"id": "second", "kind": "command", "program": "sleep", "args": ["0.5"], "depends_on": ["first"]}
+        ]}}"#;
+        let (_, submitted) = call(address, "POST", "/runs", body);
+        let run_id = submitted["run_id"].as_str().unwrap().to_string();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while RunStore::new(dir.path()).events(&run_id).map_or(0, |events| events.len()) < 4 {
+            assert!(Instant::now() < deadline, "the run did not get to its second step");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // The events before joining come from the journal, once, then the
+        // stream follows the run until it finishes
+        let (_, mut events) = subscribe(address, &format!("/runs/{}/events", run_id), "");
+        let mut text = String::new();
+        events.read_to_string(&mut text).unwrap();
+        let names: Vec<&str> = text.lines().filter_map(|line| line.strip_prefix("event: ")).collect();
+        assert_eq!(
+            names,
+            vec!["run_started", "step_started", "step_finished", "step_started", "step_finished", "run_finished"]
+        );
+
+        token.cancel();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_websocket_answers_ping_and_close() {
+        let dir = tempfile::tempdir().unwrap();
+        let (address, token, server) = start(dir.path());
+        let upgrade = "Upgrade: websocket\r\nConnection: Upgrade\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n";
+        let (status, mut socket) = subscribe(address, "/events", upgrade);
+        assert_eq!(status, "HTTP/1.1 101 Switching Protocols");
+
+        socket.get_mut().write_all(&masked(opcode::PING, b"Hello")).unwrap();
+        let mut pong = [0u8; 7];
+        socket.read_exact(&mut pong).unwrap();
+        assert_eq!(pong, [0x8A, 5, b'H', b'e', b'l', b'l', b'o']);
+
+        socket.get_mut().write_all(&masked(opcode::CLOSE, &1000u16.to_be_bytes())).unwrap();
+        let mut rest = Vec::new();
+        socket.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, vec![0x88, 2, 0x03, 0xE8]);
+
+        token.cancel();
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,367 @@
+// src/state.rs
+/*
+ * Durable run state: one append-only JSON journal per run
+ */
+
+use crate::events::{EventSink, ParentRun, RunEvent};
+use crate::workflow::Workflow;
+use crate::{Result, RunStatus, StepResult, StepStatus, WorkflowError};
+use chrono::{DateTime, Utc};
+use log::{debug, error, warn};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Directory holding the journals of past and current runs.
+///
+/// Every run gets a `<run-id>.jsonl` file with one [`RunEvent`] per line,
+/// flushed to disk as soon as it happens, so a run can be picked up again
+/// after the process dies.
+#[derive(Debug, Clone)]
+pub struct RunStore {
+    dir: PathBuf,
+}
+
+/// What a journal says about one run
+#[derive(Debug, Clone)]
+pub struct RunState {
+    pub run_id: String,
+    pub workflow: Workflow,
+    /// Set when this is the run of a sub-workflow
+    pub parent: Option<ParentRun>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// `None` while the run is in progress or after it was interrupted
+    pub status: Option<RunStatus>,
+    /// Latest result of every step that finished, in journal order
+    pub steps: Vec<StepResult>,
+    /// The workflow's `outputs`, once the run finished
+    pub outputs: serde_json::Map<String, serde_json::Value>,
+}
+
+impl RunState {
+    /// Steps that do not need to run again when the run is resumed
+    pub fn completed_steps(&self) -> Vec<&StepResult> {
+        self.steps
+            .iter()
+            .filter(|step| step.status == StepStatus::Succeeded)
+            .collect()
+    }
+
+    pub fn step(&self, id: &str) -> Option<&StepResult> {
+        self.steps.iter().find(|step| step.id == id)
+    }
+
+    /// Final status, or `incomplete` for runs that are still going or died
+    pub fn status_label(&self) -> String {
+        self.status.map_or_else(|| "incomplete".to_string(), |status| status.to_string())
+    }
+
+    fn apply(&mut self, event: RunEvent) {
+        match event {
+            RunEvent::RunStarted { .. } => {
+                self.status = None;
+                self.finished_at = None;
+            }
+            // Kept for replaying event streams; resuming needs only what finished
+            RunEvent::StepStarted { .. } | RunEvent::RetryScheduled { .. } => {}
+            RunEvent::StepFinished { step, .. } => {
+                self.steps.retain(|existing| existing.id != step.id);
+                self.steps.push(step);
+            }
+            RunEvent::RunFinished { status, at, outputs, .. } => {
+                self.status = Some(status);
+                self.finished_at = Some(at);
+                self.outputs = outputs;
+            }
+        }
+    }
+}
+
+/// Generates a sortable, human-readable run id such as
+/// `20240101T120000-4f1c9a2e7b3d3f2a-0`.
+///
+/// The last part counts the ids this process handed out, so they never
+/// repeat within a process; ids of different processes only collide if their
+/// 64 random bits do within the same second. Processors also check that their
+/// event sinks have no run by the id yet, see [`EventSink::reserve`].
+pub fn new_run_id() -> String {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "{}-{:016x}-{:x}",
+        Utc::now().format("%Y%m%dT%H%M%S"),
+        rand::random::<u64>(),
+        COUNT.fetch_add(1, Ordering::SeqCst)
+    )
+}
+
+impl RunStore {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn journal_path(&self, run_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.jsonl", run_id))
+    }
+
+    /// Creates the empty journal of a new run; `false` if there is one already
+    pub fn reserve(&self, run_id: &str) -> Result<bool> {
+        let path = self.journal_path(run_id);
+        let fail = |e: std::io::Error| {
+            WorkflowError::persistence(run_id, format!("cannot create {}", path.display())).with_source(e)
+        };
+        fs::create_dir_all(&self.dir).map_err(fail)?;
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(fail(e)),
+        }
+    }
+
+    /// Appends one event to its run's journal and syncs it to disk.
+    ///
+    /// A `run_started` that is not a resume must open the journal: it fails
+    /// if another run has written there already.
+    pub fn append(&self, event: &RunEvent) -> Result<()> {
+        let run_id = event.run_id();
+        let path = self.journal_path(run_id);
+        let fail = |e: std::io::Error| {
+            WorkflowError::persistence(run_id, format!("cannot write {}", path.display())).with_source(e)
+        };
+
+        let mut line = serde_json::to_string(event)
+            .map_err(|e| WorkflowError::persistence(run_id, "cannot serialize event").with_source(e))?;
+        line.push('\n');
+        fs::create_dir_all(&self.dir).map_err(fail)?;
+        let mut file = OpenOptions::new().create(true).append(true).open(&path).map_err(fail)?;
+        if let RunEvent::RunStarted { resumed: false, .. } = event {
+            if file.metadata().map_err(fail)?.len() > 0 {
+                let message = format!("{} already holds another run", path.display());
+                return Err(WorkflowError::persistence(run_id, message));
+            }
+        }
+        file.write_all(line.as_bytes()).map_err(fail)?;
+        file.sync_data().map_err(fail)?;
+        Ok(())
+    }
+
+    /// Replays a run's journal.
+    ///
+    /// A torn last line, left behind when the process died mid-write, is ignored.
+    pub fn load(&self, run_id: &str) -> Result<RunState> {
+        let path = self.journal_path(run_id);
+        let mut state: Option<RunState> = None;
+        for event in self.events(run_id)? {
+            match (&mut state, event) {
+                (None, RunEvent::RunStarted { run_id, workflow, at, parent, .. }) => {
+                    state = Some(RunState {
+                        run_id,
+                        workflow: *workflow,
+                        parent,
+                        started_at: at,
+                        finished_at: None,
+                        status: None,
+                        steps: Vec::new(),
+                        outputs: serde_json::Map::new(),
+                    });
+                }
+                (None, _) => {
+                    let message = format!("{} does not start with run_started", path.display());
+                    return Err(WorkflowError::persistence(run_id, message));
+                }
+                (Some(state), event) => state.apply(event),
+            }
+        }
+
+        state.ok_or_else(|| WorkflowError::persistence(run_id, format!("{} is empty", path.display())))
+    }
+
+    /// The events journaled for a run, oldest first.
+    ///
+    /// A torn last line, left behind when the process died mid-write, is ignored.
+    pub fn events(&self, run_id: &str) -> Result<Vec<RunEvent>> {
+        let path = self.journal_path(run_id);
+        let text = fs::read_to_string(&path).map_err(|e| {
+            WorkflowError::persistence(run_id, format!("cannot read journal {}", path.display())).with_source(e)
+        })?;
+
+        let lines: Vec<&str> = text.lines().filter(|line| !line.trim().is_empty()).collect();
+        let mut events = Vec::new();
+        for (number, line) in lines.iter().enumerate() {
+            match serde_json::from_str(line) {
+                Ok(event) => events.push(event),
+                Err(e) if number + 1 == lines.len() => {
+                    warn!("Ignoring incomplete last entry in {}: {}", path.display(), e);
+                }
+                Err(e) => {
+                    let message = format!("corrupt journal entry at {}:{}", path.display(), number + 1);
+                    return Err(WorkflowError::persistence(run_id, message).with_source(e));
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    /// Loads every run in the store, most recently started first.
+    ///
+    /// Journals that cannot be read are skipped with a warning.
+    pub fn list(&self) -> Result<Vec<RunState>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(WorkflowError::from(e).with_path(&self.dir)),
+        };
+
+        let mut runs = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("jsonl") {
+                continue;
+            }
+            // Reserved for a run that is about to start
+            if entry.metadata().is_ok_and(|metadata| metadata.len() == 0) {
+                continue;
+            }
+            let Some(run_id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            match self.load(run_id) {
+                Ok(state) => runs.push(state),
+                Err(e) => warn!("Skipping run journal {}: {}", path.display(), e.full_message()),
+            }
+        }
+        runs.sort_by(|a, b| b.started_at.cmp(&a.started_at).then_with(|| b.run_id.cmp(&a.run_id)));
+        Ok(runs)
+    }
+}
+
+impl EventSink for RunStore {
+    fn reserve(&self, run_id: &str) -> bool {
+        // Errors are left to `emit` to report
+        RunStore::reserve(self, run_id).unwrap_or(true)
+    }
+
+    fn emit(&self, event: &RunEvent) {
+        debug!("Journaling event for run {}", event.run_id());
+        if let Err(e) = self.append(event) {
+            error!("Could not persist run state: {}", e.full_message());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(id: &str, status: StepStatus) -> StepResult {
+        StepResult {
+            id: id.to_string(),
+            status,
+            result: None,
+            started_at: None,
+            duration_ms: None,
+            attempts: Vec::new(),
+        }
+    }
+
+    fn started(run_id: &str) -> RunEvent {
+        RunEvent::RunStarted {
+            run_id: run_id.to_string(),
+            workflow: Box::new(Workflow::from_json(r#"{"name": "demo", "steps": [{"id": "a"}, {"id": "b"}]}"#).unwrap()),
+            at: Utc::now(),
+            resumed: false,
+            parent: None,
+        }
+    }
+
+    #[test]
+    fn test_journal_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = RunStore::new(dir.path().join("runs"));
+        store.emit(&started("r1"));
+        store.emit(&RunEvent::StepFinished { run_id: "r1".to_string(), step: step("a", StepStatus::Failed) });
+        store.emit(&RunEvent::StepFinished { run_id: "r1".to_string(), step: step("a", StepStatus::Succeeded) });
+
+        let state = store.load("r1").unwrap();
+        assert_eq!(state.workflow.name, "demo");
+        assert_eq!(state.status, None);
+        assert_eq!(state.steps.len(), 1);
+        assert_eq!(state.completed_steps().len(), 1);
+
+        store.emit(&RunEvent::RunFinished { run_id: "r1".to_string(), status: RunStatus::Failed, at: Utc::now(), outputs: serde_json::Map::new() });
+        assert_eq!(store.load("r1").unwrap().status, Some(RunStatus::Failed));
+    }
+
+    #[test]
+    fn test_torn_last_line_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = RunStore::new(dir.path());
+        store.emit(&started("r2"));
+        store.emit(&RunEvent::StepFinished { run_id: "r2".to_string(), step: step("a", StepStatus::Succeeded) });
+        let mut file = OpenOptions::new().append(true).open(store.journal_path("r2")).unwrap();
+        file.write_all(br#"{"event": "step_finished", "run_id": "r2", "st"#).unwrap();
+
+        let state = store.load("r2").unwrap();
+        assert_eq!(state.steps.len(), 1);
+    }
+
+    #[test]
+    fn test_list_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = RunStore::new(dir.path().join("runs"));
+        assert!(store.list().unwrap().is_empty());
+
+        store.emit(&started("r1"));
+        store.emit(&RunEvent::RunFinished { run_id: "r1".to_string(), status: RunStatus::Succeeded, at: Utc::now(), outputs: serde_json::Map::new() });
+        store.emit(&started("r2"));
+        fs::write(store.dir().join("notes.txt"), "not a journal").unwrap();
+        fs::write(store.journal_path("broken"), "").unwrap();
+
+        let runs = store.list().unwrap();
+        let ids: Vec<&str> = runs.iter().map(|run| run.run_id.as_str()).collect();
+        assert_eq!(ids, vec!["r2", "r1"]);
+        assert_eq!(runs[0].status_label(), "incomplete");
+        assert_eq!(runs[1].status_label(), "succeeded");
+    }
+
+    #[test]
+    fn test_run_ids_are_unique() {
+        let ids: std::collections::HashSet<String> = (0..100_000).map(|_| new_run_id()).collect();
+        assert_eq!(ids.len(), 100_000);
+        assert!(ids.iter().all(|id| id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')));
+    }
+
+    #[test]
+    fn test_journals_are_not_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = RunStore::new(dir.path().join("runs"));
+        assert!(store.reserve("r1").unwrap());
+        assert!(!store.reserve("r1").unwrap());
+        store.append(&started("r1")).unwrap();
+        let error = store.append(&started("r1")).unwrap_err();
+        assert!(error.full_message().contains("already holds another run"), "{}", error.full_message());
+        assert!(!store.reserve("r1").unwrap());
+
+        // A resume carries on in the same journal
+        let resumed = match started("r1") {
+            RunEvent::RunStarted { run_id, workflow, at, parent, .. } => {
+                RunEvent::RunStarted { run_id, workflow, at, resumed: true, parent }
+            }
+            _ => unreachable!(),
+        };
+        store.append(&resumed).unwrap();
+        assert_eq!(store.events("r1").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_missing_run() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(RunStore::new(dir.path()).load("nope").is_err());
+    }
+}
@@ -0,0 +1,572 @@
+// src/watch.rs
+/*
+ * File watch trigger: starts runs of a workflow when files appear or change
+ */
+
+use crate::cancel::CancellationToken;
+use crate::daemon::spawn_run;
+use crate::workflow::Workflow;
+use crate::{Result, WorkflowEngineProcessor, WorkflowError};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How long a file must stay unchanged before it is picked up, by default
+const DEFAULT_DEBOUNCE_MS: u64 = 2000;
+
+/// Files a workflow's run is started for, from the `watch` field of its
+/// definition.
+///
+/// Written either as a bare directory or glob, or as a table:
+///
+/// ```yaml
+/// watch: drop/orders
+/// # or
+/// watch:
+///   path: "drop/**/*.csv"
+///   input: files
+///   batch: true
+///   debounce_ms: 5000
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Watch {
+    /// A directory, whose files are watched, or a glob with `*`, `?` and `**`
+    pub path: String,
+    /// Input the file path is passed in; `file`, or `files` with `batch`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input: Option<String>,
+    /// Start one run for every burst of files, with an array of their paths,
+    /// instead of one run per file
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub batch: bool,
+    /// How long a file must stay unchanged before it is picked up, so files
+    /// still being written and bursts of files are waited for
+    pub debounce_ms: u64,
+}
+
+impl<'de> Deserialize<'de> for Watch {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Table {
+            path: String,
+            #[serde(default)]
+            input: Option<String>,
+            #[serde(default)]
+            batch: bool,
+            #[serde(default = "default_debounce_ms")]
+            debounce_ms: u64,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Form {
+            Path(String),
+            Table(Table),
+        }
+
+        Ok(match Form::deserialize(deserializer)? {
+            Form::Path(path) => Watch { path, input: None, batch: false, debounce_ms: DEFAULT_DEBOUNCE_MS },
+            Form::Table(table) => Watch {
+                path: table.path,
+                input: table.input,
+                batch: table.batch,
+                debounce_ms: table.debounce_ms,
+            },
+        })
+    }
+}
+
+fn default_debounce_ms() -> u64 {
+    DEFAULT_DEBOUNCE_MS
+}
+
+impl Watch {
+    /// Name of the input the files are passed in
+    pub fn input(&self) -> &str {
+        match &self.input {
+            Some(input) => input,
+            None if self.batch => "files",
+            None => "file",
+        }
+    }
+
+    /// Parses `path`, as [`Workflow::validate`](crate::Workflow::validate) does
+    pub fn pattern(&self) -> std::result::Result<Pattern, String> {
+        self.path.parse()
+    }
+}
+
+/// Which files a [`Watch`] covers: a directory and a glob relative to it.
+///
+/// A path without wildcards is a directory, and covers the files directly in
+/// it. `*` and `?` match within one path component, `**` matches any number
+/// of directories. Wildcards never match a leading `.`, so hidden and
+/// temporary files such as `.upload.part` are left alone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    base: PathBuf,
+    components: Vec<String>,
+}
+
+impl std::str::FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(text: &str) -> std::result::Result<Self, String> {
+        if text.trim().is_empty() {
+            return Err("path must not be empty".to_string());
+        }
+        let mut base = PathBuf::new();
+        let mut components = Vec::new();
+        for component in text.split('/') {
+            if !components.is_empty() || component.contains(['*', '?']) {
+                if component.contains("**") && component != "**" {
+                    return Err(format!("'{}': `**` must be a whole path component", text));
+                }
+                if !component.is_empty() {
+                    components.push(component.to_string());
+                }
+            } else if component.is_empty() && base.as_os_str().is_empty() && text.starts_with('/') {
+                base.push("/");
+            } else if !component.is_empty() {
+                base.push(component);
+            }
+        }
+        if components.is_empty() {
+            components.push("*".to_string());
+        }
+        if components.last().is_some_and(|last| last == "**") {
+            components.push("*".to_string());
+        }
+        if base.as_os_str().is_empty() {
+            base.push(".");
+        }
+        Ok(Pattern { base, components })
+    }
+}
+
+impl Pattern {
+    /// The files the pattern covers, with their size and modification time.
+    /// A missing base directory has no files.
+    pub fn files(&self) -> std::io::Result<BTreeMap<PathBuf, Fingerprint>> {
+        let mut files = BTreeMap::new();
+        match fs::metadata(&self.base) {
+            Ok(metadata) if metadata.is_dir() => self.collect(&self.base, &mut Vec::new(), &mut files)?,
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(files)
+    }
+
+    fn collect(
+        &self,
+        dir: &Path,
+        relative: &mut Vec<String>,
+        files: &mut BTreeMap<PathBuf, Fingerprint>,
+    ) -> std::io::Result<()> {
+        let recursive = self.components.iter().any(|component| component == "**");
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let metadata = entry.metadata()?;
+            relative.push(name);
+            if metadata.is_dir() {
+                if recursive || relative.len() < self.components.len() {
+                    self.collect(&entry.path(), relative, files)?;
+                }
+            } else if metadata.is_file() && matches_components(&self.components, relative) {
+                files.insert(entry.path(), Fingerprint::of(&metadata));
+            }
+            relative.pop();
+        }
+        Ok(())
+    }
+}
+
+fn matches_components(pattern: &[String], path: &[String]) -> bool {
+    match (pattern.first(), path.first()) {
+        (None, None) => true,
+        (Some(first), _) if first == "**" => {
+            matches_components(&pattern[1..], path)
+                || (!path.is_empty() && !path[0].starts_with('.') && matches_components(pattern, &path[1..]))
+        }
+        (Some(first), Some(name)) => matches_name(first, name) && matches_components(&pattern[1..], &path[1..]),
+        _ => false,
+    }
+}
+
+fn matches_name(pattern: &str, name: &str) -> bool {
+    fn glob(pattern: &[char], name: &[char]) -> bool {
+        match pattern.split_first() {
+            None => name.is_empty(),
+            Some(('*', rest)) => (0..=name.len()).any(|skip| glob(rest, &name[skip..])),
+            Some(('?', rest)) => !name.is_empty() && glob(rest, &name[1..]),
+            Some((c, rest)) => name.first() == Some(c) && glob(rest, &name[1..]),
+        }
+    }
+    if name.starts_with('.') && !pattern.starts_with('.') {
+        return false;
+    }
+    glob(&pattern.chars().collect::<Vec<_>>(), &name.chars().collect::<Vec<_>>())
+}
+
+/// Size and modification time of a file, which tell whether it changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+impl Fingerprint {
+    fn of(metadata: &fs::Metadata) -> Self {
+        Fingerprint { size: metadata.len(), modified: metadata.modified().ok().map(DateTime::<Utc>::from) }
+    }
+}
+
+/// Starts runs of workflows when files covered by their `watch` appear or
+/// change, passing the paths as an input.
+///
+/// Files are picked up once they stop changing for `debounce_ms`. The files
+/// already run for are kept in a state file, so a file is not run for again
+/// after a restart unless it changed. Like the schedule daemon, a workflow
+/// never runs twice at once; files that arrive during a run wait for it.
+#[derive(Debug)]
+pub struct Watcher {
+    processor: Arc<WorkflowEngineProcessor>,
+    entries: Vec<Entry>,
+    state_file: Option<PathBuf>,
+    runs: Vec<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    workflow: Arc<Workflow>,
+    pattern: Pattern,
+    input: String,
+    batch: bool,
+    debounce: Duration,
+    /// Files a run was started for
+    seen: BTreeMap<PathBuf, Fingerprint>,
+    /// Files that changed, and when they were last seen changing
+    settling: BTreeMap<PathBuf, (Fingerprint, Instant)>,
+    /// Files of runs waiting to start
+    queue: VecDeque<Vec<(PathBuf, Fingerprint)>>,
+    running: Arc<AtomicBool>,
+}
+
+impl Entry {
+    fn queued(&self, path: &Path, fingerprint: &Fingerprint) -> bool {
+        self.queue.iter().flatten().any(|(queued, queued_fingerprint)| queued == path && queued_fingerprint == fingerprint)
+    }
+
+    // Rescans the files and moves the ones that settled by `now` to the queue
+    fn scan(&mut self, now: Instant) {
+        let files = match self.pattern.files() {
+            Ok(files) => files,
+            Err(e) => {
+                warn!("Could not scan {} for workflow '{}': {}", self.pattern.base.display(), self.workflow.name, e);
+                return;
+            }
+        };
+        self.seen.retain(|path, _| files.contains_key(path));
+        self.settling.retain(|path, _| files.contains_key(path));
+        for (path, fingerprint) in files {
+            if self.seen.get(&path) == Some(&fingerprint) || self.queued(&path, &fingerprint) {
+                continue;
+            }
+            match self.settling.get(&path) {
+                Some((settling, _)) if *settling == fingerprint => {}
+                _ => {
+                    debug!("File {} changed, watched by workflow '{}'", path.display(), self.workflow.name);
+                    self.settling.insert(path, (fingerprint, now));
+                }
+            }
+        }
+
+        let settled = |changed: &Instant| now.saturating_duration_since(*changed) >= self.debounce;
+        if self.batch {
+            if !self.settling.is_empty() && self.settling.values().all(|(_, changed)| settled(changed)) {
+                let files = std::mem::take(&mut self.settling).into_iter().map(|(path, (fingerprint, _))| (path, fingerprint));
+                self.queue.push_back(files.collect());
+            }
+        } else {
+            let ready: Vec<PathBuf> =
+                self.settling.iter().filter(|(_, (_, changed))| settled(changed)).map(|(path, _)| path.clone()).collect();
+            for path in ready {
+                if let Some((fingerprint, _)) = self.settling.remove(&path) {
+                    self.queue.push_back(vec![(path, fingerprint)]);
+                }
+            }
+        }
+    }
+
+    fn inputs(&self, files: &[(PathBuf, Fingerprint)]) -> serde_json::Map<String, serde_json::Value> {
+        let mut paths = files.iter().map(|(path, _)| serde_json::Value::String(path.display().to_string()));
+        let value = match (self.batch, paths.next()) {
+            (false, Some(path)) => path,
+            (_, first) => serde_json::Value::Array(first.into_iter().chain(paths).collect()),
+        };
+        serde_json::Map::from_iter([(self.input.clone(), value)])
+    }
+}
+
+impl Watcher {
+    /// A watcher for `workflows`, which must all have a `watch`
+    pub fn new(processor: WorkflowEngineProcessor, workflows: Vec<Workflow>) -> Result<Self> {
+        let mut entries: Vec<Entry> = Vec::new();
+        for workflow in workflows {
+            let invalid = |message: String| WorkflowError::Config {
+                origin: format!("workflow '{}'", workflow.name),
+                message,
+            };
+            let Some(watch) = &workflow.watch else {
+                return Err(invalid("has no watch".to_string()));
+            };
+            if entries.iter().any(|entry| entry.workflow.name == workflow.name) {
+                return Err(invalid("is watched twice; workflow names must be unique".to_string()));
+            }
+            let pattern = watch.pattern().map_err(invalid)?;
+            let input = watch.input().to_string();
+            let batch = watch.batch;
+            let debounce = Duration::from_millis(watch.debounce_ms);
+            entries.push(Entry {
+                workflow: Arc::new(workflow),
+                pattern,
+                input,
+                batch,
+                debounce,
+                seen: BTreeMap::new(),
+                settling: BTreeMap::new(),
+                queue: VecDeque::new(),
+                running: Arc::new(AtomicBool::new(false)),
+            });
+        }
+        Ok(Self {
+            processor: Arc::new(processor),
+            entries,
+            state_file: None,
+            runs: Vec::new(),
+        })
+    }
+
+    /// Remembers the files run for in `path`, a JSON object of workflow
+    /// names and files, and reads what an earlier watcher left there
+    pub fn with_state_file<P: Into<PathBuf>>(mut self, path: P) -> Result<Self> {
+        let path = path.into();
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                let mut seen: BTreeMap<String, BTreeMap<PathBuf, Fingerprint>> =
+                    serde_json::from_str(&text).map_err(|e| {
+                        WorkflowError::persistence("watch", format!("cannot read {}", path.display())).with_source(e)
+                    })?;
+                for entry in &mut self.entries {
+                    entry.seen = seen.remove(&entry.workflow.name).unwrap_or_default();
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(WorkflowError::from(e).with_path(&path)),
+        }
+        self.state_file = Some(path);
+        Ok(self)
+    }
+
+    /// Watches until `token` is cancelled, then waits for the runs that are
+    /// still going, which are cancelled along with it.
+    pub fn run(&mut self, token: &CancellationToken) -> Result<()> {
+        for entry in &self.entries {
+            info!("Workflow '{}' watches {}", entry.workflow.name, entry.pattern.base.display());
+        }
+        let interval = self
+            .entries
+            .iter()
+            .map(|entry| entry.debounce / 4)
+            .min()
+            .unwrap_or_default()
+            .clamp(Duration::from_millis(50), Duration::from_secs(1));
+        while !token.is_cancelled() {
+            self.poll(Instant::now(), token);
+            token.sleep(interval);
+        }
+        info!("Watcher stopping; waiting for {} running workflows", self.running());
+        self.wait();
+        Ok(())
+    }
+
+    // Rescans every watch and starts the runs that are waiting, one at a
+    // time per workflow. Returns the workflows started and their files.
+    pub(crate) fn poll(&mut self, now: Instant, token: &CancellationToken) -> Vec<(String, Vec<PathBuf>)> {
+        self.runs.retain(|run| !run.is_finished());
+        let mut started = Vec::new();
+        for entry in &mut self.entries {
+            entry.scan(now);
+            if entry.running.load(Ordering::SeqCst) {
+                continue;
+            }
+            let Some(files) = entry.queue.pop_front() else {
+                continue;
+            };
+            let paths: Vec<PathBuf> = files.iter().map(|(path, _)| path.clone()).collect();
+            info!("Starting run of workflow '{}' for {} files", entry.workflow.name, paths.len());
+            match spawn_run(&self.processor, &entry.workflow, entry.inputs(&files), &entry.running, token) {
+                Ok(run) => {
+                    self.runs.push(run);
+                    entry.seen.extend(files);
+                    started.push((entry.workflow.name.clone(), paths));
+                }
+                Err(e) => {
+                    error!("Could not start workflow '{}': {}", entry.workflow.name, e);
+                    entry.queue.push_front(files);
+                }
+            }
+        }
+        if !started.is_empty() {
+            self.save();
+        }
+        started
+    }
+
+    fn running(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.running.load(Ordering::SeqCst)).count()
+    }
+
+    /// Waits for every run started so far
+    pub fn wait(&mut self) {
+        for run in self.runs.drain(..) {
+            let _ = run.join();
+        }
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        let seen: BTreeMap<&str, &BTreeMap<PathBuf, Fingerprint>> =
+            self.entries.iter().map(|entry| (entry.workflow.name.as_str(), &entry.seen)).collect();
+        // Written next to the real file and renamed, so a crash never leaves half a file
+        let temporary = path.with_extension("json.tmp");
+        let written = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&temporary, serde_json::to_string_pretty(&seen).unwrap_or_default()))
+            .and_then(|_| fs::rename(&temporary, path));
+        if let Err(e) = written {
+            error!("Could not save watch state to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watching(dir: &Path, watch: serde_json::Value) -> Workflow {
+        let mut workflow = Workflow::from_json(r#"{"name": "ingest", "inputs": {"file": "", "files": []}, "steps": [{"id": "a"}]}"#)
+            .unwrap();
+        workflow.watch = Some(serde_json::from_value(watch).unwrap());
+        if let Some(watch) = &mut workflow.watch {
+            watch.path = dir.join(&watch.path).display().to_string();
+        }
+        workflow
+    }
+
+    #[test]
+    fn test_patterns() {
+        let pattern: Pattern = "drop/**/*.csv".parse().unwrap();
+        assert_eq!(pattern.base, PathBuf::from("drop"));
+        let matches = |path: &str| matches_components(&pattern.components, &path.split('/').map(String::from).collect::<Vec<_>>());
+        assert!(matches("a.csv"));
+        assert!(matches("2024/03/a.csv"));
+        assert!(!matches("a.txt"));
+        assert!(!matches(".a.csv"));
+        assert!(!matches(".hidden/a.csv"));
+
+        let pattern: Pattern = "/srv/in".parse().unwrap();
+        assert_eq!((pattern.base.as_path(), pattern.components.as_slice()), (Path::new("/srv/in"), ["*".to_string()].as_slice()));
+        assert!(matches_name("report-??.json", "report-07.json"));
+        assert!(!matches_name("report-??.json", "report-7.json"));
+        assert_eq!("in/a**".parse::<Pattern>().unwrap_err(), "'in/a**': `**` must be a whole path component");
+    }
+
+    #[test]
+    fn test_files_run_once_after_settling() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("in")).unwrap();
+        let state = dir.path().join("watch.json");
+        let workflow = watching(dir.path(), serde_json::json!({"path": "in/*.csv", "debounce_ms": 1000}));
+        let token = CancellationToken::new();
+        let start = Instant::now();
+
+        let mut watcher = Watcher::new(WorkflowEngineProcessor::new(false), vec![workflow.clone()])
+            .unwrap()
+            .with_state_file(&state)
+            .unwrap();
+        let file = dir.path().join("in/orders.csv");
+        fs::write(&file, "id\n1\n").unwrap();
+        fs::write(dir.path().join("in/notes.txt"), "ignored").unwrap();
+        assert!(watcher.poll(start, &token).is_empty());
+        let started = watcher.poll(start + Duration::from_millis(1000), &token);
+        assert_eq!(started, vec![("ingest".to_string(), vec![file.clone()])]);
+        watcher.wait();
+        assert!(watcher.poll(start + Duration::from_millis(3000), &token).is_empty());
+        assert_eq!(watcher.processor.processed_count(), 1);
+
+        // A new watcher remembers the file, until it changes
+        let mut watcher = Watcher::new(WorkflowEngineProcessor::new(false), vec![workflow])
+            .unwrap()
+            .with_state_file(&state)
+            .unwrap();
+        assert!(watcher.poll(start, &token).is_empty());
+        assert!(watcher.poll(start + Duration::from_millis(5000), &token).is_empty());
+        fs::write(&file, "id\n1\n2\n").unwrap();
+        assert!(watcher.poll(start + Duration::from_millis(6000), &token).is_empty());
+        assert_eq!(watcher.poll(start + Duration::from_millis(7000), &token).len(), 1);
+        watcher.wait();
+    }
+
+    #[test]
+    fn test_batches_wait_for_the_burst_to_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let workflow = watching(dir.path(), serde_json::json!({"path": ".", "batch": true, "debounce_ms": 1000}));
+        let token = CancellationToken::new();
+        let start = Instant::now();
+
+        let mut watcher = Watcher::new(WorkflowEngineProcessor::new(false), vec![workflow]).unwrap();
+        fs::write(dir.path().join("a.json"), "1").unwrap();
+        assert!(watcher.poll(start, &token).is_empty());
+        fs::write(dir.path().join("b.json"), "2").unwrap();
+        assert!(watcher.poll(start + Duration::from_millis(800), &token).is_empty());
+        assert!(watcher.poll(start + Duration::from_millis(1200), &token).is_empty());
+        let started = watcher.poll(start + Duration::from_millis(1800), &token);
+        assert_eq!(started, vec![("ingest".to_string(), vec![dir.path().join("a.json"), dir.path().join("b.json")])]);
+        assert_eq!(
+            watcher.entries[0].inputs(&[(dir.path().join("a.json"), Fingerprint { size: 1, modified: None })])["files"],
+            serde_json::json!([dir.path().join("a.json").display().to_string()])
+        );
+        watcher.wait();
+    }
+
+    #[test]
+    fn test_watch_input_is_checked() {
+        let workflow = Workflow::from_json(r#"{"name": "ingest", "watch": "in", "steps": [{"id": "a"}]}"#);
+        assert_eq!(
+            workflow.unwrap_err().full_message(),
+            "invalid workflow: watch: workflow has no input 'file' to pass the files in"
+        );
+        let workflow =
+            Workflow::from_json(r#"{"name": "ingest", "inputs": {"file": 0}, "watch": "in", "steps": [{"id": "a"}]}"#);
+        assert_eq!(
+            workflow.unwrap_err().full_message(),
+            "invalid workflow: watch: input 'file' must be string to take the files, not number"
+        );
+    }
+}
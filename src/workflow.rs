@@ -0,0 +1,1090 @@
+// src/workflow.rs
+/*
+ * Declarative workflow definitions: steps, dependency edges and validation
+ */
+
+use crate::command::CommandSpec;
+use crate::expr::{self, Expression, PathSegment, Template, Type};
+use crate::format::{parse_workflow, WorkflowFormat};
+use crate::inputs;
+use crate::retry::RetryPolicy;
+use crate::schedule::Schedule;
+use crate::watch::Watch;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+/// A named workflow made of steps connected by `depends_on` edges.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Workflow {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Wall-clock limit for the whole run, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// Values expressions can read as `inputs.<name>`; these are the defaults,
+    /// which a run can override
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub inputs: serde_json::Map<String, serde_json::Value>,
+    /// JSON Schema the inputs of a run must match, see [`crate::inputs`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_schema: Option<serde_json::Value>,
+    /// Results of the run, each an expression evaluated once every step is done
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub outputs: BTreeMap<String, String>,
+    /// When the schedule daemon starts runs of this workflow
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+    /// Files whose arrival or change starts a run of this workflow
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watch: Option<Watch>,
+    pub steps: Vec<Step>,
+}
+
+/// A single node of the workflow graph.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    pub id: String,
+    /// Ids of the steps that must finish before this one starts
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+    /// Condition that must hold for the step to run, e.g. `steps.fetch.output.count > 10`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<String>,
+    /// Parameters handed to the step when it runs; strings may contain `${{ ... }}`
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub inputs: serde_json::Map<String, serde_json::Value>,
+    /// Keys the step promises to put into its result `data`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<String>,
+    /// Re-run the step on failure; without a policy it runs once
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+    /// Limit for each attempt, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// Which outcomes of the dependencies let the step run
+    #[serde(default, skip_serializing_if = "TriggerRule::is_default")]
+    pub trigger: TriggerRule,
+    #[serde(flatten, deserialize_with = "deserialize_action")]
+    pub action: StepAction,
+}
+
+/// When a step runs, given how its dependencies finished.
+///
+/// Steps that are not allowed to run are recorded as skipped, which in turn
+/// counts as "did not succeed" for their own dependents.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerRule {
+    /// Every dependency succeeded
+    #[default]
+    AllSuccess,
+    /// At least one dependency succeeded, e.g. to join the branches of an `if`
+    AnySuccess,
+    /// Whatever happened to the dependencies, e.g. for cleanup
+    Always,
+}
+
+impl TriggerRule {
+    fn is_default(&self) -> bool {
+        *self == TriggerRule::AllSuccess
+    }
+}
+
+/// What a step does when it runs, selected by the `kind` field.
+///
+/// Steps without a `kind` are plain `process` steps.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum StepAction {
+    /// Runs the step inputs through `WorkflowEngineProcessor::process`
+    Process {},
+    /// Runs an external program, see [`CommandSpec`]
+    Command(CommandSpec),
+    /// Runs `then` when `condition` holds and `else` otherwise
+    If {
+        condition: String,
+        then: Vec<Step>,
+        #[serde(default, rename = "else", skip_serializing_if = "Vec::is_empty")]
+        otherwise: Vec<Step>,
+    },
+    /// Runs the case whose key equals `value`, or `default` when none does
+    Switch {
+        value: String,
+        cases: BTreeMap<String, Vec<Step>>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        default: Vec<Step>,
+    },
+    /// Runs `steps` again and again until `until` holds, or while `while` does
+    Loop {
+        #[serde(default, rename = "while", skip_serializing_if = "Option::is_none")]
+        repeat_while: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        until: Option<String>,
+        /// The loop fails when its condition still asks for more after this many iterations
+        #[serde(default = "default_max_iterations")]
+        max_iterations: u32,
+        /// Pause between iterations, in milliseconds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delay_ms: Option<u64>,
+        steps: Vec<Step>,
+    },
+    /// Runs another workflow, given by file `path` or registered `name`, as a
+    /// child run; the step's `inputs` become the child's inputs
+    #[serde(rename = "workflow")]
+    SubWorkflow {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+    /// Runs `steps` once for every element of the `items` array
+    Map {
+        items: String,
+        steps: Vec<Step>,
+        /// Items processed at the same time; defaults to the worker count
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        concurrency: Option<usize>,
+        #[serde(default, skip_serializing_if = "MapMode::is_default")]
+        mode: MapMode,
+    },
+}
+
+fn default_max_iterations() -> u32 {
+    100
+}
+
+/// What a `map` step does when one of its items fails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MapMode {
+    /// Start no further items; the ones not started are recorded as cancelled
+    #[default]
+    FailFast,
+    /// Run every item and report all failures
+    CollectErrors,
+}
+
+impl MapMode {
+    fn is_default(&self) -> bool {
+        *self == MapMode::FailFast
+    }
+}
+
+impl Step {
+    /// Every step nested in this one's branches, at any depth.
+    ///
+    /// The bodies of `loop` and `map` steps are not included: they run many
+    /// times and their results end up in the loop or map step's output instead.
+    pub fn descendants(&self) -> Vec<&Step> {
+        let mut found = Vec::new();
+        for (_, branch) in self.action.branches() {
+            for step in branch {
+                found.push(step);
+                found.extend(step.descendants());
+            }
+        }
+        found
+    }
+
+    /// Every `${{ ... }}` template in the step's inputs and command fields
+    pub fn templates(&self) -> std::result::Result<Vec<Template>, expr::ExprError> {
+        let mut templates = expr::templates(&serde_json::Value::Object(self.inputs.clone()))?;
+        if let StepAction::Command(spec) = &self.action {
+            let fields = std::iter::once(spec.program.as_str())
+                .chain(spec.args.iter().map(String::as_str))
+                .chain(spec.env.values().map(String::as_str))
+                .chain(spec.working_dir.iter().filter_map(|dir| dir.to_str()));
+            for field in fields {
+                templates.extend(Template::parse(field)?);
+            }
+        }
+        Ok(templates)
+    }
+}
+
+impl StepAction {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            StepAction::Process {} => "process",
+            StepAction::Command(_) => "command",
+            StepAction::If { .. } => "if",
+            StepAction::Switch { .. } => "switch",
+            StepAction::Loop { .. } => "loop",
+            StepAction::SubWorkflow { .. } => "workflow",
+            StepAction::Map { .. } => "map",
+        }
+    }
+
+    /// Whether the step does its work itself instead of running other steps
+    pub fn is_leaf(&self) -> bool {
+        matches!(self, StepAction::Process {} | StepAction::Command(_))
+    }
+
+    /// The named branches of an `if` or `switch` step; empty for other steps
+    pub fn branches(&self) -> Vec<(&str, &[Step])> {
+        match self {
+            StepAction::Process {}
+            | StepAction::Command(_)
+            | StepAction::Loop { .. }
+            | StepAction::SubWorkflow { .. }
+            | StepAction::Map { .. } => Vec::new(),
+            StepAction::If { then, otherwise, .. } => vec![("then", then.as_slice()), ("else", otherwise.as_slice())],
+            StepAction::Switch { cases, default, .. } => cases
+                .iter()
+                .map(|(key, steps)| (key.as_str(), steps.as_slice()))
+                .chain(std::iter::once(("default", default.as_slice())))
+                .collect(),
+        }
+    }
+
+    /// Every list of nested steps: the branches, or the body of a `loop` or `map`
+    pub fn nested(&self) -> Vec<(&str, &[Step])> {
+        match self {
+            StepAction::Loop { steps, .. } | StepAction::Map { steps, .. } => vec![("steps", steps.as_slice())],
+            _ => self.branches(),
+        }
+    }
+
+    pub fn nested_mut(&mut self) -> Vec<&mut Vec<Step>> {
+        match self {
+            StepAction::Process {} | StepAction::Command(_) | StepAction::SubWorkflow { .. } => Vec::new(),
+            StepAction::If { then, otherwise, .. } => vec![then, otherwise],
+            StepAction::Switch { cases, default, .. } => cases.values_mut().chain(std::iter::once(default)).collect(),
+            StepAction::Loop { steps, .. } | StepAction::Map { steps, .. } => vec![steps],
+        }
+    }
+}
+
+impl Default for StepAction {
+    fn default() -> Self {
+        StepAction::Process {}
+    }
+}
+
+// Fills in `kind: process` when a step leaves it out.
+fn deserialize_action<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<StepAction, D::Error> {
+    let mut fields = serde_json::Map::deserialize(deserializer)?;
+    fields
+        .entry("kind")
+        .or_insert_with(|| serde_json::Value::String("process".to_string()));
+    StepAction::deserialize(serde_json::Value::Object(fields)).map_err(D::Error::custom)
+}
+
+/// Reasons a workflow definition is rejected before it runs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    NoSteps,
+    EmptyStepId,
+    DuplicateStep(String),
+    UnknownDependency { step: String, dependency: String },
+    Cycle(Vec<String>),
+    InvalidStep { step: String, reason: String },
+    /// `input_schema` itself is malformed
+    InvalidSchema(String),
+    /// A run was given inputs that do not match the workflow's declaration
+    InvalidInput { input: String, reason: String },
+    InvalidOutput { output: String, reason: String },
+    InvalidSchedule(String),
+    InvalidWatch(String),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::NoSteps => write!(f, "workflow has no steps"),
+            ValidationError::EmptyStepId => write!(f, "step id must not be empty"),
+            ValidationError::DuplicateStep(id) => write!(f, "duplicate step id '{}'", id),
+            ValidationError::UnknownDependency { step, dependency } => {
+                write!(f, "step '{}' depends on unknown step '{}'", step, dependency)
+            }
+            ValidationError::Cycle(path) => write!(f, "dependency cycle: {}", path.join(" -> ")),
+            ValidationError::InvalidStep { step, reason } => write!(f, "step '{}': {}", step, reason),
+            ValidationError::InvalidSchema(reason) => write!(f, "input_schema: {}", reason),
+            ValidationError::InvalidInput { input, reason } if input.is_empty() => write!(f, "inputs: {}", reason),
+            ValidationError::InvalidInput { input, reason } => write!(f, "input '{}': {}", input, reason),
+            ValidationError::InvalidOutput { output, reason } => write!(f, "output '{}': {}", output, reason),
+            ValidationError::InvalidSchedule(reason) => write!(f, "schedule: {}", reason),
+            ValidationError::InvalidWatch(reason) => write!(f, "watch: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl Workflow {
+    /// Parses a workflow from JSON text and validates it.
+    pub fn from_json(text: &str) -> crate::Result<Self> {
+        Self::parse(text, WorkflowFormat::Json)
+    }
+
+    /// Parses a workflow in the given format and validates it.
+    pub fn parse(text: &str, format: WorkflowFormat) -> crate::Result<Self> {
+        let workflow = parse_workflow(text, format)?;
+        workflow.validate()?;
+        Ok(workflow)
+    }
+
+    /// A workflow running `steps` on their own, e.g. one branch of an `if`
+    pub(crate) fn of_steps(name: String, steps: Vec<Step>) -> Self {
+        Self {
+            name,
+            description: None,
+            timeout_ms: None,
+            inputs: serde_json::Map::new(),
+            input_schema: None,
+            outputs: BTreeMap::new(),
+            schedule: None,
+            watch: None,
+            steps,
+        }
+    }
+
+    /// Finds a step by id, including steps nested in branches
+    pub fn step(&self, id: &str) -> Option<&Step> {
+        self.all_steps().into_iter().find(|step| step.id == id)
+    }
+
+    /// Every step of the definition, each followed by the steps nested in it
+    pub fn all_steps(&self) -> Vec<&Step> {
+        fn collect<'a>(steps: &'a [Step], found: &mut Vec<&'a Step>) {
+            for step in steps {
+                found.push(step);
+                for (_, nested) in step.action.nested() {
+                    collect(nested, found);
+                }
+            }
+        }
+        let mut found = Vec::new();
+        collect(&self.steps, &mut found);
+        found
+    }
+
+    /// The steps a run records a result for: all of them except the bodies
+    /// of `loop` and `map` steps
+    pub fn recorded_steps(&self) -> Vec<&Step> {
+        self.steps
+            .iter()
+            .flat_map(|step| std::iter::once(step).chain(step.descendants()))
+            .collect()
+    }
+
+    /// Checks step ids, dependency references, acyclicity and expressions.
+    pub fn validate(&self) -> std::result::Result<(), ValidationError> {
+        self.check_graph()?;
+        self.check_inputs()?;
+        self.check_expressions(&self.steps, &HashSet::new(), &[])?;
+        self.check_outputs()?;
+        if let Some(schedule) = &self.schedule {
+            schedule.parse().map_err(ValidationError::InvalidSchedule)?;
+        }
+        if let Some(watch) = &self.watch {
+            self.check_watch(watch).map_err(ValidationError::InvalidWatch)?;
+        }
+        Ok(())
+    }
+
+    /// The type of input `name` as declared by `input_schema`, or else by its
+    /// default; `None` when the workflow has no such input
+    pub fn input_type(&self, name: &str) -> Option<Type> {
+        match self.input_schema.as_ref().and_then(|schema| inputs::property(schema, name)) {
+            Some(property) => Some(inputs::schema_type(property)),
+            None => self.inputs.get(name).map(Type::of),
+        }
+    }
+
+    /// The inputs of a run given `values`: the defaults of `inputs` and of
+    /// `input_schema`, overridden by `values`, checked against the schema.
+    ///
+    /// Values for undeclared inputs are refused, and so are values for inputs
+    /// without a schema whose type differs from a non-null default.
+    pub fn resolve_inputs(
+        &self,
+        values: &serde_json::Map<String, serde_json::Value>,
+    ) -> std::result::Result<serde_json::Map<String, serde_json::Value>, ValidationError> {
+        let schema = self.input_schema.as_ref();
+        let mut resolved = serde_json::Map::new();
+        if let Some(serde_json::Value::Object(properties)) = schema.and_then(|schema| schema.get("properties")) {
+            for (name, property) in properties {
+                if let Some(default) = property.get("default") {
+                    resolved.insert(name.clone(), default.clone());
+                }
+            }
+        }
+        resolved.extend(self.inputs.clone());
+
+        for (name, value) in values {
+            let invalid = |reason: String| ValidationError::InvalidInput { input: name.clone(), reason };
+            let declared = schema.and_then(|schema| inputs::property(schema, name));
+            match (declared, self.inputs.get(name)) {
+                (None, None) if schema.and_then(|schema| schema.get("additionalProperties")).is_none() => {
+                    return Err(invalid(format!("workflow '{}' has no such input", self.name)));
+                }
+                (None, Some(default)) if !default.is_null() && Type::of(default) != Type::of(value) => {
+                    return Err(invalid(format!("must be {}, got {}", Type::of(default), Type::of(value))));
+                }
+                _ => {}
+            }
+            resolved.insert(name.clone(), value.clone());
+        }
+
+        if let Some(schema) = schema {
+            inputs::validate(schema, &serde_json::Value::Object(resolved.clone())).map_err(|e| {
+                let (input, reason) = match e.path.split_once(['.', '[']) {
+                    Some((input, _)) => (input.to_string(), e.to_string()),
+                    None => (e.path, e.message),
+                };
+                ValidationError::InvalidInput { input, reason }
+            })?;
+        }
+        Ok(resolved)
+    }
+
+    /// This workflow with its inputs resolved from `values`, see
+    /// [`resolve_inputs`](Self::resolve_inputs)
+    pub fn with_inputs(
+        &self,
+        values: &serde_json::Map<String, serde_json::Value>,
+    ) -> std::result::Result<Workflow, ValidationError> {
+        let inputs = self.resolve_inputs(values)?;
+        Ok(Workflow { inputs, ..self.clone() })
+    }
+
+    // The watched files are passed in an input, which must take them
+    fn check_watch(&self, watch: &Watch) -> std::result::Result<(), String> {
+        watch.pattern()?;
+        let expected = if watch.batch { Type::Array } else { Type::String };
+        match self.input_type(watch.input()) {
+            None => Err(format!("workflow has no input '{}' to pass the files in", watch.input())),
+            Some(Type::Any | Type::Null) => Ok(()),
+            Some(found) if found == expected => Ok(()),
+            Some(found) => Err(format!("input '{}' must be {} to take the files, not {}", watch.input(), expected, found)),
+        }
+    }
+
+    fn check_inputs(&self) -> std::result::Result<(), ValidationError> {
+        let Some(schema) = &self.input_schema else {
+            return Ok(());
+        };
+        inputs::check_schema(schema).map_err(|e| ValidationError::InvalidSchema(e.to_string()))?;
+        if schema.get("type").is_some_and(|kind| kind != "object") {
+            return Err(ValidationError::InvalidSchema("inputs are an object; `type` must be \"object\"".to_string()));
+        }
+        for (name, default) in &self.inputs {
+            if let Some(property) = inputs::property(schema, name) {
+                inputs::validate(property, default).map_err(|e| ValidationError::InvalidInput {
+                    input: name.clone(),
+                    reason: format!("default does not match input_schema: {}", e),
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    // Output expressions run after every step, so they can read any step
+    // that records a result
+    fn check_outputs(&self) -> std::result::Result<(), ValidationError> {
+        let upstream: HashSet<&str> = self.recorded_steps().into_iter().map(|step| step.id.as_str()).collect();
+        let resolve = |path: &[PathSegment]| self.reference_type(&upstream, &[], path);
+        for (name, source) in &self.outputs {
+            Expression::parse_condition(source)
+                .and_then(|expression| expression.check(&resolve))
+                .map_err(|e| ValidationError::InvalidOutput {
+                    output: name.clone(),
+                    reason: e.to_string(),
+                })?;
+        }
+        Ok(())
+    }
+
+    /// The structural part of [`validate`](Self::validate): ids, dependencies,
+    /// cycles and retry policies, without looking at expressions.
+    pub(crate) fn check_graph(&self) -> std::result::Result<(), ValidationError> {
+        if self.steps.is_empty() {
+            return Err(ValidationError::NoSteps);
+        }
+
+        let mut seen = HashSet::new();
+        for step in self.all_steps() {
+            if step.id.is_empty() {
+                return Err(ValidationError::EmptyStepId);
+            }
+            if !seen.insert(step.id.as_str()) {
+                return Err(ValidationError::DuplicateStep(step.id.clone()));
+            }
+        }
+        check_siblings(&self.steps, &seen)
+    }
+
+    // Parses and type-checks every expression against the steps upstream of
+    // the step that uses it; `outer` holds those upstream of the enclosing
+    // branch, and `locals` the names a loop or map body adds, like `item`.
+    fn check_expressions(
+        &self,
+        steps: &[Step],
+        outer: &HashSet<&str>,
+        locals: &[&str],
+    ) -> std::result::Result<(), ValidationError> {
+        for step in steps {
+            let invalid = |reason: String| ValidationError::InvalidStep {
+                step: step.id.clone(),
+                reason,
+            };
+            let mut upstream = outer.clone();
+            upstream.extend(upstream_of(step, steps));
+            let resolve = |path: &[PathSegment]| self.reference_type(&upstream, locals, path);
+            let condition = |field: &str, source: &str, resolve: &Resolver| -> std::result::Result<(), ValidationError> {
+                let condition = Expression::parse_condition(source).map_err(|e| invalid(format!("{}: {}", field, e)))?;
+                match condition.check(&resolve).map_err(|e| invalid(format!("{}: {}", field, e)))? {
+                    Type::Bool | Type::Any => Ok(()),
+                    other => Err(invalid(format!(
+                        "{}: `{}` is a {}, not a boolean",
+                        field,
+                        condition.source(),
+                        other
+                    ))),
+                }
+            };
+
+            if let Some(when) = &step.when {
+                condition("when", when, &resolve)?;
+            }
+            for template in step.templates().map_err(|e| invalid(e.to_string()))? {
+                for expression in template.expressions() {
+                    expression.check(&resolve).map_err(|e| invalid(e.to_string()))?;
+                }
+            }
+            match &step.action {
+                StepAction::If { condition: source, .. } => condition("condition", source, &resolve)?,
+                StepAction::Switch { value, .. } => {
+                    Expression::parse_condition(value)
+                        .and_then(|value| value.check(&resolve))
+                        .map_err(|e| invalid(format!("value: {}", e)))?;
+                }
+                StepAction::Map { items, steps, concurrency, .. } => {
+                    if steps.is_empty() {
+                        return Err(invalid("map needs at least one step to run per item".to_string()));
+                    }
+                    if *concurrency == Some(0) {
+                        return Err(invalid("concurrency must be at least 1".to_string()));
+                    }
+                    let items = Expression::parse_condition(items).map_err(|e| invalid(format!("items: {}", e)))?;
+                    match items.check(&resolve).map_err(|e| invalid(format!("items: {}", e)))? {
+                        Type::Array | Type::Any => {}
+                        other => {
+                            return Err(invalid(format!("items: `{}` is a {}, not an array", items.source(), other)))
+                        }
+                    }
+                }
+                StepAction::Loop { repeat_while, until, max_iterations, steps, .. } => {
+                    if steps.is_empty() {
+                        return Err(invalid("loop needs at least one step to repeat".to_string()));
+                    }
+                    if *max_iterations == 0 {
+                        return Err(invalid("max_iterations must be at least 1".to_string()));
+                    }
+                    // The condition is checked after each iteration, so it can read the body
+                    let mut after = upstream.clone();
+                    after.extend(steps.iter().flat_map(|s| std::iter::once(s).chain(s.descendants())).map(|s| s.id.as_str()));
+                    let body_locals: Vec<&str> = locals.iter().copied().chain(["iteration"]).collect();
+                    let resolve_after = |path: &[PathSegment]| self.reference_type(&after, &body_locals, path);
+                    match (repeat_while, until) {
+                        (Some(source), None) => condition("while", source, &resolve_after)?,
+                        (None, Some(source)) => condition("until", source, &resolve_after)?,
+                        _ => return Err(invalid("loop needs exactly one of `while` and `until`".to_string())),
+                    }
+                }
+                StepAction::SubWorkflow { path, name } => match (path, name) {
+                    (Some(target), None) | (None, Some(target)) if !target.trim().is_empty() => {}
+                    (Some(_), None) | (None, Some(_)) => {
+                        return Err(invalid("the workflow to run must not be empty".to_string()))
+                    }
+                    _ => return Err(invalid("workflow step needs exactly one of `path` and `name`".to_string())),
+                },
+                StepAction::Process {} | StepAction::Command(_) => {}
+            }
+            let body_locals: &[&str] = match step.action {
+                StepAction::Map { .. } => &["item", "index"],
+                StepAction::Loop { .. } => &["iteration"],
+                _ => &[],
+            };
+            let locals: Vec<&str> = locals.iter().chain(body_locals).copied().collect();
+            for (_, nested) in step.action.nested() {
+                self.check_expressions(nested, &upstream, &locals)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Static type of a reference such as `steps.fetch.output.count`
+    fn reference_type(
+        &self,
+        upstream: &HashSet<&str>,
+        locals: &[&str],
+        path: &[PathSegment],
+    ) -> std::result::Result<Type, String> {
+        let field = |i: usize| path.get(i).map(|segment| segment.as_deref());
+        let (kind, rest) = match field(0) {
+            Some(Some("steps")) => match field(1) {
+                None => return Ok(Type::Object),
+                Some(None) => return Err("steps must be referenced by name, e.g. `steps.fetch`".to_string()),
+                Some(Some(id)) if self.step(id).is_none() => return Err(format!("unknown step '{}'", id)),
+                Some(Some(id)) if !upstream.contains(id) => {
+                    return Err(format!("step '{}' is not upstream of this step; add it to depends_on", id))
+                }
+                Some(Some(_)) => match field(2) {
+                    None => (Type::Object, 3),
+                    Some(Some("status" | "message")) => (Type::String, 3),
+                    Some(Some("success")) => (Type::Bool, 3),
+                    Some(Some("output")) => (Type::Any, 3),
+                    Some(other) => {
+                        return Err(format!(
+                            "steps have no field '{}'; use status, success, message or output",
+                            other.unwrap_or("[...]")
+                        ))
+                    }
+                },
+            },
+            Some(Some("inputs")) => match field(1) {
+                None => (Type::Object, 1),
+                Some(None) => (Type::Any, 2),
+                Some(Some(name)) => match self.input_type(name) {
+                    Some(kind) => (kind, 2),
+                    None => return Err(format!("unknown workflow input '{}'", name)),
+                },
+            },
+            Some(Some(name)) if locals.contains(&name) => (if name == "item" { Type::Any } else { Type::Number }, 1),
+            Some(Some(name @ ("item" | "index"))) => {
+                return Err(format!("'{}' can only be used inside the steps of a map", name))
+            }
+            Some(Some("iteration")) => return Err("'iteration' can only be used inside a loop".to_string()),
+            Some(Some(name)) => return Err(format!("unknown name '{}'; expressions can read `steps` and `inputs`", name)),
+            _ => return Err("expressions can read `steps` and `inputs`".to_string()),
+        };
+        match (kind, path.get(rest)) {
+            (kind, None) => Ok(kind),
+            (Type::Any | Type::Object | Type::Array | Type::Null, Some(_)) => Ok(Type::Any),
+            (kind, Some(segment)) => Err(format!(
+                "cannot read '{}' of a {}",
+                segment.as_deref().unwrap_or("[...]"),
+                kind
+            )),
+        }
+    }
+
+    /// Returns step indices so that every step comes after its dependencies.
+    ///
+    /// Ties are broken by definition order, so the result is deterministic.
+    pub fn topological_order(&self) -> std::result::Result<Vec<usize>, ValidationError> {
+        topological_order(&self.steps)
+    }
+}
+
+type Resolver<'a> = dyn Fn(&[PathSegment]) -> std::result::Result<Type, String> + 'a;
+
+// Checks the dependencies among one list of sibling steps, then every branch
+// below them; `all_ids` tells a step outside the branch from an unknown one.
+fn check_siblings(steps: &[Step], all_ids: &HashSet<&str>) -> std::result::Result<(), ValidationError> {
+    let siblings: HashSet<&str> = steps.iter().map(|step| step.id.as_str()).collect();
+    for step in steps {
+        if let Some(Err(reason)) = step.retry.as_ref().map(RetryPolicy::check) {
+            return Err(ValidationError::InvalidStep {
+                step: step.id.clone(),
+                reason,
+            });
+        }
+        for dependency in &step.depends_on {
+            if siblings.contains(dependency.as_str()) {
+                continue;
+            }
+            if all_ids.contains(dependency.as_str()) {
+                return Err(ValidationError::InvalidStep {
+                    step: step.id.clone(),
+                    reason: format!("depends on '{}', which is not in the same branch", dependency),
+                });
+            }
+            return Err(ValidationError::UnknownDependency {
+                step: step.id.clone(),
+                dependency: dependency.clone(),
+            });
+        }
+    }
+    topological_order(steps)?;
+    for step in steps {
+        for (_, nested) in step.action.nested() {
+            check_siblings(nested, all_ids)?;
+        }
+    }
+    Ok(())
+}
+
+// Ids of every sibling `step` transitively depends on, and of the steps
+// nested in those, which have all finished by the time `step` starts
+fn upstream_of<'a>(step: &'a Step, siblings: &'a [Step]) -> HashSet<&'a str> {
+    let mut upstream = HashSet::new();
+    let mut pending: Vec<&str> = step.depends_on.iter().map(String::as_str).collect();
+    while let Some(id) = pending.pop() {
+        if !upstream.insert(id) {
+            continue;
+        }
+        if let Some(dependency) = siblings.iter().find(|sibling| sibling.id == id) {
+            pending.extend(dependency.depends_on.iter().map(String::as_str));
+            upstream.extend(dependency.descendants().into_iter().map(|nested| nested.id.as_str()));
+        }
+    }
+    upstream
+}
+
+fn topological_order(steps: &[Step]) -> std::result::Result<Vec<usize>, ValidationError> {
+    let index: HashMap<&str, usize> = steps
+        .iter()
+        .enumerate()
+        .map(|(i, step)| (step.id.as_str(), i))
+        .collect();
+
+    let mut remaining: Vec<usize> = steps.iter().map(|step| step.depends_on.len()).collect();
+    let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); steps.len()];
+    for (i, step) in steps.iter().enumerate() {
+        for dependency in &step.depends_on {
+            match index.get(dependency.as_str()) {
+                Some(&d) => dependents[d].push(i),
+                None => {
+                    return Err(ValidationError::UnknownDependency {
+                        step: step.id.clone(),
+                        dependency: dependency.clone(),
+                    })
+                }
+            }
+        }
+    }
+
+    let mut order = Vec::with_capacity(steps.len());
+    let mut ready: Vec<usize> = (0..steps.len()).filter(|&i| remaining[i] == 0).collect();
+    while let Some(&next) = ready.iter().min() {
+        ready.retain(|&i| i != next);
+        order.push(next);
+        for &dependent in &dependents[next] {
+            remaining[dependent] -= 1;
+            if remaining[dependent] == 0 {
+                ready.push(dependent);
+            }
+        }
+    }
+
+    if order.len() < steps.len() {
+        return Err(ValidationError::Cycle(find_cycle(steps, &remaining)));
+    }
+    Ok(order)
+}
+
+// Walks dependency edges among the unresolved steps until one repeats.
+fn find_cycle(steps: &[Step], remaining: &[usize]) -> Vec<String> {
+    let start = remaining.iter().position(|&n| n > 0).unwrap_or(0);
+    let mut path: Vec<&str> = Vec::new();
+    let mut current = steps[start].id.as_str();
+    loop {
+        if let Some(pos) = path.iter().position(|&id| id == current) {
+            let mut cycle: Vec<String> = path[pos..].iter().map(|id| id.to_string()).collect();
+            cycle.push(current.to_string());
+            return cycle;
+        }
+        path.push(current);
+        let step = match steps.iter().find(|step| step.id == current) {
+            Some(step) => step,
+            None => return path.iter().map(|id| id.to_string()).collect(),
+        };
+        let next = step.depends_on.iter().find(|dependency| {
+            steps
+                .iter()
+                .position(|s| &s.id == *dependency)
+                .map(|i| remaining[i] > 0)
+                .unwrap_or(false)
+        });
+        match next {
+            Some(dependency) => current = dependency.as_str(),
+            None => return path.iter().map(|id| id.to_string()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workflow(json: &str) -> Workflow {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_parse_defaults_to_process_steps() {
+        let wf = workflow(
+            r#"{"name": "demo", "steps": [
+                {"id": "fetch", "inputs": {"url": "http://example.com"}, "outputs": ["url"]},
+                {"id": "store", "kind": "process", "depends_on": ["fetch"]}
+            ]}"#,
+        );
+        assert_eq!(wf.steps.len(), 2);
+        assert_eq!(wf.steps[0].action, StepAction::Process {});
+        assert_eq!(wf.steps[1].depends_on, vec!["fetch".to_string()]);
+        assert!(wf.validate().is_ok());
+    }
+
+    #[test]
+    fn test_parse_command_step() {
+        let wf = workflow(
+            r#"{"name": "demo", "steps": [
+                {"id": "list", "kind": "command", "program": "ls", "args": ["-l"], "env": {"LC_ALL": "C"}}
+            ]}"#,
+        );
+        match &wf.steps[0].action {
+            StepAction::Command(spec) => {
+                assert_eq!(spec.program, "ls");
+                assert_eq!(spec.args, vec!["-l".to_string()]);
+                assert_eq!(spec.env["LC_ALL"], "C");
+                assert_eq!(spec.working_dir, None);
+            }
+            other => panic!("expected command step, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unknown_step_fields_are_rejected() {
+        let parsed: std::result::Result<Workflow, _> =
+            serde_json::from_str(r#"{"name": "demo", "steps": [{"id": "a", "depend_on": ["b"]}]}"#);
+        assert!(parsed.is_err());
+    }
+
+    #[test]
+    fn test_topological_order() {
+        let wf = workflow(
+            r#"{"name": "demo", "steps": [
+                {"id": "report", "depends_on": ["left", "right"]},
+                {"id": "left", "depends_on": ["start"]},
+                {"id": "right", "depends_on": ["start"]},
+                {"id": "start"}
+            ]}"#,
+        );
+        let order: Vec<&str> = wf
+            .topological_order()
+            .unwrap()
+            .into_iter()
+            .map(|i| wf.steps[i].id.as_str())
+            .collect();
+        assert_eq!(order, vec!["start", "left", "right", "report"]);
+    }
+
+    #[test]
+    fn test_missing_reference() {
+        let wf = workflow(r#"{"name": "demo", "steps": [{"id": "a", "depends_on": ["ghost"]}]}"#);
+        assert_eq!(
+            wf.validate(),
+            Err(ValidationError::UnknownDependency {
+                step: "a".to_string(),
+                dependency: "ghost".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_cycle_detection() {
+        let wf = workflow(
+            r#"{"name": "demo", "steps": [
+                {"id": "a"},
+                {"id": "b", "depends_on": ["a", "d"]},
+                {"id": "c", "depends_on": ["b"]},
+                {"id": "d", "depends_on": ["c"]}
+            ]}"#,
+        );
+        match wf.validate() {
+            Err(ValidationError::Cycle(path)) => {
+                assert_eq!(path.first(), path.last());
+                assert_eq!(path.len(), 4);
+            }
+            other => panic!("expected cycle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_retry_policy_defaults_and_validation() {
+        let wf = workflow(r#"{"name": "demo", "steps": [{"id": "a", "retry": {"max_attempts": 5, "jitter": 0.1}}]}"#);
+        let retry = wf.steps[0].retry.as_ref().unwrap();
+        assert_eq!(retry.max_attempts, 5);
+        assert_eq!(retry.multiplier, 2.0);
+        assert!(wf.validate().is_ok());
+
+        let wf = workflow(r#"{"name": "demo", "steps": [{"id": "a", "retry": {"max_attempts": 0}}]}"#);
+        assert!(matches!(wf.validate(), Err(ValidationError::InvalidStep { .. })));
+    }
+
+    #[test]
+    fn test_expressions_are_checked() {
+        let invalid = |json: &str| match workflow(json).validate() {
+            Err(ValidationError::InvalidStep { reason, .. }) => reason,
+            other => panic!("expected invalid step, got {:?}", other),
+        };
+        let ok = workflow(
+            r#"{"name": "demo", "inputs": {"limit": 10}, "steps": [
+                {"id": "fetch"},
+                {"id": "parse", "depends_on": ["fetch"]},
+                {"id": "report", "depends_on": ["parse"], "when": "${{ steps.fetch.output.count > inputs.limit }}",
+                 "inputs": {"title": "rows: ${{ steps.parse.output.rows }}"}}
+            ]}"#,
+        );
+        assert!(ok.validate().is_ok());
+
+        let reason = invalid(r#"{"name": "demo", "steps": [{"id": "a"}, {"id": "b", "depends_on": ["a"], "when": "steps.fetch.output.count > 10"}]}"#);
+        assert!(reason.contains("unknown step 'fetch'"), "{}", reason);
+        let reason = invalid(r#"{"name": "demo", "steps": [{"id": "a"}, {"id": "b", "when": "steps.a.success"}]}"#);
+        assert!(reason.contains("not upstream"), "{}", reason);
+        let reason = invalid(r#"{"name": "demo", "steps": [{"id": "a"}, {"id": "b", "depends_on": ["a"], "when": "steps.a.status"}]}"#);
+        assert!(reason.contains("is a string, not a boolean"), "{}", reason);
+        let reason = invalid(r#"{"name": "demo", "steps": [{"id": "a", "inputs": {"n": "${{ inputs.missing }}"}}]}"#);
+        assert!(reason.contains("unknown workflow input 'missing'"), "{}", reason);
+        let reason = invalid(r#"{"name": "demo", "steps": [{"id": "a", "kind": "command", "program": "echo", "args": ["${{ 1 + }}"]}]}"#);
+        assert!(reason.contains("expected a value"), "{}", reason);
+    }
+
+    #[test]
+    fn test_branches_are_validated() {
+        let wf = workflow(
+            r#"{"name": "demo", "steps": [
+                {"id": "fetch"},
+                {"id": "check", "kind": "if", "depends_on": ["fetch"], "condition": "${{ steps.fetch.success }}",
+                 "then": [{"id": "a"}, {"id": "b", "depends_on": ["a"], "when": "steps.fetch.success && steps.a.success"}],
+                 "else": [{"id": "c"}]},
+                {"id": "after", "depends_on": ["check"], "when": "steps.b.success || steps.c.success", "trigger": "any_success"}
+            ]}"#,
+        );
+        assert!(wf.validate().is_ok());
+        assert_eq!(wf.all_steps().len(), 6);
+        assert_eq!(wf.step("b").unwrap().depends_on, vec!["a".to_string()]);
+        assert_eq!(wf.steps[2].trigger, TriggerRule::AnySuccess);
+
+        let wf = workflow(
+            r#"{"name": "demo", "steps": [{"id": "a"}, {"id": "s", "kind": "switch", "value": "1",
+                "cases": {"x": [{"id": "a"}]}}]}"#,
+        );
+        assert_eq!(wf.validate(), Err(ValidationError::DuplicateStep("a".to_string())));
+
+        let wf = workflow(
+            r#"{"name": "demo", "steps": [{"id": "a"}, {"id": "s", "kind": "if", "condition": "true",
+                "then": [{"id": "b", "depends_on": ["a"]}]}]}"#,
+        );
+        assert!(matches!(wf.validate(), Err(ValidationError::InvalidStep { reason, .. }) if reason.contains("not in the same branch")));
+
+        let wf = workflow(
+            r#"{"name": "demo", "steps": [{"id": "a"}, {"id": "s", "kind": "if", "depends_on": ["a"],
+                "condition": "steps.a.status", "then": []}]}"#,
+        );
+        assert!(matches!(wf.validate(), Err(ValidationError::InvalidStep { reason, .. }) if reason.contains("not a boolean")));
+    }
+
+    #[test]
+    fn test_map_template_is_validated() {
+        let wf = workflow(
+            r#"{"name": "demo", "steps": [
+                {"id": "list"},
+                {"id": "each", "kind": "map", "depends_on": ["list"], "items": "steps.list.output.files",
+                 "steps": [{"id": "one", "inputs": {"file": "${{ item }}", "n": "${{ index * 2 }}"}}]}
+            ]}"#,
+        );
+        assert!(wf.validate().is_ok());
+        assert_eq!(wf.all_steps().len(), 3);
+        assert_eq!(wf.recorded_steps().len(), 2);
+
+        let invalid = |json: &str| match workflow(json).validate() {
+            Err(ValidationError::InvalidStep { reason, .. }) => reason,
+            other => panic!("expected invalid step, got {:?}", other),
+        };
+        let reason = invalid(r#"{"name": "demo", "steps": [{"id": "a", "inputs": {"x": "${{ item }}"}}]}"#);
+        assert!(reason.contains("only be used inside the steps of a map"), "{}", reason);
+        let reason = invalid(r#"{"name": "demo", "steps": [{"id": "m", "kind": "map", "items": "'text'", "steps": [{"id": "a"}]}]}"#);
+        assert!(reason.contains("not an array"), "{}", reason);
+        let reason = invalid(r#"{"name": "demo", "inputs": {"files": []}, "steps": [{"id": "m", "kind": "map", "items": "inputs.files", "steps": []}]}"#);
+        assert!(reason.contains("at least one step"), "{}", reason);
+    }
+
+    #[test]
+    fn test_loop_is_validated() {
+        let wf = workflow(
+            r#"{"name": "demo", "steps": [
+                {"id": "poll", "kind": "loop", "until": "steps.check.output.n >= 3 || iteration > 5",
+                 "steps": [{"id": "check", "inputs": {"n": "${{ iteration }}"}}]}
+            ]}"#,
+        );
+        assert!(wf.validate().is_ok());
+        assert_eq!(wf.recorded_steps().len(), 1);
+
+        let invalid = |json: &str| match workflow(json).validate() {
+            Err(ValidationError::InvalidStep { reason, .. }) => reason,
+            other => panic!("expected invalid step, got {:?}", other),
+        };
+        let reason = invalid(r#"{"name": "demo", "steps": [{"id": "l", "kind": "loop", "steps": [{"id": "a"}]}]}"#);
+        assert!(reason.contains("exactly one of"), "{}", reason);
+        let reason = invalid(r#"{"name": "demo", "steps": [{"id": "l", "kind": "loop", "while": "true", "max_iterations": 0, "steps": [{"id": "a"}]}]}"#);
+        assert!(reason.contains("at least 1"), "{}", reason);
+        let reason = invalid(r#"{"name": "demo", "steps": [{"id": "l", "kind": "loop", "while": "'yes'", "steps": [{"id": "a"}]}]}"#);
+        assert!(reason.contains("bool"), "{}", reason);
+        let reason = invalid(r#"{"name": "demo", "steps": [{"id": "a", "when": "iteration > 1"}]}"#);
+        assert!(reason.contains("only be used inside a loop"), "{}", reason);
+    }
+
+    #[test]
+    fn test_input_schema_and_outputs_are_validated() {
+        let wf = workflow(
+            r#"{"name": "demo", "inputs": {"region": "eu"},
+                "input_schema": {"properties": {"replicas": {"type": "integer", "default": 1}}},
+                "outputs": {"count": "steps.a.output.n"},
+                "steps": [{"id": "a", "when": "inputs.replicas > 0 && inputs.region != 'us'"}]}"#,
+        );
+        assert!(wf.validate().is_ok());
+        assert_eq!(wf.input_type("replicas"), Some(Type::Number));
+        assert_eq!(wf.input_type("region"), Some(Type::String));
+        let resolved = wf.resolve_inputs(&serde_json::Map::new()).unwrap();
+        assert_eq!(serde_json::Value::Object(resolved), serde_json::json!({"region": "eu", "replicas": 1}));
+
+        let error = |json: &str| workflow(json).validate().unwrap_err().to_string();
+        assert_eq!(
+            error(r#"{"name": "d", "input_schema": {"properties": {"a": {"format": "date"}}}, "steps": [{"id": "a"}]}"#),
+            "input_schema: a: unsupported schema keyword 'format'"
+        );
+        assert_eq!(
+            error(r#"{"name": "d", "inputs": {"a": "x"}, "input_schema": {"properties": {"a": {"type": "number"}}}, "steps": [{"id": "a"}]}"#),
+            "input 'a': default does not match input_schema: must be number, got string"
+        );
+        assert_eq!(
+            error(r#"{"name": "d", "input_schema": {"properties": {"n": {"type": "string"}}}, "steps": [{"id": "a", "when": "inputs.n > 1"}]}"#),
+            "step 'a': when: `inputs.n > 1`: cannot compare a string with a number using `>`"
+        );
+        assert_eq!(
+            error(r#"{"name": "d", "outputs": {"x": "steps.b.output"}, "steps": [{"id": "a"}]}"#),
+            "output 'x': `steps.b.output`: unknown step 'b'"
+        );
+    }
+
+    #[test]
+    fn test_workflow_step_needs_one_target() {
+        let wf = workflow(r#"{"name": "demo", "steps": [{"id": "a", "kind": "workflow", "name": "deploy"}]}"#);
+        assert!(wf.validate().is_ok());
+        assert_eq!(wf.steps[0].action.kind(), "workflow");
+
+        for json in [
+            r#"{"name": "demo", "steps": [{"id": "a", "kind": "workflow"}]}"#,
+            r#"{"name": "demo", "steps": [{"id": "a", "kind": "workflow", "name": "x", "path": "x.yaml"}]}"#,
+        ] {
+            match workflow(json).validate() {
+                Err(ValidationError::InvalidStep { reason, .. }) => assert!(reason.contains("exactly one of"), "{}", reason),
+                other => panic!("expected invalid step, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_duplicate_ids() {
+        let wf = workflow(r#"{"name": "demo", "steps": [{"id": "a"}, {"id": "a"}]}"#);
+        assert_eq!(wf.validate(), Err(ValidationError::DuplicateStep("a".to_string())));
+    }
+}